@@ -1,4 +1,4 @@
-CREATE TABLE user(
+CREATE TABLE IF NOT EXISTS user(
     id INTEGER PRIMARY KEY,
     username TEXT NOT NULL,
     password TEXT NOT NULL,
@@ -11,9 +11,3 @@ CREATE TABLE IF NOT EXISTS article(
     title TEXT NOT NULL,
     description TEXT NOT NULL
 );
-
-INSERT OR IGNORE INTO user(id, username, password, is_admin)
-VALUES (0, 'root', 'toor', 1);
-
-INSERT OR IGNORE INTO article(id, owner, title, description)
-VALUES (0, 'root', 'Example', 'This is a test case. This part is the description');
@@ -0,0 +1,8 @@
+ALTER TABLE user ADD COLUMN email TEXT;
+ALTER TABLE user ADD COLUMN email_verified INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE user ADD COLUMN verify_nonce TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS user_email ON user(email);
+
+-- Accounts created before verification existed keep publishing
+UPDATE user SET email_verified=1;
@@ -0,0 +1,7 @@
+ALTER TABLE user ADD COLUMN session_stamp TEXT;
+
+CREATE TABLE IF NOT EXISTS password_reset(
+    token_hash TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES user(id) ON DELETE CASCADE,
+    expires_at INTEGER NOT NULL
+);
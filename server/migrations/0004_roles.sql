@@ -0,0 +1,63 @@
+CREATE TABLE IF NOT EXISTS role(
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    description TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS permission(
+    name TEXT PRIMARY KEY,
+    description TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS role_permission(
+    role_id INTEGER NOT NULL REFERENCES role(id) ON DELETE CASCADE,
+    permission TEXT NOT NULL REFERENCES permission(name) ON DELETE CASCADE,
+    PRIMARY KEY (role_id, permission)
+);
+
+INSERT INTO role(id, name, description) VALUES
+    (1, 'reader', 'Reads articles'),
+    (2, 'author', 'Publishes and manages their own articles'),
+    (3, 'editor', 'Author who can also edit the articles of others'),
+    (4, 'moderator', 'Author who can also hide articles'),
+    (5, 'admin', 'Can do everything, including managing users');
+
+INSERT INTO permission(name, description) VALUES
+    ('article.create', 'Publish new articles'),
+    ('article.edit.own', 'Edit own articles'),
+    ('article.delete.own', 'Delete own articles'),
+    ('article.edit.any', 'Edit articles of other users'),
+    ('article.delete.any', 'Delete articles of other users'),
+    ('article.hide', 'Hide and unhide articles'),
+    ('user.manage', 'Delete users and assign roles');
+
+INSERT INTO role_permission(role_id, permission)
+    SELECT role.id, permission.name FROM role, permission
+    WHERE role.name IN ('author', 'editor', 'moderator')
+      AND permission.name IN ('article.create', 'article.edit.own', 'article.delete.own');
+INSERT INTO role_permission(role_id, permission) VALUES
+    (3, 'article.edit.any'),
+    (4, 'article.hide');
+INSERT INTO role_permission(role_id, permission)
+    SELECT 5, name FROM permission;
+
+-- Rebuilt instead of dropping is_admin, DROP COLUMN needs SQLite 3.35
+CREATE TABLE user_new(
+    id INTEGER PRIMARY KEY,
+    username TEXT NOT NULL,
+    email TEXT,
+    email_verified INTEGER NOT NULL DEFAULT 0,
+    verify_nonce TEXT,
+    password TEXT NOT NULL,
+    session_stamp TEXT,
+    role_id INTEGER NOT NULL DEFAULT 2 REFERENCES role(id)
+);
+INSERT INTO user_new(id, username, email, email_verified, verify_nonce, password, session_stamp, role_id)
+    SELECT id, username, email, email_verified, verify_nonce, password, session_stamp,
+           CASE WHEN is_admin THEN 5 ELSE 2 END
+    FROM user;
+DROP TABLE user;
+ALTER TABLE user_new RENAME TO user;
+CREATE UNIQUE INDEX user_email ON user(email);
+
+ALTER TABLE article ADD COLUMN hidden INTEGER NOT NULL DEFAULT 0;
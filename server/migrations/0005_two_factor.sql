@@ -0,0 +1,11 @@
+-- Sealed with the two_factor key, set while enrolling and kept once enabled
+ALTER TABLE user ADD COLUMN totp_secret TEXT;
+ALTER TABLE user ADD COLUMN totp_enabled INTEGER NOT NULL DEFAULT 0;
+-- Last accepted time step, a code is never accepted twice
+ALTER TABLE user ADD COLUMN totp_last_step INTEGER;
+
+CREATE TABLE IF NOT EXISTS recovery_code(
+    user_id INTEGER NOT NULL REFERENCES user(id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    PRIMARY KEY (user_id, code_hash)
+);
@@ -0,0 +1,15 @@
+-- Personal API tokens, only their digest is stored
+CREATE TABLE IF NOT EXISTS api_token(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES user(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    -- Space separated scope names, empty for read only tokens
+    scopes TEXT NOT NULL DEFAULT '',
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER,
+    -- NULL for tokens that never expire
+    expires_at INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS api_token_user ON api_token(user_id);
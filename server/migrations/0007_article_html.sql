@@ -0,0 +1,3 @@
+-- Sanitized HTML rendered from the Markdown in description, NULL until the
+-- server renders it on startup
+ALTER TABLE article ADD COLUMN html TEXT;
@@ -0,0 +1,9 @@
+-- Existing articles were all public
+ALTER TABLE article ADD COLUMN status TEXT NOT NULL DEFAULT 'published'
+    CHECK (status IN ('draft', 'in_review', 'published', 'archived'));
+
+INSERT INTO permission(name, description) VALUES
+    ('article.publish', 'Publish and unpublish articles they can edit');
+-- Everyone who writes articles publishes them, remove it from a role to have its articles reviewed
+INSERT INTO role_permission(role_id, permission)
+    SELECT role_id, 'article.publish' FROM role_permission WHERE permission='article.create';
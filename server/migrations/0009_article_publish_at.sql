@@ -0,0 +1,4 @@
+-- Unix time a draft or an article in review gets published at, NULL when it isn't scheduled
+ALTER TABLE article ADD COLUMN publish_at INTEGER;
+
+CREATE INDEX IF NOT EXISTS article_publish_at ON article(publish_at) WHERE publish_at IS NOT NULL;
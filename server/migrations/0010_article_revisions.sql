@@ -0,0 +1,20 @@
+-- Every saved version of an article, rows are never changed once written
+CREATE TABLE IF NOT EXISTS article_revision(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    article_id INTEGER NOT NULL REFERENCES article(id) ON DELETE CASCADE,
+    author TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    description TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS article_revision_article ON article_revision(article_id);
+
+CREATE TRIGGER IF NOT EXISTS article_revision_immutable BEFORE UPDATE ON article_revision
+BEGIN
+    SELECT RAISE(ABORT, 'article revisions are immutable');
+END;
+
+-- The current content of existing articles becomes their first revision
+INSERT INTO article_revision(article_id, author, created_at, title, description)
+    SELECT id, owner, CAST(strftime('%s', 'now') AS INTEGER), title, description FROM article;
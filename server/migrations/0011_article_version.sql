@@ -0,0 +1,2 @@
+-- Bumped by every save, edits made against an older version are rejected
+ALTER TABLE article ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
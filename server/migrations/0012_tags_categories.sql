@@ -0,0 +1,33 @@
+-- Categories form a tree, tags are shared between articles
+CREATE TABLE IF NOT EXISTS category(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- NULL for top level categories
+    parent_id INTEGER REFERENCES category(id),
+    name TEXT NOT NULL,
+    slug TEXT NOT NULL UNIQUE
+);
+
+CREATE INDEX IF NOT EXISTS category_parent ON category(parent_id);
+
+ALTER TABLE article ADD COLUMN category_id INTEGER REFERENCES category(id);
+
+CREATE INDEX IF NOT EXISTS article_category ON article(category_id);
+
+-- Names are stored lower case
+CREATE TABLE IF NOT EXISTS tag(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS article_tag(
+    article_id INTEGER NOT NULL REFERENCES article(id) ON DELETE CASCADE,
+    tag_id INTEGER NOT NULL REFERENCES tag(id),
+    PRIMARY KEY (article_id, tag_id)
+);
+
+CREATE INDEX IF NOT EXISTS article_tag_tag ON article_tag(tag_id);
+
+INSERT INTO permission(name, description) VALUES
+    ('category.manage', 'Create and delete categories');
+INSERT INTO role_permission(role_id, permission)
+    SELECT role_id, 'category.manage' FROM role_permission WHERE permission='article.edit.any';
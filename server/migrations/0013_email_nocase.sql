@@ -0,0 +1,4 @@
+-- Addresses that only differ in case belong to the same mailbox. Fails when
+-- two accounts already have such addresses, one of them has to be changed first.
+DROP INDEX IF EXISTS user_email;
+CREATE UNIQUE INDEX user_email ON user(email COLLATE NOCASE);
@@ -0,0 +1,351 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_identity::RequestIdentity;
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use crate::error::AppError;
+use crate::identity::TokenScopes;
+use crate::models::{Article, ArticleStatus};
+use crate::repo::{self, Pool};
+use crate::totp::TwoFactor;
+
+/// Something a role may be allowed to do. The names match the `permission` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    CreateArticle,
+    EditOwnArticle,
+    EditAnyArticle,
+    DeleteOwnArticle,
+    DeleteAnyArticle,
+    HideArticle,
+    PublishArticle,
+    ManageCategories,
+    ManageUsers,
+}
+
+impl Permission {
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::CreateArticle => "article.create",
+            Permission::EditOwnArticle => "article.edit.own",
+            Permission::EditAnyArticle => "article.edit.any",
+            Permission::DeleteOwnArticle => "article.delete.own",
+            Permission::DeleteAnyArticle => "article.delete.any",
+            Permission::HideArticle => "article.hide",
+            Permission::PublishArticle => "article.publish",
+            Permission::ManageCategories => "category.manage",
+            Permission::ManageUsers => "user.manage",
+        }
+    }
+
+    /// The API token scope needed to use the permission with a token.
+    pub fn scope(self) -> Scope {
+        match self {
+            Permission::CreateArticle
+            | Permission::EditOwnArticle
+            | Permission::EditAnyArticle
+            | Permission::DeleteOwnArticle
+            | Permission::DeleteAnyArticle
+            | Permission::PublishArticle => Scope::WriteArticles,
+            Permission::HideArticle | Permission::ManageCategories => Scope::ModerateArticles,
+            Permission::ManageUsers => Scope::ManageUsers,
+        }
+    }
+}
+
+/// What an API token may be used for. A token only gets the permissions of its
+/// owner's role that its scopes cover, a token without scopes can only read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    WriteArticles,
+    ModerateArticles,
+    ManageUsers,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::WriteArticles, Scope::ModerateArticles, Scope::ManageUsers];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::WriteArticles => "articles:write",
+            Scope::ModerateArticles => "articles:moderate",
+            Scope::ManageUsers => "users:manage",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Scope::WriteArticles => "Create, edit and delete articles",
+            Scope::ModerateArticles => "Hide and unhide articles, manage categories",
+            Scope::ManageUsers => "Manage users and their roles",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Scope> {
+        Scope::ALL.iter().copied().find(|scope| scope.name() == name)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ArticleAction {
+    Edit,
+    Delete,
+    Hide,
+}
+
+impl ArticleAction {
+    /// Permission needed to perform the action on an article, depending on whether it is owned.
+    pub fn permission(self, own: bool) -> Permission {
+        match (self, own) {
+            (ArticleAction::Edit, true) => Permission::EditOwnArticle,
+            (ArticleAction::Edit, false) => Permission::EditAnyArticle,
+            (ArticleAction::Delete, true) => Permission::DeleteOwnArticle,
+            (ArticleAction::Delete, false) => Permission::DeleteAnyArticle,
+            (ArticleAction::Hide, _) => Permission::HideArticle,
+        }
+    }
+}
+
+impl fmt::Display for ArticleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArticleAction::Edit => write!(f, "edit"),
+            ArticleAction::Delete => write!(f, "delete"),
+            ArticleAction::Hide => write!(f, "hide"),
+        }
+    }
+}
+
+pub enum Authorization {
+    Allowed,
+    Denied,
+    NotFound,
+}
+
+/// Checks whether `user` may perform `action` on article `id`.
+/// Denials are written to the `audit` log target.
+pub fn authorize_article(pool: &Pool, user: &CurrentUser, id: i32, action: ArticleAction) -> Result<Authorization, AppError> {
+    let article = match repo::get_article(pool.get()?, id) {
+        Ok(article) => article,
+        Err(AppError::NotFound(_)) => return Ok(Authorization::NotFound),
+        Err(err) => return Err(err),
+    };
+
+    if user.may(action, &article.owner) {
+        Ok(Authorization::Allowed)
+    } else {
+        log::warn!(
+            target: "audit",
+            "denied: user '{}' ({}) tried to {} article {} owned by '{}'",
+            user.username, user.role, action, article.id, article.owner
+        );
+        Ok(Authorization::Denied)
+    }
+}
+
+/// Runs `authorize_article` on the blocking pool, turning a denial into `AppError::Forbidden`
+/// and a missing article into `AppError::NotFound`.
+pub async fn require_article(db: &Pool, user: &CurrentUser, id: i32, action: ArticleAction) -> Result<(), AppError> {
+    let pool = db.clone();
+    let user = user.clone();
+    let res = web::block(move || authorize_article(&pool, &user, id, action)).await?;
+
+    match res {
+        Authorization::Allowed => Ok(()),
+        Authorization::NotFound => Err(AppError::NotFound(format!("Article '{}' was not found", id))),
+        Authorization::Denied => Err(AppError::Forbidden(format!("You are not allowed to {} this article", action))),
+    }
+}
+
+/// Hidden articles stay visible to their owner and to whoever can act on them,
+/// unpublished ones to their owner and to whoever can edit them.
+pub fn can_view(user: Option<&CurrentUser>, article: &Article) -> bool {
+    let hidden_ok = !article.hidden || user.is_some_and(|user| {
+        user.username == article.owner
+            || user.may(ArticleAction::Edit, &article.owner)
+            || user.may(ArticleAction::Hide, &article.owner)
+    });
+    let status_ok = article.status == ArticleStatus::Published || user.is_some_and(|user| {
+        user.username == article.owner || user.may(ArticleAction::Edit, &article.owner)
+    });
+    hidden_ok && status_ok
+}
+
+/// Whether `user` works on every article rather than only their own, hidden ones included.
+pub fn sees_all_articles(user: &CurrentUser) -> bool {
+    [Permission::EditAnyArticle, Permission::DeleteAnyArticle, Permission::HideArticle]
+        .iter()
+        .any(|permission| user.can(*permission))
+}
+
+/// Whether `user` sees every unpublished article rather than only their own.
+pub fn sees_all_unpublished(user: &CurrentUser) -> bool {
+    user.can(Permission::EditAnyArticle)
+}
+
+/// Whether `user` may move `article` to status `to`. Anyone who can edit the
+/// article can move it between draft and review, or archive it; making it
+/// public or taking it back also needs `Permission::PublishArticle`.
+pub fn may_move(user: &CurrentUser, article: &Article, to: ArticleStatus) -> bool {
+    let publishing = to == ArticleStatus::Published
+        || (article.status == ArticleStatus::Published && to != ArticleStatus::Archived);
+    article.status.next().contains(&to)
+        && user.may(ArticleAction::Edit, &article.owner)
+        && (!publishing || user.can(Permission::PublishArticle))
+}
+
+/// Only accounts with a verified email address may publish new articles.
+pub async fn can_publish(db: &Pool, username: String) -> Result<bool, AppError> {
+    let pool = db.clone();
+    Ok(web::block(move || repo::is_verified(pool.get()?, username)).await?)
+}
+
+/// How long permissions read from the database are trusted.
+const PERMISSION_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Short lived cache of each user's role and permissions, so the dashboard doesn't query them on every request.
+#[derive(Default)]
+pub struct PermissionCache {
+    entries: Mutex<HashMap<String, (CurrentUser, Instant)>>,
+}
+
+impl PermissionCache {
+    fn get(&self, username: &str) -> Option<CurrentUser> {
+        let entries = self.entries.lock().ok()?;
+        entries.get(username)
+            .filter(|(_, fetched)| fetched.elapsed() < PERMISSION_CACHE_TTL)
+            .map(|(user, _)| user.clone())
+    }
+
+    fn insert(&self, user: CurrentUser) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(user.username.to_owned(), (user, Instant::now()));
+        }
+    }
+
+    /// Forgets every cached entry. Call it whenever a role changes.
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+    }
+}
+
+/// The logged in user, with the role and permissions read from the database or the cache.
+/// Rejects anonymous requests with `AppError::Unauthorized`.
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    pub username: String,
+    pub role: String,
+    permissions: HashSet<String>,
+    /// The role requires two-factor authentication
+    pub two_factor_required: bool,
+    pub two_factor_enabled: bool,
+    /// Scopes of the API token the request was made with, `None` for a session
+    token_scopes: Option<Vec<Scope>>,
+}
+
+impl CurrentUser {
+    /// Two-factor authentication is required but not enabled, every permission
+    /// is withheld until it is.
+    pub fn two_factor_missing(&self) -> bool {
+        self.two_factor_required && !self.two_factor_enabled
+    }
+
+    pub fn via_token(&self) -> bool {
+        self.token_scopes.is_some()
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        let in_scope = self.token_scopes.as_ref().is_none_or(|scopes| scopes.contains(&permission.scope()));
+        !self.two_factor_missing() && in_scope && self.permissions.contains(permission.name())
+    }
+
+    /// Fails with `AppError::Forbidden` for requests made with an API token.
+    /// Account settings, like the tokens themselves, are only changed from a session.
+    pub fn require_session(&self) -> Result<(), AppError> {
+        if self.via_token() {
+            log::warn!(target: "audit", "denied: user '{}' tried to change account settings with an API token", self.username);
+            return Err(AppError::Forbidden("API tokens can't change account settings".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Fails with `AppError::Forbidden` unless the user has `permission`.
+    /// Denials are written to the `audit` log target.
+    pub fn require(&self, permission: Permission) -> Result<(), AppError> {
+        if self.can(permission) {
+            return Ok(());
+        }
+        if self.two_factor_missing() {
+            log::warn!(target: "audit", "denied: user '{}' ({}) needs two-factor authentication for '{}'", self.username, self.role, permission);
+            return Err(AppError::Forbidden("Enable two-factor authentication in your options first".to_string()));
+        }
+        if self.token_scopes.as_ref().is_some_and(|scopes| !scopes.contains(&permission.scope())) {
+            log::warn!(target: "audit", "denied: API token of user '{}' lacks scope '{}'", self.username, permission.scope());
+            return Err(AppError::Forbidden(format!("The API token needs the '{}' scope", permission.scope())));
+        }
+        log::warn!(target: "audit", "denied: user '{}' ({}) lacks permission '{}'", self.username, self.role, permission);
+        Err(AppError::Forbidden(format!("Your role '{}' is not allowed to do this", self.role)))
+    }
+
+    /// Whether the user may perform `action` on an article owned by `owner`.
+    pub fn may(&self, action: ArticleAction, owner: &str) -> bool {
+        self.can(action.permission(owner == self.username))
+    }
+}
+
+impl FromRequest for CurrentUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let username = req.get_identity();
+        let pool = req.app_data::<web::Data<Pool>>().cloned();
+        let cache = req.app_data::<web::Data<PermissionCache>>().cloned();
+        let required = req.app_data::<web::Data<TwoFactor>>().is_some_and(|two_factor| two_factor.required_for_admins);
+        let token_scopes = req.extensions().get::<TokenScopes>().and_then(TokenScopes::get);
+
+        async move {
+            let username = username.ok_or_else(|| AppError::Unauthorized("Unauthorized access".to_string()))?;
+            let pool = pool.ok_or_else(|| AppError::internal("Database pool is not configured"))?;
+            let cache = cache.ok_or_else(|| AppError::internal("Permission cache is not configured"))?;
+
+            if let Some(user) = cache.get(&username) {
+                return Ok(CurrentUser { token_scopes, ..user });
+            }
+            let name = username.to_owned();
+            let (role, permissions, enabled) = web::block(move || {
+                let (role, permissions) = repo::get_permissions(pool.get()?, name.to_owned())?;
+                let enabled = repo::get_two_factor(pool.get()?, name)?.enabled;
+                Ok::<_, AppError>((role, permissions, enabled))
+            }).await?;
+            let user = CurrentUser {
+                username,
+                role,
+                two_factor_required: required && permissions.iter().any(|permission| permission == Permission::ManageUsers.name()),
+                two_factor_enabled: enabled,
+                permissions: permissions.into_iter().collect(),
+                token_scopes: None,
+            };
+            cache.insert(user.clone());
+            Ok(CurrentUser { token_scopes, ..user })
+        }.boxed_local()
+    }
+}
@@ -0,0 +1,659 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use clap::Parser;
+use serde::Deserialize;
+use crate::email::FailurePolicy;
+use crate::mailer::TlsMode;
+use crate::password::HashPolicy;
+
+/// Runtime configuration.
+///
+/// Every value is resolved from, in increasing priority: the defaults below,
+/// the TOML file, `DEVCLECTIC_*` environment variables and command line flags.
+/// See `devclectic.example.toml` for the file layout.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub paths: PathsConfig,
+    pub cookie: CookieConfig,
+    pub session: SessionConfig,
+    pub login: LoginConfig,
+    pub password: HashPolicy,
+    pub email: EmailConfig,
+    pub smtp: SmtpConfig,
+    pub tokens: TokensConfig,
+    pub keys: KeysConfig,
+    pub two_factor: TwoFactorConfig,
+    pub scheduler: SchedulerConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the HTTP server binds to
+    pub listen: String,
+    /// Public url of the site, used for links put into emails
+    pub base_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    pub templates: PathBuf,
+    pub static_files: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieConfig {
+    pub name: String,
+    /// Left out of the cookie when unset, which binds it to the exact host
+    pub domain: Option<String>,
+    pub secure: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// How long a login stays valid, in seconds
+    pub lifetime_secs: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginConfig {
+    /// Failed logins in a row before an account is locked
+    pub max_failures: u32,
+    pub lockout_secs: u64,
+    /// Wait after the first failure, doubled with every further one
+    pub backoff_base_ms: u64,
+    pub backoff_max_secs: u64,
+    /// Takes the client address from `X-Forwarded-For`, only enable behind a proxy setting it
+    pub trust_proxy: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmailConfig {
+    /// File with one disposable domain per line
+    pub blocklist: PathBuf,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    /// Enables the HTTP provider, may contain `{domain}` and `{email}` placeholders
+    pub provider_url: Option<String>,
+    pub provider_headers: BTreeMap<String, String>,
+    pub provider_timeout_ms: u64,
+    pub provider_policy: FailurePolicy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Defaults to 1025 without TLS, to the standard port of the TLS mode otherwise
+    pub port: Option<u16>,
+    pub tls: TlsMode,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    /// Sender address
+    pub from: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokensConfig {
+    /// Key for signed links, derived from the cookie keys when unset
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// File with one base64 cookie key per line, newest first
+    pub file: Option<PathBuf>,
+    /// Base64 cookie key used instead of a file
+    pub current: Option<String>,
+    /// Keys still accepted for cookies issued before the last rotation
+    pub previous: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwoFactorConfig {
+    /// Name shown next to the account in authenticator apps
+    pub issuer: String,
+    /// Key the TOTP secrets are encrypted with, derived from the cookie keys when unset
+    pub key: Option<String>,
+    /// Users who can manage users must enable two-factor authentication before doing anything else
+    pub required_for_admins: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    /// How often scheduled articles are checked, in seconds
+    pub interval_secs: u64,
+}
+
+/// Resolves a path inside the source checkout.
+fn checkout(path: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(path)
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: "127.0.0.1:8080".to_string(),
+            base_url: "http://127.0.0.1:8080".to_string(),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig { path: checkout("data.sqlite") }
+    }
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        PathsConfig {
+            templates: checkout("templates"),
+            static_files: checkout("static"),
+        }
+    }
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            name: "auth".to_string(),
+            domain: None,
+            secure: false,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig { lifetime_secs: 86400 }
+    }
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig {
+            max_failures: 5,
+            lockout_secs: 900,
+            backoff_base_ms: 1000,
+            backoff_max_secs: 60,
+            trust_proxy: false,
+        }
+    }
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        TwoFactorConfig {
+            issuer: "Devclectic".to_string(),
+            key: None,
+            required_for_admins: true,
+        }
+    }
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig { interval_secs: 30 }
+    }
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        EmailConfig {
+            blocklist: checkout("data/disposable_domains.txt"),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            provider_url: None,
+            provider_headers: BTreeMap::new(),
+            provider_timeout_ms: 3000,
+            provider_policy: FailurePolicy::Open,
+        }
+    }
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port: None,
+            tls: TlsMode::None,
+            user: None,
+            password: None,
+            from: "Devclectic <noreply@devclectic.local>".to_string(),
+        }
+    }
+}
+
+/// Command line flags, they override the file and the environment.
+#[derive(Debug, Parser)]
+#[clap(name = "devclectic-server", version, about = "Devclectic article server")]
+pub struct Cli {
+    /// TOML configuration file [env: DEVCLECTIC_CONFIG] [default: ./devclectic.toml when present]
+    #[clap(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[clap(long, value_name = "ADDR")]
+    pub listen: Option<String>,
+    /// SQLite database file
+    #[clap(long, value_name = "FILE")]
+    pub database: Option<PathBuf>,
+    /// Directory with the Tera templates
+    #[clap(long, value_name = "DIR")]
+    pub templates: Option<PathBuf>,
+    /// Directory served at `/`
+    #[clap(long = "static", value_name = "DIR")]
+    pub static_files: Option<PathBuf>,
+    /// Domain of the login cookie
+    #[clap(long, value_name = "DOMAIN")]
+    pub cookie_domain: Option<String>,
+    /// Only send cookies over HTTPS
+    #[clap(long)]
+    pub cookie_secure: bool,
+    /// How long a login stays valid, in seconds
+    #[clap(long, value_name = "SECS")]
+    pub session_lifetime: Option<i64>,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Apply pending database migrations and exit, the server also does this on startup
+    Migrate,
+    /// Add an admin account with a random password, which is printed once
+    CreateAdmin {
+        username: String,
+        /// Email address of the account, counts as verified
+        #[clap(long)]
+        email: Option<String>,
+    },
+    /// Print a new cookie key, or make it the current key of a key file
+    GenerateKey {
+        /// Key file to put the new key into, the keys already in it become previous keys
+        #[clap(long, value_name = "FILE")]
+        rotate: Option<PathBuf>,
+        /// Number of previous keys to keep in the key file
+        #[clap(long, value_name = "N", requires = "rotate")]
+        keep: Option<usize>,
+    },
+}
+
+impl Config {
+    /// Builds the configuration from every layer and validates it.
+    pub fn load(cli: &Cli) -> Result<Config, String> {
+        let file = match &cli.config {
+            Some(path) => Some(path.to_owned()),
+            None => match std::env::var_os("DEVCLECTIC_CONFIG") {
+                Some(path) => Some(PathBuf::from(path)),
+                None => Some(PathBuf::from("devclectic.toml")).filter(|path| path.is_file()),
+            },
+        };
+
+        let mut config = match file {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads a TOML file. Relative paths in it are taken relative to the file.
+    pub fn from_file(path: &Path) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read config file '{}': {}", path.display(), err))?;
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|err| format!("Invalid config file '{}': {}", path.display(), err))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for path in [
+            &mut config.database.path,
+            &mut config.paths.templates,
+            &mut config.paths.static_files,
+            &mut config.email.blocklist,
+        ] {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        }
+        if let Some(path) = config.keys.file.as_mut().filter(|path| path.is_relative()) {
+            *path = base.join(&*path);
+        }
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        env("DEVCLECTIC_LISTEN", &mut self.server.listen)?;
+        env("DEVCLECTIC_BASE_URL", &mut self.server.base_url)?;
+        env("DEVCLECTIC_DATABASE", &mut self.database.path)?;
+        env("DEVCLECTIC_TEMPLATES", &mut self.paths.templates)?;
+        env("DEVCLECTIC_STATIC", &mut self.paths.static_files)?;
+        env("DEVCLECTIC_COOKIE_NAME", &mut self.cookie.name)?;
+        env_opt("DEVCLECTIC_COOKIE_DOMAIN", &mut self.cookie.domain)?;
+        env("DEVCLECTIC_COOKIE_SECURE", &mut self.cookie.secure)?;
+        env("DEVCLECTIC_SESSION_LIFETIME", &mut self.session.lifetime_secs)?;
+
+        env("DEVCLECTIC_LOGIN_MAX_FAILURES", &mut self.login.max_failures)?;
+        env("DEVCLECTIC_LOGIN_LOCKOUT_SECS", &mut self.login.lockout_secs)?;
+        env("DEVCLECTIC_LOGIN_BACKOFF_BASE_MS", &mut self.login.backoff_base_ms)?;
+        env("DEVCLECTIC_LOGIN_BACKOFF_MAX_SECS", &mut self.login.backoff_max_secs)?;
+        env("DEVCLECTIC_TRUST_PROXY", &mut self.login.trust_proxy)?;
+
+        env("DEVCLECTIC_ARGON2_MEMORY_KIB", &mut self.password.memory_kib)?;
+        env("DEVCLECTIC_ARGON2_ITERATIONS", &mut self.password.iterations)?;
+        env("DEVCLECTIC_ARGON2_PARALLELISM", &mut self.password.parallelism)?;
+
+        env("DEVCLECTIC_EMAIL_BLOCKLIST", &mut self.email.blocklist)?;
+        if let Some(list) = var("DEVCLECTIC_EMAIL_ALLOW")? {
+            self.email.allow = split_list(&list);
+        }
+        if let Some(list) = var("DEVCLECTIC_EMAIL_DENY")? {
+            self.email.deny = split_list(&list);
+        }
+        env_opt("DEVCLECTIC_EMAIL_PROVIDER_URL", &mut self.email.provider_url)?;
+        if let Some(pairs) = var("DEVCLECTIC_EMAIL_PROVIDER_HEADERS")? {
+            self.email.provider_headers = split_list(&pairs)
+                .into_iter()
+                .map(|pair| match pair.split_once('=') {
+                    Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+                    None => Err(format!("DEVCLECTIC_EMAIL_PROVIDER_HEADERS: malformed header '{}'", pair)),
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        env("DEVCLECTIC_EMAIL_PROVIDER_TIMEOUT_MS", &mut self.email.provider_timeout_ms)?;
+        env("DEVCLECTIC_EMAIL_PROVIDER_POLICY", &mut self.email.provider_policy)?;
+
+        env("DEVCLECTIC_SMTP_HOST", &mut self.smtp.host)?;
+        env_opt("DEVCLECTIC_SMTP_PORT", &mut self.smtp.port)?;
+        env("DEVCLECTIC_SMTP_TLS", &mut self.smtp.tls)?;
+        env_opt("DEVCLECTIC_SMTP_USER", &mut self.smtp.user)?;
+        env_opt("DEVCLECTIC_SMTP_PASSWORD", &mut self.smtp.password)?;
+        env("DEVCLECTIC_MAIL_FROM", &mut self.smtp.from)?;
+
+        env_opt("DEVCLECTIC_TOKEN_SECRET", &mut self.tokens.secret)?;
+
+        env_opt("DEVCLECTIC_KEY_FILE", &mut self.keys.file)?;
+        env_opt("DEVCLECTIC_COOKIE_KEY", &mut self.keys.current)?;
+        if let Some(list) = var("DEVCLECTIC_PREVIOUS_COOKIE_KEYS")? {
+            self.keys.previous = split_list(&list);
+        }
+
+        env("DEVCLECTIC_TOTP_ISSUER", &mut self.two_factor.issuer)?;
+        env_opt("DEVCLECTIC_TOTP_KEY", &mut self.two_factor.key)?;
+        env("DEVCLECTIC_TOTP_REQUIRED_FOR_ADMINS", &mut self.two_factor.required_for_admins)?;
+
+        env("DEVCLECTIC_SCHEDULER_INTERVAL_SECS", &mut self.scheduler.interval_secs)?;
+        Ok(())
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(listen) = &cli.listen {
+            self.server.listen = listen.to_owned();
+        }
+        if let Some(path) = &cli.database {
+            self.database.path = path.to_owned();
+        }
+        if let Some(path) = &cli.templates {
+            self.paths.templates = path.to_owned();
+        }
+        if let Some(path) = &cli.static_files {
+            self.paths.static_files = path.to_owned();
+        }
+        if let Some(domain) = &cli.cookie_domain {
+            self.cookie.domain = Some(domain.to_owned());
+        }
+        if cli.cookie_secure {
+            self.cookie.secure = true;
+        }
+        if let Some(lifetime) = cli.session_lifetime {
+            self.session.lifetime_secs = lifetime;
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let listen_ok = self.server.listen
+            .to_socket_addrs()
+            .is_ok_and(|mut addrs| addrs.next().is_some());
+        if !listen_ok {
+            return Err(format!("server.listen: '{}' is not a valid address, expected host:port", self.server.listen));
+        }
+        if !self.server.base_url.starts_with("http://") && !self.server.base_url.starts_with("https://") {
+            return Err(format!("server.base_url: '{}' must start with http:// or https://", self.server.base_url));
+        }
+
+        let db_dir = self.database.path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        if let Some(dir) = db_dir {
+            if !dir.is_dir() {
+                return Err(format!("database.path: directory '{}' does not exist", dir.display()));
+            }
+        }
+        dir_exists("paths.templates", &self.paths.templates)?;
+        dir_exists("paths.static_files", &self.paths.static_files)?;
+
+        let name_ok = !self.cookie.name.is_empty()
+            && self.cookie.name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if !name_ok {
+            return Err(format!("cookie.name: '{}' may only contain letters, digits, '-', '_' and '.'", self.cookie.name));
+        }
+        if self.cookie.domain.as_deref() == Some("") {
+            return Err("cookie.domain: must not be empty, leave it out instead".to_string());
+        }
+        if self.session.lifetime_secs <= 0 {
+            return Err(format!("session.lifetime_secs: must be positive, got {}", self.session.lifetime_secs));
+        }
+        if self.login.max_failures == 0 {
+            return Err("login.max_failures: must be at least 1".to_string());
+        }
+        match self.login.backoff_max_secs.checked_mul(1000) {
+            None => return Err(format!("login.backoff_max_secs: must be at most {}", u64::MAX / 1000)),
+            Some(max_ms) if self.login.backoff_base_ms > max_ms => {
+                return Err("login.backoff_base_ms: must not exceed login.backoff_max_secs".to_string());
+            }
+            Some(_) => {}
+        }
+
+        self.password.validate().map_err(|err| format!("password: {}", err))?;
+        if self.tokens.secret.as_ref().is_some_and(|secret| secret.len() < 32) {
+            return Err("tokens.secret: must be at least 32 characters long".to_string());
+        }
+        if self.two_factor.key.as_ref().is_some_and(|key| key.len() < 32) {
+            return Err("two_factor.key: must be at least 32 characters long".to_string());
+        }
+        if self.two_factor.issuer.is_empty() || self.two_factor.issuer.contains(':') {
+            return Err(format!("two_factor.issuer: '{}' must be non-empty and not contain ':'", self.two_factor.issuer));
+        }
+        if self.scheduler.interval_secs == 0 {
+            return Err("scheduler.interval_secs: must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn dir_exists(key: &str, path: &Path) -> Result<(), String> {
+    if path.is_dir() {
+        Ok(())
+    } else {
+        Err(format!("{}: directory '{}' does not exist", key, path.display()))
+    }
+}
+
+fn var(name: &str) -> Result<Option<String>, String> {
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotUnicode(_)) => Err(format!("{}: value is not valid UTF-8", name)),
+    }
+}
+
+/// Overrides `field` with the parsed value of the variable `name`, when set.
+fn env<T>(name: &str, field: &mut T) -> Result<(), String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = var(name)? {
+        *field = value.parse().map_err(|err| format!("{}: invalid value '{}': {}", name, value, err))?;
+    }
+    Ok(())
+}
+
+fn env_opt<T>(name: &str, field: &mut Option<T>) -> Result<(), String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = var(name)? {
+        *field = Some(value.parse().map_err(|err| format!("{}: invalid value '{}': {}", name, value, err))?);
+    }
+    Ok(())
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use super::*;
+
+    // The environment is shared by every test thread
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Writes `text` to a config file in a directory of its own, next to an empty `db` directory.
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("devclectic-config-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("devclectic.toml");
+        std::fs::write(&path, text).unwrap();
+        std::fs::create_dir_all(dir.join("db")).unwrap();
+        path
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(["devclectic-server"].iter().chain(args))
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let path = config_file("layers", &format!(
+            "[server]\nlisten = \"127.0.0.1:1001\"\nbase_url = \"https://file.example\"\n\
+             [session]\nlifetime_secs = 100\n\
+             [database]\npath = \"db/data.sqlite\"\n\
+             [paths]\ntemplates = '{}'\nstatic_files = '{}'\n",
+            checkout("templates").display(), checkout("static").display(),
+        ));
+        let path_arg = path.to_str().unwrap();
+
+        let config = Config::load(&cli(&["--config", path_arg])).unwrap();
+        assert_eq!(config.server.listen, "127.0.0.1:1001");
+        assert_eq!(config.session.lifetime_secs, 100);
+        // Relative paths are taken relative to the file
+        assert_eq!(config.database.path, path.parent().unwrap().join("db/data.sqlite"));
+
+        std::env::set_var("DEVCLECTIC_LISTEN", "127.0.0.1:1002");
+        std::env::set_var("DEVCLECTIC_SESSION_LIFETIME", "200");
+        let config = Config::load(&cli(&["--config", path_arg]));
+        let overridden = Config::load(&cli(&["--config", path_arg, "--listen", "127.0.0.1:1003"]));
+        std::env::remove_var("DEVCLECTIC_LISTEN");
+        std::env::remove_var("DEVCLECTIC_SESSION_LIFETIME");
+
+        let config = config.unwrap();
+        assert_eq!(config.server.listen, "127.0.0.1:1002");
+        assert_eq!(config.session.lifetime_secs, 200);
+        assert_eq!(config.server.base_url, "https://file.example");
+        let config = overridden.unwrap();
+        assert_eq!(config.server.listen, "127.0.0.1:1003");
+        assert_eq!(config.session.lifetime_secs, 200);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn resolves_paths_relative_to_the_file() {
+        let path = config_file("relative", "[database]\npath = \"db/data.sqlite\"\n[keys]\nfile = \"keys\"\n");
+        let config = Config::from_file(&path).unwrap();
+        let dir = path.parent().unwrap();
+        assert_eq!(config.database.path, dir.join("db/data.sqlite"));
+        assert_eq!(config.keys.file, Some(dir.join("keys")));
+        assert_eq!(config.paths.templates, dir.join(checkout("templates")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_layers() {
+        let path = config_file("unknown", "[server]\nport = 80\n");
+        assert!(Config::from_file(&path).unwrap_err().starts_with("Invalid config file"));
+        assert!(Config::from_file(&path.with_file_name("missing.toml")).unwrap_err().starts_with("Failed to read"));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::env::set_var("DEVCLECTIC_SESSION_LIFETIME", "a day");
+        let err = Config::default().apply_env();
+        std::env::remove_var("DEVCLECTIC_SESSION_LIFETIME");
+        assert!(err.unwrap_err().starts_with("DEVCLECTIC_SESSION_LIFETIME: invalid value 'a day'"));
+    }
+
+    #[test]
+    fn accepts_the_defaults() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        type Change = fn(&mut Config);
+        let cases: Vec<(&str, Change)> = vec![
+            ("server.listen", |config| config.server.listen = "localhost".to_string()),
+            ("server.base_url", |config| config.server.base_url = "example.com".to_string()),
+            ("database.path", |config| config.database.path = checkout("missing/data.sqlite")),
+            ("paths.templates", |config| config.paths.templates = checkout("missing")),
+            ("paths.static_files", |config| config.paths.static_files = checkout("missing")),
+            ("cookie.name", |config| config.cookie.name = "auth cookie".to_string()),
+            ("cookie.name", |config| config.cookie.name = String::new()),
+            ("cookie.domain", |config| config.cookie.domain = Some(String::new())),
+            ("session.lifetime_secs", |config| config.session.lifetime_secs = 0),
+            ("login.max_failures", |config| config.login.max_failures = 0),
+            ("login.backoff_base_ms", |config| config.login.backoff_base_ms = 61_000),
+            ("login.backoff_max_secs", |config| config.login.backoff_max_secs = u64::MAX / 999),
+            ("password", |config| config.password.iterations = 0),
+            ("tokens.secret", |config| config.tokens.secret = Some("short".to_string())),
+            ("two_factor.key", |config| config.two_factor.key = Some("short".to_string())),
+            ("two_factor.issuer", |config| config.two_factor.issuer = String::new()),
+            ("two_factor.issuer", |config| config.two_factor.issuer = "Dev:clectic".to_string()),
+            ("scheduler.interval_secs", |config| config.scheduler.interval_secs = 0),
+        ];
+        for (key, change) in cases {
+            let mut config = Config::default();
+            change(&mut config);
+            let err = config.validate().unwrap_err();
+            assert!(err.starts_with(&format!("{}:", key)), "expected a '{}' error, got '{}'", key, err);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::task::{Context, Poll};
+use actix_session::{Session, UserSession};
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::http::{header, Method};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use futures_util::future::{ok, ready, FutureExt, LocalBoxFuture, Ready};
+use futures_util::StreamExt;
+use rand::Rng;
+use subtle::ConstantTimeEq;
+use crate::error::AppError;
+use crate::identity;
+
+/// Session key holding the token.
+const SESSION_KEY: &str = "csrf_token";
+/// Form field carrying the token, `{{ csrf_token }}` in the templates.
+pub const FIELD: &str = "csrf_token";
+/// Header carrying the token for requests that aren't HTML forms.
+pub const HEADER: &str = "x-csrf-token";
+/// Largest form body read while looking for the token.
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes[..]);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Replaces the session's token, call it whenever the session changes hands
+/// (logging in or out) so a token seen before doesn't stay valid.
+pub fn renew(session: &Session) -> Result<(), Error> {
+    session.set(SESSION_KEY, new_token())
+}
+
+/// The token of the request's session, `None` before the middleware issued one.
+pub fn token(req: &HttpRequest) -> Option<String> {
+    req.get_session().get::<String>(SESSION_KEY).ok().flatten()
+}
+
+/// The session's anti-forgery token, for the templates.
+pub struct CsrfToken(pub String);
+
+impl CsrfToken {
+    /// A template context with `csrf_token` already set.
+    pub fn context(&self) -> tera::Context {
+        let mut ctx = tera::Context::new();
+        ctx.insert(FIELD, &self.0);
+        ctx
+    }
+}
+
+impl FromRequest for CsrfToken {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = token(req).ok_or_else(|| AppError::internal("CSRF middleware is not configured"));
+        ready(token.map(CsrfToken))
+    }
+}
+
+/// Middleware issuing a token per session and rejecting POST, PUT, PATCH and
+/// DELETE requests that don't echo it back, in the `csrf_token` form field or
+/// the `X-CSRF-Token` header.
+///
+/// Requests with an `Authorization: Bearer` header are let through, browsers
+/// never send that header on their own and the identity policy ignores the
+/// cookies of such requests.
+///
+/// Must be wrapped inside the session middleware and the error pages, a
+/// rejection is a plain 403 response for them to render.
+pub struct Csrf;
+
+impl<S, B> Transform<S> for Csrf
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    // Shared with the future, the body has to be read before the request is passed on
+    service: Rc<RefCell<S>>,
+}
+
+fn is_unsafe(method: &Method) -> bool {
+    matches!(*method, Method::POST | Method::PUT | Method::PATCH | Method::DELETE)
+}
+
+fn is_form(req: &ServiceRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"))
+}
+
+fn form_token(body: &[u8]) -> Option<String> {
+    serde_urlencoded::from_bytes::<Vec<(String, String)>>(body)
+        .ok()?
+        .into_iter()
+        .find(|(name, _)| name == FIELD)
+        .map(|(_, value)| value)
+}
+
+/// Reads the whole body, putting it back on the request for the handler.
+async fn peek_body(req: &mut ServiceRequest) -> Result<Bytes, PayloadError> {
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(PayloadError::Overflow);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+    let restored = body.clone();
+    req.set_payload(Payload::Stream(futures_util::stream::once(async move { Ok(restored) }).boxed_local()));
+    Ok(body)
+}
+
+impl<S, B> Service for CsrfMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let session = req.get_session();
+        let expected = match session.get::<String>(SESSION_KEY) {
+            Ok(Some(token)) => Some(token),
+            _ => {
+                if let Err(err) = renew(&session) {
+                    return async move { Err(err) }.boxed_local();
+                }
+                None
+            }
+        };
+
+        if !is_unsafe(req.method()) || identity::bearer_token(&req).is_some() {
+            return self.service.borrow_mut().call(req).boxed_local();
+        }
+
+        let service = self.service.clone();
+        async move {
+            let header = req.headers()
+                .get(HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            let received = match header {
+                Some(token) => Some(token),
+                None if is_form(&req) => match peek_body(&mut req).await {
+                    Ok(body) => form_token(&body),
+                    Err(err) => return Ok(req.error_response(err)),
+                },
+                None => None,
+            };
+
+            let valid = match (&expected, &received) {
+                (Some(expected), Some(received)) => bool::from(expected.as_bytes().ct_eq(received.as_bytes())),
+                _ => false,
+            };
+            if !valid {
+                log::warn!(target: "audit", "denied: {} {} without a valid CSRF token", req.method(), req.path());
+                let err = AppError::Forbidden("The form has expired, reload the page and try again".to_string());
+                return Ok(req.error_response(err));
+            }
+
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        }.boxed_local()
+    }
+}
@@ -0,0 +1,106 @@
+use serde::Serialize;
+
+/// Above this many line pairs the changed region isn't aligned any further,
+/// it is shown as removed and then added instead.
+const MAX_CELLS: usize = 4_000_000;
+
+/// One line of a diff.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "line", rename_all = "lowercase")]
+pub enum Change<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Line by line difference between `old` and `new`, following the longest common
+/// subsequence of lines.
+pub fn lines<'a>(old: &'a str, new: &'a str) -> Vec<Change<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (old_mid, new_mid) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut changes: Vec<Change> = old[..prefix].iter().map(|line| Change::Same(line)).collect();
+    if old_mid.len().saturating_mul(new_mid.len()) > MAX_CELLS {
+        changes.extend(old_mid.iter().map(|line| Change::Removed(line)));
+        changes.extend(new_mid.iter().map(|line| Change::Added(line)));
+    } else {
+        align(old_mid, new_mid, &mut changes);
+    }
+    changes.extend(old[old.len() - suffix..].iter().map(|line| Change::Same(line)));
+    changes
+}
+
+/// Whether the diff has anything but unchanged lines.
+pub fn changed(changes: &[Change]) -> bool {
+    changes.iter().any(|change| !matches!(change, Change::Same(_)))
+}
+
+fn align<'a>(old: &[&'a str], new: &[&'a str], changes: &mut Vec<Change<'a>>) {
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let width = new.len() + 1;
+    let mut common = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i * width + j] = if old[i] == new[j] {
+                common[(i + 1) * width + j + 1] + 1
+            } else {
+                common[(i + 1) * width + j].max(common[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            changes.push(Change::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if common[(i + 1) * width + j] >= common[i * width + j + 1] {
+            changes.push(Change::Removed(old[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Added(new[j]));
+            j += 1;
+        }
+    }
+    changes.extend(old[i..].iter().map(|line| Change::Removed(line)));
+    changes.extend(new[j..].iter().map(|line| Change::Added(line)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::Change::*;
+
+    #[test]
+    fn finds_changed_lines() {
+        let changes = lines("a\nb\nc\nd\ne", "a\nc\nx\nd\ne\nf");
+        assert_eq!(changes, vec![Same("a"), Removed("b"), Same("c"), Added("x"), Same("d"), Same("e"), Added("f")]);
+        assert!(changed(&changes));
+    }
+
+    #[test]
+    fn handles_empty_sides() {
+        assert_eq!(lines("", "a\nb"), vec![Added("a"), Added("b")]);
+        assert_eq!(lines("a\nb", ""), vec![Removed("a"), Removed("b")]);
+        assert!(lines("", "").is_empty());
+        assert!(!changed(&lines("same\ntext", "same\ntext")));
+    }
+
+    #[test]
+    fn replaces_huge_regions_wholesale() {
+        let old: String = (0..2100).map(|n| format!("old {}\n", n)).collect();
+        let new: String = (0..2100).map(|n| format!("new {}\n", n)).collect();
+        let (old, new) = (format!("top\n{}bottom", old), format!("top\n{}bottom", new));
+        let changes = lines(&old, &new);
+        assert_eq!(changes.len(), 2 + 2 * 2100);
+        assert_eq!(changes[0], Same("top"));
+        assert_eq!(changes[1], Removed("old 0"));
+        assert_eq!(changes[2101], Added("new 0"));
+        assert_eq!(changes[4201], Same("bottom"));
+    }
+}
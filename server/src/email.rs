@@ -0,0 +1,256 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::client::Client;
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use serde_json::Value;
+use crate::config::EmailConfig;
+
+/// Decides whether an email address may be used to register.
+pub trait EmailValidator: Send + Sync {
+    /// Resolves to `Err` with a user facing reason when the address is rejected.
+    fn validate<'a>(&'a self, email: &'a str) -> LocalBoxFuture<'a, Result<(), String>>;
+}
+
+/// Offline validator: syntax check followed by allow, deny and disposable domain lists.
+pub struct LocalValidator {
+    allow: HashSet<String>,
+    deny: HashSet<String>,
+    disposable: HashSet<String>,
+}
+
+impl LocalValidator {
+    pub fn new(allow: HashSet<String>, deny: HashSet<String>, disposable: HashSet<String>) -> Self {
+        LocalValidator { allow, deny, disposable }
+    }
+
+    /// Returns the lowercased domain of a syntactically valid address.
+    fn check(&self, email: &str) -> Result<String, String> {
+        let domain = domain_of(email).ok_or_else(|| "Invalid email".to_string())?;
+
+        if self.allow.contains(&domain) {
+            return Ok(domain);
+        }
+        if self.deny.contains(&domain) || self.disposable.contains(&domain) {
+            return Err("Email domain is not allowed".to_string());
+        }
+        Ok(domain)
+    }
+}
+
+impl EmailValidator for LocalValidator {
+    fn validate<'a>(&'a self, email: &'a str) -> LocalBoxFuture<'a, Result<(), String>> {
+        let res = self.check(email).map(|_| ());
+        async move { res }.boxed_local()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Accept the address when the provider cannot be reached
+    Open,
+    /// Reject the address when the provider cannot be reached
+    Closed,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "open" => Ok(FailurePolicy::Open),
+            "closed" => Ok(FailurePolicy::Closed),
+            other => Err(format!("unknown policy '{}', expected 'open' or 'closed'", other)),
+        }
+    }
+}
+
+/// Runs the local checks, then asks an HTTP provider about the domain.
+///
+/// The provider is expected to answer with a JSON object carrying a boolean
+/// `block` field, like the mailcheck API does.
+pub struct HttpValidator {
+    local: LocalValidator,
+    url: String,
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+    policy: FailurePolicy,
+}
+
+impl HttpValidator {
+    /// `url` may contain `{domain}` and `{email}` placeholders.
+    pub fn new(local: LocalValidator, url: String, headers: Vec<(String, String)>, timeout: Duration, policy: FailurePolicy) -> Self {
+        HttpValidator { local, url, headers, timeout, policy }
+    }
+
+    async fn ask_provider(&self, email: &str, domain: &str) -> Result<bool, String> {
+        let url = provider_url(&self.url, email, domain);
+        let mut req = Client::default().get(url).timeout(self.timeout);
+        for (name, value) in &self.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+
+        let mut resp = req.send().await.map_err(|err| err.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("provider answered {}", resp.status()));
+        }
+        let body = resp.body().await.map_err(|err| err.to_string())?;
+        let json: Value = serde_json::from_slice(&body).map_err(|err| err.to_string())?;
+        json.get("block")
+            .and_then(Value::as_bool)
+            .ok_or_else(|| "provider response has no 'block' field".to_string())
+    }
+}
+
+/// Fills the placeholders of `template`. The values are percent-encoded, local parts may
+/// contain `&`, `=`, `#` and `?`, which would otherwise change the url.
+fn provider_url(template: &str, email: &str, domain: &str) -> String {
+    template
+        .replace("{domain}", &utf8_percent_encode(domain, NON_ALPHANUMERIC).to_string())
+        .replace("{email}", &utf8_percent_encode(email, NON_ALPHANUMERIC).to_string())
+}
+
+impl EmailValidator for HttpValidator {
+    fn validate<'a>(&'a self, email: &'a str) -> LocalBoxFuture<'a, Result<(), String>> {
+        async move {
+            let domain = self.local.check(email)?;
+            if self.local.allow.contains(&domain) {
+                return Ok(());
+            }
+
+            match self.ask_provider(email, &domain).await {
+                Ok(false) => Ok(()),
+                Ok(true) => Err("Invalid email".to_string()),
+                Err(err) => {
+                    log::warn!("Email provider unavailable: {}", err);
+                    match self.policy {
+                        FailurePolicy::Open => Ok(()),
+                        FailurePolicy::Closed => Err("Email could not be verified, try again later".to_string()),
+                    }
+                }
+            }
+        }.boxed_local()
+    }
+}
+
+/// Builds the validator, with the HTTP provider when `provider_url` is set.
+pub fn from_config(config: &EmailConfig) -> Result<Arc<dyn EmailValidator>, String> {
+    let disposable = std::fs::read_to_string(&config.blocklist)
+        .map_err(|err| format!("Failed to read email blocklist '{}': {}", config.blocklist.display(), err))?;
+
+    let local = LocalValidator::new(
+        domain_list(&config.allow),
+        domain_list(&config.deny),
+        domain_list(disposable.lines().filter(|line| !line.trim_start().starts_with('#'))),
+    );
+
+    let url = match &config.provider_url {
+        Some(url) => url.to_owned(),
+        None => return Ok(Arc::new(local)),
+    };
+    let headers = config.provider_headers.iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+
+    Ok(Arc::new(HttpValidator::new(
+        local,
+        url,
+        headers,
+        Duration::from_millis(config.provider_timeout_ms),
+        config.provider_policy,
+    )))
+}
+
+fn domain_list<I, S>(domains: I) -> HashSet<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    domains.into_iter()
+        .map(|domain| domain.as_ref().trim().to_string())
+        .filter(|domain| !domain.is_empty())
+        .map(|domain| domain.to_lowercase())
+        .collect()
+}
+
+/// Loose RFC 5321 syntax check, returns the lowercased domain.
+fn domain_of(email: &str) -> Option<String> {
+    let (local, domain) = email.trim().rsplit_once('@')?;
+
+    let local_ok = !local.is_empty()
+        && local.len() <= 64
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c));
+    if !local_ok || domain.len() > 253 {
+        return None;
+    }
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let labels_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && labels.last().is_some_and(|tld| tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic()));
+
+    if labels_ok {
+        Some(domain.to_lowercase())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator() -> LocalValidator {
+        LocalValidator::new(
+            domain_list(["Partner.example"]),
+            domain_list(["spam.example"]),
+            domain_list(["mailinator.com", "  ", "partner.example"]),
+        )
+    }
+
+    #[test]
+    fn checks_address_syntax() {
+        assert_eq!(domain_of(" Bob@Example.COM ").as_deref(), Some("example.com"));
+        assert_eq!(domain_of("a+b&c=d#e?f@mail.example.org").as_deref(), Some("mail.example.org"));
+        assert_eq!(domain_of("\"quoted@x\"@example.com"), None);
+        for email in [
+            "", "plain", "@example.com", "bob@", "bob@localhost", "bob@example.c", "bob@example.123",
+            ".bob@example.com", "bob.@example.com", "b..ob@example.com", "bob@-example.com", "bob@example..com",
+            "bob@exa_mple.com", "bob smith@example.com",
+        ] {
+            assert_eq!(domain_of(email), None, "{:?} was accepted", email);
+        }
+        assert_eq!(domain_of(&format!("{}@example.com", "x".repeat(65))), None);
+        assert!(domain_of(&format!("{}@example.com", "x".repeat(64))).is_some());
+    }
+
+    #[test]
+    fn applies_domain_lists() {
+        let validator = validator();
+        assert_eq!(validator.check("bob@example.com"), Ok("example.com".to_string()));
+        assert!(validator.check("bob@SPAM.example").is_err());
+        assert!(validator.check("bob@mailinator.com").is_err());
+        // The allow list wins over the disposable list
+        assert_eq!(validator.check("bob@partner.example"), Ok("partner.example".to_string()));
+        assert_eq!(validator.check("not an address"), Err("Invalid email".to_string()));
+    }
+
+    #[test]
+    fn encodes_provider_url() {
+        let url = provider_url("https://api.example/check?email={email}&domain={domain}", "a&b=c#d?e+f@x.example", "x.example");
+        assert_eq!(url, "https://api.example/check?email=a%26b%3Dc%23d%3Fe%2Bf%40x%2Eexample&domain=x%2Eexample");
+    }
+}
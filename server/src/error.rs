@@ -0,0 +1,201 @@
+use std::fmt;
+use actix_identity::RequestIdentity;
+use actix_web::dev::{Body, ResponseBody, ServiceResponse};
+use actix_web::error::BlockingError;
+use actix_web::http::{header, HeaderValue, StatusCode};
+use actix_web::middleware::errhandlers::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use r2d2_sqlite::rusqlite;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::csrf;
+
+/// Error type shared by the repository and the handlers.
+///
+/// Everything but `Internal` carries a message that is safe to show to the
+/// visitor. `Internal` keeps the cause for the logs only.
+#[derive(Debug)]
+pub enum AppError {
+    NotFound(String),
+    Conflict(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Validation(String),
+    Internal(String),
+}
+
+/// JSON body of error responses.
+#[derive(Serialize, ToSchema)]
+#[schema(as = Error)]
+pub struct ErrorBody<'a> {
+    /// `not_found`, `conflict`, `unauthorized`, `forbidden`, `validation` or `internal`
+    #[schema(example = "not_found")]
+    error: &'a str,
+    message: String,
+}
+
+impl AppError {
+    /// Logs `cause` and wraps it, use for failures the visitor can't do anything about.
+    pub fn internal(cause: impl fmt::Display) -> AppError {
+        log::error!("{}", cause);
+        AppError::Internal(cause.to_string())
+    }
+
+    /// Machine readable name used in JSON bodies.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::Validation(_) => "validation",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    /// The message including the cause of internal errors, for logs and the console.
+    pub fn detail(&self) -> &str {
+        match self {
+            AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::Validation(message)
+            | AppError::Internal(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::Validation(message) => write!(f, "{}", message),
+            AppError::Internal(_) => write!(f, "Something went wrong on our side, try again later"),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: self.kind(),
+            message: self.to_string(),
+        })
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(err: rusqlite::Error) -> Self {
+        match err {
+            rusqlite::Error::QueryReturnedNoRows => AppError::NotFound("The requested item was not found".to_string()),
+            err => AppError::internal(format!("Database error: {}", err)),
+        }
+    }
+}
+
+impl From<r2d2::Error> for AppError {
+    fn from(err: r2d2::Error) -> Self {
+        AppError::internal(format!("Database pool error: {}", err))
+    }
+}
+
+impl From<tera::Error> for AppError {
+    fn from(err: tera::Error) -> Self {
+        AppError::internal(format!("Template error: {:?}", err))
+    }
+}
+
+impl From<actix_web::Error> for AppError {
+    fn from(err: actix_web::Error) -> Self {
+        AppError::internal(err)
+    }
+}
+
+impl From<BlockingError<AppError>> for AppError {
+    fn from(err: BlockingError<AppError>) -> Self {
+        match err {
+            BlockingError::Error(err) => err,
+            BlockingError::Canceled => AppError::internal("Blocking task was canceled"),
+        }
+    }
+}
+
+/// API clients get the JSON body, everyone else gets an HTML page.
+fn wants_json(req: &HttpRequest) -> bool {
+    if req.path().starts_with("/api/") {
+        return true;
+    }
+    let accept = req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or("");
+    accept.contains("application/json") && !accept.contains("text/html")
+}
+
+/// Middleware rendering error responses with `error.html`.
+pub fn error_pages<B: 'static>() -> ErrorHandlers<B> {
+    [
+        StatusCode::BAD_REQUEST,
+        StatusCode::UNAUTHORIZED,
+        StatusCode::FORBIDDEN,
+        StatusCode::NOT_FOUND,
+        StatusCode::METHOD_NOT_ALLOWED,
+        StatusCode::CONFLICT,
+        StatusCode::UNPROCESSABLE_ENTITY,
+        StatusCode::INTERNAL_SERVER_ERROR,
+    ]
+    .iter()
+    .fold(ErrorHandlers::new(), |handlers, status| handlers.handler(*status, render_error_page))
+}
+
+fn render_error_page<B>(mut res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    // Responses that aren't errors already are pages built by the handler
+    let message = match res.response().error() {
+        Some(err) => match err.as_error::<AppError>() {
+            Some(err) => err.to_string(),
+            None => res.status().canonical_reason().unwrap_or("Error").to_string(),
+        },
+        None => return Ok(ErrorHandlerResponse::Response(res)),
+    };
+    if wants_json(res.request()) {
+        return Ok(ErrorHandlerResponse::Response(res));
+    }
+
+    let tmpl = match res.request().app_data::<web::Data<tera::Tera>>() {
+        Some(tmpl) => tmpl.clone(),
+        None => return Ok(ErrorHandlerResponse::Response(res)),
+    };
+    let mut ctx = tera::Context::new();
+    // For the logout form, the page is rendered outside the CSRF middleware
+    ctx.insert(csrf::FIELD, &csrf::token(res.request()).unwrap_or_default());
+    ctx.insert("is_loggedin", &res.request().get_identity().is_some());
+    ctx.insert("status", &res.status().as_u16());
+    ctx.insert("reason", res.status().canonical_reason().unwrap_or("Error"));
+    ctx.insert("message", &message);
+
+    let body = match tmpl.render("error.html", &ctx) {
+        Ok(body) => body,
+        Err(err) => {
+            log::error!("Template error: {:?}", err);
+            return Ok(ErrorHandlerResponse::Response(res));
+        }
+    };
+
+    res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    Ok(ErrorHandlerResponse::Response(res.map_body(|_, _| ResponseBody::Other(Body::from(body)))))
+}
@@ -0,0 +1,114 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use actix_identity::{CookieIdentityPolicy, IdentityPolicy};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{web, Error, HttpMessage};
+use chrono::Utc;
+use futures_util::future::{ok, FutureExt, LocalBoxFuture, Ready};
+use crate::authz::Scope;
+use crate::repo::{self, Pool};
+use crate::token;
+
+/// Cookie identity bound to the user's session stamp, or a personal API token.
+///
+/// The cookie holds `stamp:username`. Handlers only ever see the username, and
+/// the cookie stops being accepted once the stamp stored for the user changes,
+/// which is how every session of a user gets logged out at once.
+///
+/// Requests with an `Authorization: Bearer` header are identified by the token
+/// alone, the cookie is ignored. The token's scopes are left in the request
+/// extensions as `TokenScopes`.
+pub struct StampedIdentityPolicy {
+    inner: CookieIdentityPolicy,
+    pool: Pool,
+}
+
+impl StampedIdentityPolicy {
+    pub fn new(inner: CookieIdentityPolicy, pool: Pool) -> Self {
+        StampedIdentityPolicy { inner, pool }
+    }
+}
+
+/// Value to pass to `Identity::remember` when logging a user in.
+pub fn stamped(stamp: &str, username: &str) -> String {
+    format!("{}:{}", stamp, username)
+}
+
+/// Scopes of the API token a request was authenticated with. Inserted for every
+/// request with a bearer token, and filled in once the token was found valid.
+#[derive(Debug, Clone, Default)]
+pub struct TokenScopes(Rc<RefCell<Option<Vec<Scope>>>>);
+
+impl TokenScopes {
+    pub fn get(&self) -> Option<Vec<Scope>> {
+        self.0.borrow().clone()
+    }
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub fn bearer_token<T: HttpMessage>(req: &T) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+}
+
+impl IdentityPolicy for StampedIdentityPolicy {
+    type Future = LocalBoxFuture<'static, Result<Option<String>, Error>>;
+    type ResponseFuture = Ready<Result<(), Error>>;
+
+    fn from_request(&self, request: &mut ServiceRequest) -> Self::Future {
+        if let Some(bearer) = bearer_token(request) {
+            let pool = self.pool.clone();
+            let scopes = TokenScopes::default();
+            request.extensions_mut().insert(scopes.clone());
+            let (method, path) = (request.method().clone(), request.path().to_string());
+            return async move {
+                let hash = token::digest(&bearer);
+                let grant = web::block(move || repo::use_api_token(pool.get()?, hash, Utc::now().timestamp())).await;
+                match grant {
+                    Ok(Some(grant)) => {
+                        *scopes.0.borrow_mut() = Some(grant.scopes.iter().filter_map(|name| Scope::from_name(name)).collect());
+                        Ok(Some(grant.username))
+                    }
+                    Ok(None) => {
+                        log::warn!(target: "audit", "denied: {} {} with an unknown or expired API token", method, path);
+                        Ok(None)
+                    }
+                    Err(_) => Ok(None),
+                }
+            }.boxed_local();
+        }
+
+        let cookie = self.inner.from_request(request);
+        let pool = self.pool.clone();
+
+        async move {
+            let value = match cookie.await? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            let (stamp, username) = match value.split_once(':') {
+                Some((stamp, username)) => (stamp.to_string(), username.to_string()),
+                None => return Ok(None),
+            };
+
+            let current = web::block(move || repo::get_session_stamp(pool.get()?, username.to_owned())
+                .map(|current| (current, username)))
+                .await;
+            match current {
+                Ok((current, username)) if current == stamp => Ok(Some(username)),
+                _ => Ok(None),
+            }
+        }.boxed_local()
+    }
+
+    fn to_response<B>(&self, identity: Option<String>, changed: bool, response: &mut ServiceResponse<B>) -> Self::ResponseFuture {
+        // Unchanged identities are the bare username, writing them back would drop the stamp
+        if changed {
+            self.inner.to_response(identity, changed, response)
+        } else {
+            ok(())
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, HeaderValue};
+use actix_web::Error;
+use futures_util::future::{ok, FutureExt, LocalBoxFuture, Ready};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use crate::config::KeysConfig;
+
+/// Cookie master keys. The current key seals every cookie sent out, previous
+/// keys are still accepted so a rotation doesn't log anyone out.
+#[derive(Clone)]
+pub struct KeyRing {
+    current: Vec<u8>,
+    previous: Vec<Vec<u8>>,
+    /// False for the random key used when none are configured
+    persistent: bool,
+}
+
+/// Master keys shorter than this are rejected by the cookie crate.
+const MIN_KEY_BYTES: usize = 32;
+
+impl KeyRing {
+    /// Loads the keys from `keys.file` or `keys.current`/`keys.previous`. Without
+    /// either a random key is used, which logs everyone out on restart.
+    pub fn from_config(config: &KeysConfig) -> Result<KeyRing, String> {
+        let encoded = match (&config.file, &config.current) {
+            (Some(_), Some(_)) => return Err("keys: set either 'file' or 'current', not both".to_string()),
+            (Some(path), None) => read_key_file(path)
+                .map_err(|err| format!("keys.file: {}", err))?,
+            (None, Some(current)) => std::iter::once(current.to_owned())
+                .chain(config.previous.iter().cloned())
+                .collect(),
+            (None, None) => {
+                log::warn!("No cookie keys are configured, everyone is logged out when the server restarts");
+                return Ok(KeyRing {
+                    current: random_key(),
+                    previous: Vec::new(),
+                    persistent: false,
+                });
+            }
+        };
+
+        let mut keys = encoded.iter()
+            .map(|key| decode(key))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter();
+        let current = keys.next().ok_or_else(|| "keys.file: no key found".to_string())?;
+        Ok(KeyRing { current, previous: keys.collect(), persistent: true })
+    }
+
+    /// Key for the cookie of `purpose`, derived from the current master key.
+    pub fn subkey(&self, purpose: &str) -> Vec<u8> {
+        subkey(&self.current, purpose)
+    }
+
+    /// Whether the keys survive a restart.
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+
+    /// Keys of `purpose` derived from every master key, the current one first.
+    pub fn subkeys(&self, purpose: &str) -> Vec<Vec<u8>> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .map(|master| subkey(master, purpose))
+            .collect()
+    }
+}
+
+/// What the identity cookie key is derived for.
+pub const IDENTITY: &str = "identity";
+/// What the session cookie key is derived for.
+pub const SESSION: &str = "session";
+/// What the TOTP secret encryption key is derived for, without `two_factor.key`.
+pub const TWO_FACTOR: &str = "two-factor";
+/// What the key of emailed links is derived for, without `tokens.secret`.
+pub const LINKS: &str = "links";
+
+/// Derives the 64 byte key of `purpose` from `master`, so no two cookies are sealed
+/// with the same key.
+fn subkey(master: &[u8], purpose: &str) -> Vec<u8> {
+    [1u8, 2].iter()
+        .flat_map(|block| {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(master).expect("HMAC accepts keys of any size");
+            mac.update(b"devclectic cookie key\0");
+            mac.update(purpose.as_bytes());
+            mac.update(&[*block]);
+            mac.finalize().into_bytes()
+        })
+        .collect()
+}
+
+fn random_key() -> Vec<u8> {
+    let mut key = vec![0; 64];
+    rand::thread_rng().fill(&mut key[..]);
+    key
+}
+
+/// A new random key, base64 encoded.
+pub fn generate() -> String {
+    base64::encode(random_key())
+}
+
+/// Puts `key` at the top of the key file, making it the current key. At most
+/// `keep` previous keys are kept when given.
+pub fn rotate_file(path: &Path, key: &str, keep: Option<usize>) -> Result<(), String> {
+    let previous = if path.exists() {
+        read_key_file(path).map_err(|err| format!("'{}': {}", path.display(), err))?
+    } else {
+        Vec::new()
+    };
+    let keep = keep.unwrap_or(previous.len());
+
+    let mut contents = String::from("# Cookie keys, newest first. Only the first one signs new cookies.\n");
+    for key in std::iter::once(key).chain(previous.iter().take(keep).map(String::as_str)) {
+        contents.push_str(key);
+        contents.push('\n');
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|err| format!("Failed to write '{}': {}", path.display(), err))
+}
+
+/// One base64 key per line, blank lines and `#` comments are skipped.
+fn read_key_file(path: &Path) -> Result<Vec<String>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("failed to read '{}': {}", path.display(), err))?;
+    Ok(contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn decode(key: &str) -> Result<Vec<u8>, String> {
+    let bytes = base64::decode(key.trim()).map_err(|_| "keys: a key is not valid base64".to_string())?;
+    if bytes.len() < MIN_KEY_BYTES {
+        return Err(format!("keys: a key is {} bytes long, at least {} are required", bytes.len(), MIN_KEY_BYTES));
+    }
+    Ok(bytes)
+}
+
+/// How a cookie is protected, matching what its middleware does.
+#[derive(Clone, Copy)]
+pub enum Sealing {
+    /// `CookieSession::signed`
+    Signed,
+    /// `CookieIdentityPolicy`, encrypted with a second key once a visit or login deadline is set
+    Identity,
+}
+
+impl Sealing {
+    /// The cookie keys the middleware derives from `key`.
+    fn keys(self, key: &[u8]) -> Vec<Key> {
+        match self {
+            Sealing::Signed => vec![Key::derive_from(key)],
+            Sealing::Identity => {
+                let with_deadlines: Vec<u8> = key.iter().chain(&[1, 0, 0, 0]).copied().collect();
+                vec![Key::derive_from(key), Key::derive_from(&with_deadlines)]
+            }
+        }
+    }
+}
+
+/// A cookie kept valid across key rotations. The template carries the
+/// attributes the cookie is set with, `purpose` what its key is derived for.
+#[derive(Clone)]
+pub struct RotatedCookie {
+    pub template: Cookie<'static>,
+    pub sealing: Sealing,
+    pub purpose: &'static str,
+}
+
+impl RotatedCookie {
+    fn open(&self, cookie: &Cookie<'static>, key: &Key) -> Option<Cookie<'static>> {
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie.clone());
+        match self.sealing {
+            Sealing::Signed => jar.signed(key).get(self.template.name()),
+            Sealing::Identity => jar.private(key).get(self.template.name()),
+        }
+    }
+
+    fn seal(&self, value: &str, key: &Key) -> Option<Cookie<'static>> {
+        let mut cookie = self.template.clone();
+        cookie.set_value(value.to_string());
+        let mut jar = CookieJar::new();
+        match self.sealing {
+            Sealing::Signed => jar.signed(key).add(cookie),
+            Sealing::Identity => jar.private(key).add(cookie),
+        }
+        jar.get(self.template.name()).cloned()
+    }
+}
+
+/// A rotated cookie with the keys it is sealed with. Every entry of `previous`
+/// lines up with `current`, an opened cookie is sealed again with the same kind of key.
+struct Rotation {
+    cookie: RotatedCookie,
+    current: Vec<Key>,
+    previous: Vec<Vec<Key>>,
+}
+
+impl Rotation {
+    /// The cookie sealed with the current key, when it only opens with a previous one.
+    fn reseal(&self, received: &Cookie<'static>) -> Option<Cookie<'static>> {
+        if self.current.iter().any(|key| self.cookie.open(received, key).is_some()) {
+            return None;
+        }
+        let (kind, opened) = self.previous.iter()
+            .find_map(|keys| keys.iter().enumerate().find_map(|(kind, key)| Some((kind, self.cookie.open(received, key)?))))?;
+        self.cookie.seal(opened.value(), &self.current[kind])
+    }
+}
+
+/// Middleware re-sealing cookies made with a previous key.
+///
+/// Must wrap the middlewares owning the cookies. The rewritten cookie is what
+/// they see on the request, and it is sent back unless they set it themselves.
+pub struct KeyRotation {
+    rotations: Rc<Vec<Rotation>>,
+}
+
+impl KeyRotation {
+    pub fn new(keys: &KeyRing, cookies: Vec<RotatedCookie>) -> Self {
+        let rotations = cookies.into_iter()
+            .map(|cookie| {
+                let sealing = cookie.sealing;
+                let previous = keys.previous.iter()
+                    .map(|master| sealing.keys(&subkey(master, cookie.purpose)))
+                    .collect();
+                Rotation {
+                    current: sealing.keys(&keys.subkey(cookie.purpose)),
+                    previous,
+                    cookie,
+                }
+            })
+            .collect();
+        KeyRotation { rotations: Rc::new(rotations) }
+    }
+}
+
+impl<S, B> Transform<S> for KeyRotation
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = KeyRotationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(KeyRotationMiddleware {
+            service,
+            rotations: self.rotations.clone(),
+        })
+    }
+}
+
+pub struct KeyRotationMiddleware<S> {
+    service: S,
+    rotations: Rc<Vec<Rotation>>,
+}
+
+impl<S> KeyRotationMiddleware<S> {
+    /// Cookies of the request that only open with a previous key, sealed with the current one.
+    fn resealed(&self, req: &ServiceRequest) -> Vec<Cookie<'static>> {
+        // Parsed by hand, `req.cookies()` would cache the stale values for the inner middlewares
+        let received: Vec<Cookie<'static>> = req.headers()
+            .get_all(header::COOKIE)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| Cookie::parse_encoded(pair.trim().to_string()).ok())
+            .collect();
+
+        self.rotations.iter()
+            .filter_map(|rotation| {
+                let cookie = received.iter().find(|cookie| cookie.name() == rotation.cookie.template.name())?;
+                rotation.reseal(cookie)
+            })
+            .collect()
+    }
+}
+
+impl<S, B> Service for KeyRotationMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let resealed = self.resealed(&req);
+        if resealed.is_empty() {
+            return self.service.call(req).boxed_local();
+        }
+
+        let header = req.headers()
+            .get_all(header::COOKIE)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .map(|pair| {
+                let name = pair.split('=').next().unwrap_or("").trim();
+                match resealed.iter().find(|cookie| cookie.name() == name) {
+                    Some(cookie) => Cookie::new(cookie.name(), cookie.value()).encoded().to_string(),
+                    None => pair.trim().to_string(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        if let Ok(header) = HeaderValue::from_str(&header) {
+            req.headers_mut().insert(header::COOKIE, header);
+        }
+
+        let fut = self.service.call(req);
+        async move {
+            let mut res = fut.await?;
+            for cookie in resealed {
+                let prefix = format!("{}=", cookie.name());
+                let already_set = res.headers()
+                    .get_all(header::SET_COOKIE)
+                    .any(|value| value.to_str().is_ok_and(|value| value.starts_with(&prefix)));
+                if already_set {
+                    continue;
+                }
+                if let Ok(value) = HeaderValue::from_str(&cookie.encoded().to_string()) {
+                    res.headers_mut().append(header::SET_COOKIE, value);
+                }
+            }
+            Ok(res)
+        }.boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_identity::{CookieIdentityPolicy, Identity, IdentityService};
+    use actix_session::{CookieSession, Session};
+    use actix_web::{test, web, App, HttpResponse};
+    use super::*;
+
+    fn ring(current: u8, previous: &[u8]) -> KeyRing {
+        KeyRing {
+            current: vec![current; 64],
+            previous: previous.iter().map(|key| vec![*key; 64]).collect(),
+            persistent: true,
+        }
+    }
+
+    fn rotated() -> Vec<RotatedCookie> {
+        vec![
+            RotatedCookie { template: Cookie::build("auth", "").path("/").finish(), sealing: Sealing::Identity, purpose: IDENTITY },
+            RotatedCookie { template: Cookie::build("actix-session", "").path("/").finish(), sealing: Sealing::Signed, purpose: SESSION },
+        ]
+    }
+
+    async fn login(id: Identity, session: Session) -> HttpResponse {
+        id.remember("alice".to_string());
+        session.set("visits", 1).unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    async fn whoami(id: Identity, session: Session) -> HttpResponse {
+        HttpResponse::Ok().body(format!("{:?} {:?}", id.identity(), session.get::<i32>("visits").unwrap()))
+    }
+
+    /// Sends the cookies to `path` of an app sealing with `keys`. Returns the body and
+    /// the rotated cookies set by the response.
+    async fn visit(keys: &KeyRing, deadline: bool, path: &str, cookies: &[Cookie<'static>]) -> (String, Vec<Cookie<'static>>) {
+        let mut policy = CookieIdentityPolicy::new(&keys.subkey(IDENTITY)).name("auth");
+        if deadline {
+            policy = policy.visit_deadline(time::Duration::hours(1));
+        }
+        let mut app = test::init_service(
+            App::new()
+                .wrap(IdentityService::new(policy))
+                .wrap(CookieSession::signed(&keys.subkey(SESSION)))
+                .wrap(KeyRotation::new(keys, rotated()))
+                .route("/login", web::get().to(login))
+                .route("/whoami", web::get().to(whoami))
+        ).await;
+        let mut req = test::TestRequest::get().uri(path);
+        for cookie in cookies {
+            req = req.cookie(cookie.clone());
+        }
+        let res = test::call_service(&mut app, req.to_request()).await;
+        let set: Vec<Cookie<'static>> = res.response().cookies()
+            .filter(|cookie| ["auth", "actix-session"].contains(&cookie.name()))
+            .map(|cookie| cookie.into_owned())
+            .collect();
+        let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        (body, set)
+    }
+
+    #[actix_rt::test]
+    async fn reseals_cookies_of_previous_keys() {
+        for deadline in [false, true] {
+            let (_, cookies) = visit(&ring(1, &[]), deadline, "/login", &[]).await;
+            assert_eq!(cookies.len(), 2);
+
+            let rotated = ring(2, &[1]);
+            let (body, resealed) = visit(&rotated, deadline, "/whoami", &cookies).await;
+            assert_eq!(body, "Some(\"alice\") Some(1)", "deadline: {}", deadline);
+            assert_eq!(resealed.len(), 2, "deadline: {}", deadline);
+
+            // The resealed cookies work once the old key is gone
+            let (body, _) = visit(&ring(2, &[]), deadline, "/whoami", &resealed).await;
+            assert_eq!(body, "Some(\"alice\") Some(1)", "deadline: {}", deadline);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn leaves_current_cookies_alone() {
+        let keys = ring(1, &[2]);
+        let (_, cookies) = visit(&keys, false, "/login", &[]).await;
+        let (body, set) = visit(&keys, false, "/whoami", &cookies).await;
+        assert_eq!(body, "Some(\"alice\") Some(1)");
+        assert!(set.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn rejects_cookies_of_unknown_keys() {
+        let (_, cookies) = visit(&ring(1, &[]), false, "/login", &[]).await;
+        let (body, set) = visit(&ring(2, &[3]), false, "/whoami", &cookies).await;
+        assert_eq!(body, "None None");
+        assert!(set.iter().all(|cookie| cookie.name() != "auth"));
+    }
+
+    #[test]
+    fn derives_a_key_per_cookie() {
+        let keys = ring(1, &[]);
+        assert_eq!(keys.subkey(IDENTITY).len(), 64);
+        assert_ne!(keys.subkey(IDENTITY), keys.subkey(SESSION));
+        assert_ne!(keys.subkey(IDENTITY), ring(2, &[]).subkey(IDENTITY));
+        assert_eq!(keys.subkey(SESSION), subkey(&[1; 64], SESSION));
+    }
+}
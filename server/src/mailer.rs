@@ -0,0 +1,81 @@
+use std::str::FromStr;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::Deserialize;
+use crate::config::SmtpConfig;
+use crate::error::AppError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Plain SMTP, for local catchers
+    None,
+    /// Upgrade the connection with STARTTLS
+    Starttls,
+    /// Implicit TLS
+    Tls,
+}
+
+impl FromStr for TlsMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "none" => Ok(TlsMode::None),
+            "starttls" => Ok(TlsMode::Starttls),
+            "tls" => Ok(TlsMode::Tls),
+            other => Err(format!("unknown TLS mode '{}', expected 'none', 'starttls' or 'tls'", other)),
+        }
+    }
+}
+
+/// Outbound mail over SMTP.
+pub struct Mailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+    base_url: String,
+}
+
+impl Mailer {
+    /// Builds the transport, links in emails are prefixed with `base_url`.
+    pub fn new(config: &SmtpConfig, base_url: &str) -> Result<Mailer, String> {
+        let host = config.host.as_str();
+        let mut builder = match config.tls {
+            TlsMode::None => SmtpTransport::builder_dangerous(host).port(1025),
+            TlsMode::Starttls => SmtpTransport::starttls_relay(host).map_err(|err| err.to_string())?,
+            TlsMode::Tls => SmtpTransport::relay(host).map_err(|err| err.to_string())?,
+        };
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(user), Some(password)) = (&config.user, &config.password) {
+            builder = builder.credentials(Credentials::new(user.to_owned(), password.to_owned()));
+        }
+
+        Ok(Mailer {
+            transport: builder.build(),
+            from: config.from.parse().map_err(|err| format!("smtp.from: invalid sender address '{}': {}", config.from, err))?,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Absolute url for `path`, which must start with a slash.
+    pub fn link(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Sends a plain text email. Blocks until the SMTP server accepts it.
+    pub fn send(&self, to: &str, subject: &str, body: String) -> Result<(), AppError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse().map_err(|err| AppError::Validation(format!("Invalid recipient '{}': {}", to, err)))?)
+            .subject(subject)
+            .body(body)
+            .map_err(AppError::internal)?;
+
+        self.transport.send(&message)
+            .map(|_| ())
+            .map_err(|err| AppError::internal(format!("Failed to send email: {}", err)))
+    }
+}
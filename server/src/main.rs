@@ -1,14 +1,36 @@
 mod routes;
 mod repo;
 mod models;
+mod password;
+mod authz;
+mod email;
+mod token;
+mod mailer;
+mod identity;
+mod error;
+mod config;
+mod migrate;
+mod keys;
+mod csrf;
+mod throttle;
+mod totp;
+mod markdown;
+mod scheduler;
+mod diff;
+mod taxonomy;
 
 use actix_session::CookieSession;
 use tera::Tera;
-use rand::Rng;
 use r2d2_sqlite::SqliteConnectionManager;
 use crate::repo::Pool;
+use crate::models::SlimUser;
+use crate::config::{Cli, Command, Config};
+use clap::Parser;
 use actix_files::Files;
 use actix_identity::{CookieIdentityPolicy, IdentityService};
+use crate::identity::StampedIdentityPolicy;
+use crate::keys::{KeyRing, KeyRotation, RotatedCookie, Sealing};
+use actix_web::cookie::Cookie;
 use actix_web::{App, HttpServer, web};
 use actix_web::middleware::Logger;
 
@@ -17,54 +39,210 @@ async fn main() -> std::io::Result<()> {
     // Initiates error logger
     env_logger::init();
 
+    // Configuration
+    let cli = Cli::parse();
+    if let Some(Command::GenerateKey { rotate, keep }) = &cli.command {
+        let key = keys::generate();
+        match rotate {
+            Some(path) => {
+                keys::rotate_file(path, &key, *keep)
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+                println!("Rotated the keys in '{}', restart the server to use the new key", path.display());
+            }
+            None => println!("{}", key),
+        }
+        return Ok(());
+    }
+
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Configuration error: {}", err);
+            std::process::exit(2);
+        }
+    };
+
     // Databas
-    let manager = SqliteConnectionManager::file(concat!(env!("CARGO_MANIFEST_DIR"), "/data.sqlite"));
-    let pool = Pool::new(manager).unwrap();
+    let manager = SqliteConnectionManager::file(&config.database.path);
+    let pool = Pool::new(manager)
+        .map_err(|err| std::io::Error::other(format!("Failed to open '{}': {}", config.database.path.display(), err)))?;
+    let applied = pool.get().map_err(error::AppError::from)
+        .and_then(|mut conn| migrate::run(&mut conn))
+        .map_err(|err| std::io::Error::other(err.detail().to_string()))?;
+    for migration in &applied {
+        println!("Applied migration {} ({})", migration.version, migration.name);
+    }
+    if let Some(Command::Migrate) = cli.command {
+        println!("Database schema is at version {}", migrate::latest());
+        return Ok(());
+    }
+
+    // Password hashing
+    let policy = config.password.clone();
+    let migrated = pool.get().map_err(error::AppError::from)
+        .and_then(|conn| repo::migrate_plaintext_passwords(conn, &policy))
+        .map_err(|err| std::io::Error::other(err.detail().to_string()))?;
+    if migrated > 0 {
+        println!("Hashed {} plaintext password(s)", migrated);
+    }
+    // Databases created before `create-admin` existed were seeded with this account
+    let seeded = pool.get().map_err(error::AppError::from)
+        .and_then(|conn| repo::get_user(conn, "root".to_string()));
+    if let Ok(root) = seeded {
+        if policy.verify("toor", &root.password) != password::Verification::Invalid {
+            log::warn!("The account 'root' still has the password 'toor', change it or delete the account");
+        }
+    }
+    if let Some(Command::CreateAdmin { username, email }) = cli.command {
+        if username.trim().is_empty() {
+            eprintln!("The username can't be empty");
+            std::process::exit(2);
+        }
+        let password = token::random_nonce();
+        let admin = policy.hash(&password)
+            .and_then(|hash| {
+                let user = SlimUser { username: username.clone(), email, password: hash, session_stamp: token::random_nonce(), two_factor: false };
+                pool.get().map_err(error::AppError::from).and_then(|conn| repo::create_admin(conn, user))
+            });
+        if let Err(err) = admin {
+            eprintln!("Failed to create '{}': {}", username, err.detail());
+            std::process::exit(1);
+        }
+        println!("Created the admin '{}' with the password {}", username, password);
+        println!("It is not shown again, change it after logging in");
+        return Ok(());
+    }
+
+    // Articles saved before their HTML was cached
+    let rendered = pool.get().map_err(error::AppError::from)
+        .and_then(repo::render_missing_html)
+        .map_err(|err| std::io::Error::other(err.detail().to_string()))?;
+    if rendered > 0 {
+        println!("Rendered {} article(s)", rendered);
+    }
+
+    // Scheduled articles
+    scheduler::Scheduler::new(pool.clone(), scheduler::SystemClock)
+        .start(std::time::Duration::from_secs(config.scheduler.interval_secs));
+
+    // Email validation
+    let validator = email::from_config(&config.email)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    // Emailed links
+    let mailer = web::Data::new(mailer::Mailer::new(&config.smtp, &config.server.base_url)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?);
+
+    // Templates
+    let templates = config.paths.templates.join("**").join("*");
+    let tera = Tera::new(&templates.to_string_lossy())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Failed to load templates: {:?}", err)))?;
 
     // Authorisation
-    let cookie_secret_key = rand::thread_rng().gen::<[u8; 32]>();
+    let keys = KeyRing::from_config(&config.keys)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let signer = token::Signer::from_secret(config.tokens.secret.as_deref(), &keys);
+
+    // Roles and permissions, shared by the workers
+    let permission_cache = web::Data::new(authz::PermissionCache::default());
+
+    // Two-factor authentication
+    let two_factor = web::Data::new(totp::TwoFactor::from_config(&config.two_factor, &keys));
+
+    // Failed logins, shared by the workers
+    let login_throttle = web::Data::new(throttle::LoginThrottle::new(config.login.clone()));
 
-    println!("Listening on: 127.0.0.1:8080");
+    println!("Listening on: {}", config.server.listen);
+    let listen = config.server.listen.to_owned();
     HttpServer::new(move || {
-        let tera = Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*")).unwrap();
+        let mut identity_policy = CookieIdentityPolicy::new(&keys.subkey(keys::IDENTITY))
+            .name(&config.cookie.name)
+            .path("/")
+            .max_age(config.session.lifetime_secs)
+            .secure(config.cookie.secure);
+        let mut identity_cookie = Cookie::build(config.cookie.name.to_owned(), "")
+            .path("/")
+            .max_age(time::Duration::seconds(config.session.lifetime_secs))
+            .secure(config.cookie.secure)
+            .http_only(true)
+            .finish();
+        if let Some(domain) = &config.cookie.domain {
+            identity_policy = identity_policy.domain(domain);
+            identity_cookie.set_domain(domain.to_owned());
+        }
+        let session_cookie = Cookie::build("actix-session", "")
+            .path("/")
+            .secure(config.cookie.secure)
+            .http_only(true)
+            .finish();
 
         App::new()
             .data(pool.clone())
-            .data(tera)
+            .data(policy.clone())
+            .app_data(web::Data::from(validator.clone()))
+            .data(signer.clone())
+            .app_data(mailer.clone())
+            .app_data(permission_cache.clone())
+            .app_data(login_throttle.clone())
+            .app_data(two_factor.clone())
+            .data(tera.clone())
+            // Rejects forged form posts, inside the error pages so they render the rejection
+            .wrap(csrf::Csrf)
+            // Error pages, inside the identity service so they know who is logged in
+            .wrap(error::error_pages())
             // Authorisation
-            .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(&cookie_secret_key)
-                    .name("auth")
-                    .path("/")
-                    .domain("127.0.0.1")
-                    .max_age(86400)
-                    .secure(false),
-            ))
+            .wrap(IdentityService::new(StampedIdentityPolicy::new(identity_policy, pool.clone())))
             .wrap(
-                CookieSession::signed(&[0; 32])
-                    .secure(false)
+                CookieSession::signed(&keys.subkey(keys::SESSION))
+                    .secure(config.cookie.secure)
             )
+            // Accepts cookies sealed with previous keys
+            .wrap(KeyRotation::new(&keys, vec![
+                RotatedCookie { template: identity_cookie, sealing: Sealing::Identity, purpose: keys::IDENTITY },
+                RotatedCookie { template: session_cookie, sealing: Sealing::Signed, purpose: keys::SESSION },
+            ]))
             // Error logging
             .wrap(Logger::default())
             // Services
             .service(routes::index)
             .service(routes::create_article)
+            .service(routes::article)
             .service(routes::post_new_article)
+            .service(routes::tag)
+            .service(routes::category)
             .service(
                 web::scope("/login")
                     .service(web::resource("")
                         .route(web::get().to(routes::auth::login_form))
-                        .route(web::post().to(routes::auth::login))
-            ))
+                        .route(web::post().to(routes::auth::login)))
+                    .service(web::resource("/2fa")
+                        .route(web::get().to(routes::auth::two_factor_form))
+                        .route(web::post().to(routes::auth::two_factor_login)))
+            )
             .service(
                 web::scope("/register")
                     .service(web::resource("")
                         .route(web::get().to(routes::auth::register_form))
                         .route(web::post().to(routes::auth::register))
             ))
+            .service(
+                web::scope("/password")
+                    .service(web::resource("/forgot")
+                        .route(web::post().to(routes::auth::forgot_password)))
+                    .service(web::resource("/reset/{token}")
+                        .route(web::get().to(routes::auth::reset_password_form))
+                        .route(web::post().to(routes::auth::reset_password_submit)))
+            )
+            .service(
+                web::scope("/verify")
+                    .service(web::resource("/resend")
+                        .route(web::post().to(routes::auth::resend_verification)))
+                    .service(web::resource("/{token}")
+                        .route(web::get().to(routes::auth::verify)))
+            )
             .service(
                 web::resource("/logout")
-                    .route(web::get().to(routes::auth::logout)
+                    .route(web::post().to(routes::auth::logout)
             ))
             .service(
                 web::scope("/dashboard")
@@ -72,31 +250,82 @@ async fn main() -> std::io::Result<()> {
                         .route(web::get().to(routes::dashboard::dashboard)))
                     .service(web::resource("/options")
                         .route(web::get().to(routes::dashboard::dashboard_options)))
+                    .service(web::scope("/two-factor")
+                        .service(web::resource("")
+                            .route(web::get().to(routes::dashboard::two_factor_page)))
+                        .service(web::resource("/setup")
+                            .route(web::post().to(routes::dashboard::two_factor_setup)))
+                        .service(web::resource("/enable")
+                            .route(web::post().to(routes::dashboard::two_factor_enable)))
+                        .service(web::resource("/recovery-codes")
+                            .route(web::post().to(routes::dashboard::two_factor_recovery_codes)))
+                        .service(web::resource("/disable")
+                            .route(web::post().to(routes::dashboard::two_factor_disable)))
+                    )
+                    .service(web::scope("/tokens")
+                        .service(web::resource("")
+                            .route(web::get().to(routes::dashboard::dashboard_tokens))
+                            .route(web::post().to(routes::dashboard::dashboard_token_create)))
+                        .service(web::resource("/revoke-all")
+                            .route(web::post().to(routes::dashboard::dashboard_token_revoke_all)))
+                        .service(web::resource("/revoke/{tid}")
+                            .route(web::post().to(routes::dashboard::dashboard_token_revoke)))
+                    )
                     .service(web::scope("/users")
                         .service(web::resource("")
                             .route(web::get().to(routes::dashboard::dashboard_users)))
                         .service(web::resource("/delete/{uid}")
-                            .route(web::get().to(routes::dashboard::dashboard_user_del)))
-                        .service(web::resource("/promote/{uid}")
-                            .route(web::get().to(routes::dashboard::dashboard_user_promote)))
-                        .service(web::resource("/demote/{uid}")
-                                 .route(web::get().to(routes::dashboard::dashboard_user_demote)))
+                            .route(web::post().to(routes::dashboard::dashboard_user_del)))
+                        .service(web::resource("/{uid}")
+                            .route(web::delete().to(routes::dashboard::dashboard_user_del)))
+                        .service(web::resource("/unlock/{uid}")
+                            .route(web::post().to(routes::dashboard::dashboard_user_unlock)))
+                        .service(web::resource("/role/{uid}")
+                            .route(web::post().to(routes::dashboard::dashboard_user_role)))
+                    )
+                    .service(web::scope("/categories")
+                        .service(web::resource("")
+                            .route(web::get().to(routes::dashboard::dashboard_categories))
+                            .route(web::post().to(routes::dashboard::dashboard_category_create)))
+                        .service(web::resource("/delete/{cid}")
+                            .route(web::post().to(routes::dashboard::dashboard_category_del)))
                     )
                     .service(web::scope("/articles")
                         .service(web::resource("")
                             .route(web::get().to(routes::dashboard::dashboard_articles))
                         )
+                        .service(web::resource("/preview")
+                            .route(web::post().to(routes::dashboard::dashboard_article_preview)))
                         .service(web::resource("{uid}")
                             .route(web::get().to(routes::dashboard::dashboard_article_focus))
                             .route(web::post().to(routes::dashboard::dashboard_article_post))
                         )
                         .service(web::resource("/delete/{uid}")
                             .route(web::post().to(routes::dashboard::dashboard_article_del)))
+                        .service(web::resource("/visibility/{uid}")
+                            .route(web::post().to(routes::dashboard::dashboard_article_visibility)))
+                        .service(web::resource("/status/{uid}")
+                            .route(web::post().to(routes::dashboard::dashboard_article_status)))
+                        .service(web::resource("/schedule/{uid}")
+                            .route(web::post().to(routes::dashboard::dashboard_article_schedule)))
+                        .service(web::resource("/history/{uid}")
+                            .route(web::get().to(routes::dashboard::dashboard_article_history)))
+                        .service(web::resource("/restore/{rid}")
+                            .route(web::post().to(routes::dashboard::dashboard_article_restore)))
                     )
             )
-            .service(Files::new("/", "./server/static/"))
+            .service(
+                web::resource("/api/openapi.json")
+                    .route(web::get().to(routes::api::openapi)))
+            .service(
+                web::scope(routes::api::PREFIX)
+                    .app_data(routes::api::json_config())
+                    .app_data(routes::api::query_config())
+                    .configure(routes::api::configure)
+            )
+            .service(Files::new("/", &config.paths.static_files))
     })
-    .bind("127.0.0.1:8080")?
+    .bind(listen)?
     .run()
     .await
 }
@@ -0,0 +1,102 @@
+use std::sync::OnceLock;
+use ammonia::Builder;
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+
+/// Footnote labels become element ids, prefixed so they can't clash with the page's own ids.
+const FOOTNOTE_PREFIX: &str = "fn-";
+
+/// Attributes kept on top of ammonia's defaults, their values are checked in `keep_attribute`.
+const EXTRA_ATTRIBUTES: &[(&str, &[&str])] = &[
+    ("code", &["class"]),
+    ("sup", &["class"]),
+    ("div", &["class", "id"]),
+];
+
+/// Decides the attributes that are only allowed with some values.
+fn keep_attribute(element: &str, attribute: &str, value: &str) -> bool {
+    match (element, attribute) {
+        // Fenced code blocks, `language-rust` and the like
+        ("code", "class") => value.strip_prefix("language-")
+            .is_some_and(|language| !language.is_empty() && language.chars().all(|c| c.is_ascii_alphanumeric() || "+-_#.".contains(c))),
+        ("sup", "class") => matches!(value, "footnote-reference" | "footnote-definition-label"),
+        ("div", "class") => value == "footnote-definition",
+        ("div", "id") => value.starts_with(FOOTNOTE_PREFIX),
+        _ => true,
+    }
+}
+
+/// The allowlist everything rendered goes through.
+fn sanitizer() -> &'static Builder<'static> {
+    static SANITIZER: OnceLock<Builder<'static>> = OnceLock::new();
+    SANITIZER.get_or_init(|| {
+        let mut builder = Builder::default();
+        for (tag, attributes) in EXTRA_ATTRIBUTES {
+            builder.add_tag_attributes(tag, attributes.iter());
+        }
+        builder.attribute_filter(|element, attribute, value| {
+            keep_attribute(element, attribute, value).then(|| value.into())
+        });
+        builder
+    })
+}
+
+fn footnote_label(label: CowStr) -> CowStr {
+    format!("{}{}", FOOTNOTE_PREFIX, label).into()
+}
+
+/// Renders CommonMark with tables, footnotes and strikethrough to sanitized HTML.
+/// Raw HTML in the source is allowed, but only what the sanitizer keeps of it.
+pub fn render(source: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES | Options::ENABLE_STRIKETHROUGH;
+    let events = Parser::new_ext(source, options).map(|event| match event {
+        Event::FootnoteReference(label) => Event::FootnoteReference(footnote_label(label)),
+        Event::Start(Tag::FootnoteDefinition(label)) => Event::Start(Tag::FootnoteDefinition(footnote_label(label))),
+        event => event,
+    });
+
+    let mut unsafe_html = String::with_capacity(source.len() * 3 / 2);
+    html::push_html(&mut unsafe_html, events);
+    sanitizer().clean(&unsafe_html).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_extensions() {
+        let html = render("A[^note]\n\n[^note]: Aside\n\n```rust\nfn main() {}\n```\n\n|a|b|\n|-|-|\n|1|2|\n\n~~gone~~");
+        assert!(html.contains(r##"<sup class="footnote-reference"><a href="#fn-note""##), "{}", html);
+        assert!(html.contains(r#"<div class="footnote-definition" id="fn-note">"#), "{}", html);
+        assert!(html.contains(r#"<code class="language-rust">"#), "{}", html);
+        assert!(html.contains("<table>") && html.contains("<td>2</td>"), "{}", html);
+        assert!(html.contains("<del>gone</del>"), "{}", html);
+    }
+
+    #[test]
+    fn strips_scripts() {
+        let sources = [
+            "<script>alert(1)</script>",
+            "<img src=x onerror=alert(1)>",
+            "[link](javascript:alert(1))",
+            "<a href=\"javascript:alert(1)\">x</a>",
+            "<iframe src=\"https://example.com\"></iframe>",
+            "<div id=\"main\" class=\"footnote-definition\" style=\"position:fixed\">x</div>",
+            "```\" onmouseover=\"alert(1)\n```",
+            "<code class=\"language-x onclick\">x</code>",
+        ];
+        for source in sources {
+            let html = render(source);
+            for needle in ["<script", "onerror", "javascript:", "<iframe", "id=\"main\"", "style=", "onmouseover", "onclick"] {
+                assert!(!html.contains(needle), "{:?} rendered to {:?}", source, html);
+            }
+        }
+    }
+
+    #[test]
+    fn strips_forms_and_styles() {
+        let html = render("<details><summary>s</summary>x</details><form><input></form><style>p{}</style>");
+        assert!(html.contains("<details>"), "{}", html);
+        assert!(!html.contains("<form") && !html.contains("<input") && !html.contains("<style"), "{}", html);
+    }
+}
@@ -0,0 +1,224 @@
+use chrono::Utc;
+use r2d2_sqlite::rusqlite::{params, Connection, OptionalExtension};
+use crate::error::AppError;
+
+/// A schema change compiled into the binary.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration, in the order they are applied. Never edit a released one,
+/// add a new file instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "email_verification",
+        sql: include_str!("../migrations/0002_email_verification.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "password_reset",
+        sql: include_str!("../migrations/0003_password_reset.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "roles",
+        sql: include_str!("../migrations/0004_roles.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "two_factor",
+        sql: include_str!("../migrations/0005_two_factor.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "api_tokens",
+        sql: include_str!("../migrations/0006_api_tokens.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "article_html",
+        sql: include_str!("../migrations/0007_article_html.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "article_status",
+        sql: include_str!("../migrations/0008_article_status.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "article_publish_at",
+        sql: include_str!("../migrations/0009_article_publish_at.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "article_revisions",
+        sql: include_str!("../migrations/0010_article_revisions.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "article_version",
+        sql: include_str!("../migrations/0011_article_version.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "tags_categories",
+        sql: include_str!("../migrations/0012_tags_categories.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "email_nocase",
+        sql: include_str!("../migrations/0013_email_nocase.sql"),
+    },
+];
+
+/// Version of the newest migration.
+pub fn latest() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// Current schema version, 0 for an empty database.
+pub fn current(conn: &Connection) -> Result<i64, AppError> {
+    if !table_exists(conn, "schema_version")? {
+        return Ok(0);
+    }
+    Ok(conn.query_row("SELECT IFNULL(MAX(version), 0) FROM schema_version", [], |row| row.get(0))?)
+}
+
+/// Applies every pending migration, each in its own transaction.
+/// Returns the migrations that were applied.
+pub fn run(conn: &mut Connection) -> Result<Vec<&'static Migration>, AppError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version(
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        );"
+    )?;
+    adopt_unversioned(conn)?;
+
+    let current = current(conn)?;
+    if current > latest() {
+        return Err(AppError::internal(format!(
+            "Database schema version {} is newer than this binary supports ({})", current, latest()
+        )));
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql).map_err(|err| AppError::internal(format!(
+            "Migration {} ({}) failed: {}", migration.version, migration.name, err
+        )))?;
+        record(&tx, migration)?;
+        tx.commit()?;
+        log::info!("Applied migration {} ({})", migration.version, migration.name);
+        applied.push(migration);
+    }
+    Ok(applied)
+}
+
+/// Databases created with `db.sql` before migrations existed have tables but
+/// no recorded version. Their version is worked out from the columns present.
+fn adopt_unversioned(conn: &Connection) -> Result<(), AppError> {
+    let recorded: i64 = conn.query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))?;
+    if recorded > 0 || !table_exists(conn, "user")? {
+        return Ok(());
+    }
+
+    let version = if column_exists(conn, "user", "session_stamp")? {
+        3
+    } else if column_exists(conn, "user", "email")? {
+        2
+    } else {
+        1
+    };
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version <= version) {
+        record(conn, migration)?;
+    }
+    log::info!("Adopted unversioned database at schema version {}", version);
+    Ok(())
+}
+
+fn record(conn: &Connection, migration: &Migration) -> Result<(), AppError> {
+    conn.execute(
+        "INSERT INTO schema_version (version, name, applied_at) VALUES ($1, $2, $3)",
+        params![migration.version, migration.name, Utc::now().timestamp()]
+    )?;
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool, AppError> {
+    Ok(conn
+        .query_row("SELECT 1 FROM sqlite_master WHERE type='table' AND name=$1", [table], |_| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool, AppError> {
+    Ok(conn
+        .query_row("SELECT 1 FROM pragma_table_info($1) WHERE name=$2", [table, column], |_| Ok(()))
+        .optional()?
+        .is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_sequential() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i64 + 1, "migration '{}' is out of order", migration.name);
+        }
+    }
+
+    #[test]
+    fn migrates_empty_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        let applied = run(&mut conn).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(current(&conn).unwrap(), latest());
+
+        // The queries in repo.rs rely on these
+        let tables = [
+            "user", "article", "password_reset", "role", "permission", "role_permission", "recovery_code", "api_token",
+            "article_revision", "category", "tag", "article_tag",
+        ];
+        for table in tables {
+            assert!(table_exists(&conn, table).unwrap(), "table '{}' is missing", table);
+        }
+        for column in ["email", "email_verified", "verify_nonce", "session_stamp", "role_id", "totp_secret"] {
+            assert!(column_exists(&conn, "user", column).unwrap(), "column user.{} is missing", column);
+        }
+        // Admins are added with `create-admin`
+        let users: i64 = conn.query_row("SELECT COUNT(*) FROM user", [], |row| row.get(0)).unwrap();
+        assert_eq!(users, 0);
+
+        assert!(run(&mut conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn adopts_unversioned_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+        conn.execute_batch(MIGRATIONS[1].sql).unwrap();
+        conn.execute("INSERT INTO user(username, password, is_admin) VALUES ('root', 'toor', 1)", []).unwrap();
+
+        let applied = run(&mut conn).unwrap();
+        assert_eq!(applied.first().map(|migration| migration.version), Some(3));
+        assert_eq!(current(&conn).unwrap(), latest());
+        let root: String = conn.query_row(
+            "SELECT role.name FROM user JOIN role ON role.id = user.role_id WHERE user.username='root'", [], |row| row.get(0)
+        ).unwrap();
+        assert_eq!(root, "admin");
+    }
+}
@@ -1,16 +1,75 @@
+use std::fmt;
+use std::str::FromStr;
 use serde::{Serialize, Deserialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::error::AppError;
+use crate::taxonomy;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct User {
     pub id: i32,
     pub username: String,
-    pub password: String,
-    pub is_admin: bool,
+    pub email: Option<String>,
+    pub role: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Role {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RoleForm {
+    pub role: String,
 }
 
 pub struct SlimUser {
     pub username: String,
+    pub email: Option<String>,
     pub password: String,
+    pub session_stamp: String,
+    pub two_factor: bool,
+}
+
+/// Two-factor authentication state of an account.
+pub struct TwoFactorState {
+    /// Sealed TOTP secret, set from the start of the enrollment
+    pub secret: Option<String>,
+    pub enabled: bool,
+    pub recovery_codes: i64,
+}
+
+/// A personal API token as listed in the dashboard, the token itself is only shown when it is created.
+#[derive(Debug, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    pub expires_at: Option<i64>,
+}
+
+/// The owner and scopes of a valid API token.
+pub struct ApiTokenGrant {
+    pub id: i64,
+    pub username: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiTokenForm {
+    pub name: String,
+    /// Days until the token expires, "never" for no expiry
+    pub expires: String,
+    #[serde(default, rename = "articles:write")]
+    pub articles_write: bool,
+    #[serde(default, rename = "articles:moderate")]
+    pub articles_moderate: bool,
+    #[serde(default, rename = "users:manage")]
+    pub users_manage: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,6 +78,22 @@ pub struct LoginForm {
     pub password: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CodeForm {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForgotPasswordForm {
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResetPasswordForm {
+    pub password: String,
+    pub password_confirm: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RegisterForm {
     pub username: String,
@@ -28,22 +103,261 @@ pub struct RegisterForm {
 }
 
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Where an article is in its life. Only published articles are public.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ArticleStatus {
+    Draft,
+    InReview,
+    Published,
+    Archived,
+}
+
+impl ArticleStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ArticleStatus::Draft => "draft",
+            ArticleStatus::InReview => "in_review",
+            ArticleStatus::Published => "published",
+            ArticleStatus::Archived => "archived",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ArticleStatus::Draft => "Draft",
+            ArticleStatus::InReview => "In review",
+            ArticleStatus::Published => "Published",
+            ArticleStatus::Archived => "Archived",
+        }
+    }
+
+    /// The statuses an article can be moved to from this one.
+    pub fn next(self) -> &'static [ArticleStatus] {
+        match self {
+            ArticleStatus::Draft => &[ArticleStatus::InReview, ArticleStatus::Published],
+            ArticleStatus::InReview => &[ArticleStatus::Draft, ArticleStatus::Published],
+            ArticleStatus::Published => &[ArticleStatus::Draft, ArticleStatus::Archived],
+            ArticleStatus::Archived => &[ArticleStatus::Draft, ArticleStatus::Published],
+        }
+    }
+}
+
+impl fmt::Display for ArticleStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ArticleStatus {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "draft" => Ok(ArticleStatus::Draft),
+            "in_review" => Ok(ArticleStatus::InReview),
+            "published" => Ok(ArticleStatus::Published),
+            "archived" => Ok(ArticleStatus::Archived),
+            _ => Err(AppError::Validation(format!("Unknown status '{}'", value))),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct Article {
     pub id: i32,
     pub owner: String,
     pub title: String,
+    /// Markdown source
     pub description: String,
+    pub hidden: bool,
+    /// Sanitized HTML rendered from `description` when it was saved
+    pub html: String,
+    pub status: ArticleStatus,
+    /// Unix time the article gets published at, when it is scheduled
+    pub publish_at: Option<i64>,
+    /// Incremented by every save of the title or text
+    pub version: i64,
+    /// Sorted by name
+    pub tags: Vec<String>,
+    pub category: Option<ArticleCategory>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SlimArticle {
+/// The category an article is filed under.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ArticleCategory {
+    pub slug: String,
+    pub name: String,
+}
+
+/// A category, its parent is `None` for top level categories.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Category {
+    pub id: i32,
+    pub parent_id: Option<i32>,
+    pub name: String,
+    pub slug: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CategoryForm {
+    pub name: String,
+    /// Made from the name when empty
+    pub slug: String,
+    /// Empty for a top level category
+    pub parent: String,
+}
+
+/// A tag and the number of public articles that have it.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TagCount {
+    pub name: String,
+    pub articles: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TagQuery {
+    /// Only tags starting with this
+    pub q: Option<String>,
+    /// 10 by default, at most 100
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreateArticleForm {
     pub title: String,
     pub description: String,
+    /// Version of the article the changes were made to, required to update an article.
+    /// Saving fails with a conflict when the article has been saved since.
+    /// Ignored for new articles.
+    pub version: Option<i64>,
+    /// Replaces the article's tags, a list or a comma separated string. Left as they are when missing.
+    #[serde(default, deserialize_with = "taxonomy::deserialize_tags")]
+    #[schema(value_type = Option<Vec<String>>)]
+    pub tags: Option<Vec<String>>,
+    /// Slug of the category to file the article under, empty for none. Left as it is when missing.
+    pub category: Option<String>,
+}
+
+/// Longest accepted article title, in characters.
+pub const TITLE_MAX: usize = 200;
+/// Longest accepted article text, in characters.
+pub const DESCRIPTION_MAX: usize = 100_000;
+
+impl CreateArticleForm {
+    /// The checks every way of saving an article goes through.
+    pub fn validate(&self) -> Result<(), AppError> {
+        let title = self.title.trim();
+        if title.is_empty() {
+            return Err(AppError::Validation("The title can't be empty".to_string()));
+        }
+        if title.chars().count() > TITLE_MAX {
+            return Err(AppError::Validation(format!("The title can't be longer than {} characters", TITLE_MAX)));
+        }
+        if self.description.trim().is_empty() {
+            return Err(AppError::Validation("The article can't be empty".to_string()));
+        }
+        if self.description.chars().count() > DESCRIPTION_MAX {
+            return Err(AppError::Validation(format!("The article can't be longer than {} characters", DESCRIPTION_MAX)));
+        }
+        if let Some(tags) = &self.tags {
+            taxonomy::validate_tags(tags)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CreateArticleForm {
+pub struct PreviewForm {
+    pub description: String,
+}
+
+/// Partial update of an article, missing fields are left as they are.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ArticlePatch {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Like `version` of a full update, the current version when missing
+    pub version: Option<i64>,
+    #[serde(default, deserialize_with = "taxonomy::deserialize_tags")]
+    #[schema(value_type = Option<Vec<String>>)]
+    pub tags: Option<Vec<String>>,
+    pub category: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ArticleQuery {
+    /// Only articles of this user
+    pub owner: Option<String>,
+    /// Only articles with this status
+    pub status: Option<ArticleStatus>,
+    /// Only articles with every one of these comma separated tags
+    pub tag: Option<String>,
+    /// Only articles in the category with this slug or in its subcategories
+    pub category: Option<String>,
+    /// Starting at 1
+    pub page: Option<u32>,
+    /// 20 by default, at most 100
+    pub per_page: Option<u32>,
+}
+
+/// Page of the public tag and category listings, starting at 1.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListingQuery {
+    pub page: Option<u32>,
+}
+
+/// Which articles a listing includes.
+#[derive(Default)]
+pub struct ArticleFilter {
+    pub owner: Option<String>,
+    pub status: Option<ArticleStatus>,
+    /// Articles need every one of them
+    pub tags: Vec<String>,
+    /// Slug of a category, its subcategories are included
+    pub category: Option<String>,
+    /// The logged in user, who sees their own hidden and unpublished articles
+    pub viewer: Option<String>,
+    /// Include every hidden article
+    pub all_hidden: bool,
+    /// Include every unpublished article
+    pub all_unpublished: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ArticlePage {
+    pub articles: Vec<Article>,
+    pub page: u32,
+    pub per_page: u32,
+    pub total: i64,
+}
+
+/// A saved version of an article. Every save adds one, they are never changed.
+#[derive(Debug, Serialize)]
+pub struct ArticleRevision {
+    pub id: i64,
+    pub article_id: i32,
+    /// Who saved it
+    pub author: String,
+    pub created_at: i64,
     pub title: String,
     pub description: String,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct StatusForm {
+    pub status: ArticleStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleForm {
+    /// `YYYY-MM-DDTHH:MM` in UTC, as sent by a `datetime-local` input, empty to unschedule
+    pub publish_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VisibilityForm {
+    pub hidden: bool,
+}
@@ -0,0 +1,173 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::rngs::OsRng;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use crate::error::AppError;
+
+/// Argon2id cost parameters used for newly hashed passwords.
+///
+/// Stored hashes that were produced with different parameters still verify,
+/// but are reported as `Verification::NeedsRehash` so the caller can upgrade them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HashPolicy {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    NeedsRehash,
+}
+
+impl Default for HashPolicy {
+    // OWASP recommended minimum for Argon2id
+    fn default() -> Self {
+        HashPolicy {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl HashPolicy {
+    /// Checks that argon2 accepts the parameters.
+    pub fn validate(&self) -> Result<(), String> {
+        self.params().map(|_| ())
+    }
+
+    fn params(&self) -> Result<Params, String> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|err| format!("Invalid password hashing policy: {}", err))
+    }
+
+    fn hasher(&self) -> Result<Argon2<'static>, String> {
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params()?))
+    }
+
+    /// Hashes `password` into a PHC formatted string.
+    pub fn hash(&self, password: &str) -> Result<String, AppError> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.hasher()
+            .map_err(AppError::internal)?
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| AppError::internal(format!("Failed to hash password: {}", err)))
+    }
+
+    /// Checks `password` against a stored value, which is either a PHC hash
+    /// or a legacy plaintext password.
+    pub fn verify(&self, password: &str, stored: &str) -> Verification {
+        let hash = match PasswordHash::new(stored) {
+            Ok(hash) => hash,
+            Err(_) => {
+                // Legacy row, the password was stored as is
+                return if bool::from(password.as_bytes().ct_eq(stored.as_bytes())) {
+                    Verification::NeedsRehash
+                } else {
+                    Verification::Invalid
+                };
+            }
+        };
+
+        if Argon2::default().verify_password(password.as_bytes(), &hash).is_err() {
+            return Verification::Invalid;
+        }
+
+        let current = hash.algorithm == Algorithm::Argon2id.ident()
+            && Params::try_from(&hash).is_ok_and(|params| {
+                params.m_cost() == self.memory_kib
+                    && params.t_cost() == self.iterations
+                    && params.p_cost() == self.parallelism
+            });
+        if current {
+            Verification::Valid
+        } else {
+            Verification::NeedsRehash
+        }
+    }
+}
+
+/// Server side copy of the rules enforced by `validator_registration.js`.
+pub fn check_rules(password: &str, confirm: &str) -> Result<(), AppError> {
+    if password != confirm {
+        return Err(AppError::Validation("Password do not match".to_string()));
+    }
+    if password.chars().count() < 8 {
+        return Err(AppError::Validation("Password must be at least 8 characters long".to_string()));
+    }
+    let rules_ok = password.chars().any(|c| c.is_ascii_uppercase())
+        && password.chars().any(|c| c.is_ascii_lowercase())
+        && password.chars().any(|c| c.is_ascii_digit())
+        && password.chars().any(|c| "#?!@$%^&*-".contains(c));
+    if !rules_ok {
+        return Err(AppError::Validation("Upper case, Lower case, Special character and Numeric letter are required in Password field".to_string()));
+    }
+    Ok(())
+}
+
+/// Whether a stored password is already in PHC format.
+pub fn is_hashed(stored: &str) -> bool {
+    PasswordHash::new(stored).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap enough for tests, the defaults take a noticeable time per hash
+    fn policy(iterations: u32) -> HashPolicy {
+        HashPolicy { memory_kib: 64, iterations, parallelism: 1 }
+    }
+
+    #[test]
+    fn verifies_hashes() {
+        let policy = policy(1);
+        let hash = policy.hash("Secret#1").unwrap();
+        assert!(is_hashed(&hash));
+        assert!(hash.starts_with("$argon2id$"));
+        assert_ne!(hash, policy.hash("Secret#1").unwrap(), "hashes must be salted");
+
+        assert_eq!(policy.verify("Secret#1", &hash), Verification::Valid);
+        assert_eq!(policy.verify("Secret#2", &hash), Verification::Invalid);
+        assert_eq!(policy.verify("", &hash), Verification::Invalid);
+    }
+
+    #[test]
+    fn rehashes_other_parameters() {
+        let hash = policy(1).hash("Secret#1").unwrap();
+        assert_eq!(policy(2).verify("Secret#1", &hash), Verification::NeedsRehash);
+        assert_eq!(policy(2).verify("Secret#2", &hash), Verification::Invalid);
+    }
+
+    #[test]
+    fn upgrades_plaintext_passwords() {
+        let policy = policy(1);
+        assert!(!is_hashed("toor"));
+        assert_eq!(policy.verify("toor", "toor"), Verification::NeedsRehash);
+        assert_eq!(policy.verify("Toor", "toor"), Verification::Invalid);
+        assert_eq!(policy.verify("", "toor"), Verification::Invalid);
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        assert!(HashPolicy::default().validate().is_ok());
+        assert!(HashPolicy { memory_kib: 64, iterations: 0, parallelism: 1 }.validate().is_err());
+        assert!(HashPolicy { memory_kib: 1, iterations: 1, parallelism: 1 }.validate().is_err());
+    }
+
+    #[test]
+    fn applies_password_rules() {
+        assert!(check_rules("Secret#1", "Secret#1").is_ok());
+        assert!(check_rules("Secret#1", "Secret#2").is_err());
+        assert!(check_rules("Sec#1", "Sec#1").is_err());
+        for weak in ["secret#12", "SECRET#12", "Secret#ab", "Secret123"] {
+            assert!(check_rules(weak, weak).is_err(), "'{}' was accepted", weak);
+        }
+    }
+}
@@ -1,153 +1,1037 @@
+use r2d2_sqlite::rusqlite::{params, OptionalExtension, Row};
+use r2d2_sqlite::rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use crate::error::AppError;
 use crate::models::Article;
 use crate::models::User;
+use crate::models::Role;
 use crate::models::SlimUser;
+use crate::models::TwoFactorState;
+use crate::models::{ApiToken, ApiTokenGrant};
+use crate::models::{ArticleCategory, ArticleFilter, ArticleRevision, ArticleStatus, Category, CreateArticleForm, TagCount};
+use crate::markdown;
+use crate::password::{self, HashPolicy};
 
 pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 pub type Connection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 
 
-pub fn get_user(conn: Connection, username: String) -> Result<SlimUser, String> {
-    match conn.query_row("SELECT username, password FROM user WHERE username=$1", &[&username], |row| {
+fn user_not_found(username: &str) -> AppError {
+    AppError::NotFound(format!("User '{}' was not found", username))
+}
+
+pub fn get_user(conn: Connection, username: String) -> Result<SlimUser, AppError> {
+    conn.query_row("SELECT username, email, password, IFNULL(session_stamp, ''), totp_enabled FROM user WHERE username=$1", [&username], |row| {
         Ok(SlimUser{
             username: row.get(0)?,
-            password: row.get(1)?,
+            email: row.get(1)?,
+            password: row.get(2)?,
+            session_stamp: row.get(3)?,
+            two_factor: row.get(4)?,
         })
-    }) {
-        Ok(user) => Ok(user),
-        Err(_) => Err(format!("User '{}' was not found", &username))
-    }
+    })
+    .optional()?
+    .ok_or_else(|| user_not_found(&username))
+}
+
+/// Emails are compared without regard to case, like mail servers do.
+pub fn get_username_by_email(conn: Connection, email: String) -> Result<String, AppError> {
+    conn.query_row("SELECT username FROM user WHERE email=$1 COLLATE NOCASE", [&email], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| AppError::NotFound(format!("No user with email '{}'", &email)))
+}
+
+pub fn get_username(conn: Connection, id: i32) -> Result<String, AppError> {
+    conn.query_row("SELECT username FROM user WHERE id=$1", [&id], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| AppError::NotFound(format!("User {} was not found", id)))
+}
+
+pub fn get_session_stamp(conn: Connection, username: String) -> Result<String, AppError> {
+    conn.query_row("SELECT IFNULL(session_stamp, '') FROM user WHERE username=$1", [&username], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| user_not_found(&username))
 }
 
 
-pub fn del_user(conn: Connection, id: i32) -> Result<(), String> {
-    conn.execute("DELETE FROM user WHERE id=$1", &[&id]).unwrap();
+pub fn del_user(mut conn: Connection, id: i32) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM password_reset WHERE user_id=$1", [&id])?;
+    tx.execute("DELETE FROM recovery_code WHERE user_id=$1", [&id])?;
+    tx.execute("DELETE FROM api_token WHERE user_id=$1", [&id])?;
+    if tx.execute("DELETE FROM user WHERE id=$1", [&id])? == 0 {
+        return Err(AppError::NotFound(format!("User {} was not found", id)));
+    }
+    ensure_user_manager(&tx)?;
+    tx.commit()?;
     Ok(())
 }
 
-pub fn promote_user(conn: Connection, id: i32) -> Result<(), String> {
-    conn.execute("UPDATE user SET is_admin=1 WHERE id=$1", &[&id]).unwrap();
+/// Gives the user `id` the role named `role`.
+pub fn set_role(mut conn: Connection, id: i32, role: String) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    let role_id: i32 = tx.query_row("SELECT id FROM role WHERE name=$1", [&role], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| AppError::Validation(format!("Unknown role '{}'", role)))?;
+    if tx.execute("UPDATE user SET role_id=$1 WHERE id=$2", [&role_id, &id])? == 0 {
+        return Err(AppError::NotFound(format!("User {} was not found", id)));
+    }
+    ensure_user_manager(&tx)?;
+    tx.commit()?;
     Ok(())
 }
 
-pub fn demote_user(conn: Connection, id: i32) -> Result<(), String> {
-    conn.execute("UPDATE user SET is_admin=0 WHERE id=$1", &[&id]).unwrap();
+/// Refuses changes that would leave nobody able to manage users.
+fn ensure_user_manager(conn: &r2d2_sqlite::rusqlite::Connection) -> Result<(), AppError> {
+    let managers: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM user
+         JOIN role_permission ON role_permission.role_id = user.role_id
+         WHERE role_permission.permission='user.manage'",
+        [], |row| row.get(0)
+    )?;
+    if managers == 0 {
+        return Err(AppError::Conflict("At least one user must be able to manage users".to_string()));
+    }
     Ok(())
 }
-pub fn get_users(conn: Connection, ) -> Result<Vec<User>, String> {
-    let mut stmt = conn.prepare("SELECT id, username, is_admin FROM user").unwrap();
+
+fn user_from_row(row: &Row) -> Result<User, r2d2_sqlite::rusqlite::Error> {
+    Ok(User{
+        id: row.get(0)?,
+        username: row.get(1)?,
+        email: row.get(2)?,
+        role: row.get(3)?,
+    })
+}
+
+pub fn get_users(conn: Connection) -> Result<Vec<User>, AppError> {
+    let mut stmt = conn.prepare("SELECT user.id, username, email, role.name FROM user JOIN role ON role.id = user.role_id ORDER BY user.id")?;
+    let results = stmt.query_map([], user_from_row)?;
+
+    Ok(results.collect::<Result<Vec<User>, _>>()?)
+}
+
+pub fn get_user_by_id(conn: Connection, id: i32) -> Result<User, AppError> {
+    conn.query_row(
+        "SELECT user.id, username, email, role.name FROM user JOIN role ON role.id = user.role_id WHERE user.id=$1",
+        [&id], user_from_row
+    )
+    .optional()?
+    .ok_or_else(|| AppError::NotFound(format!("User {} was not found", id)))
+}
+
+pub fn get_roles(conn: Connection) -> Result<Vec<Role>, AppError> {
+    let mut stmt = conn.prepare("SELECT id, name, description FROM role ORDER BY id")?;
     let results = stmt.query_map([], |row| {
-        Ok(User{
+        Ok(Role{
             id: row.get(0)?,
-            username: row.get(1)?,
-            password: "#foo".to_string(),
-            is_admin: row.get(2)?,
+            name: row.get(1)?,
+            description: row.get(2)?,
         })
-    });
+    })?;
+
+    Ok(results.collect::<Result<Vec<Role>, _>>()?)
+}
+
+/// The role name of `username` and the names of the permissions it grants.
+pub fn get_permissions(conn: Connection, username: String) -> Result<(String, Vec<String>), AppError> {
+    let (role_id, role): (i32, String) = conn.query_row(
+        "SELECT role.id, role.name FROM user JOIN role ON role.id = user.role_id WHERE username=$1",
+        [&username], |row| Ok((row.get(0)?, row.get(1)?))
+    )
+    .optional()?
+    .ok_or_else(|| user_not_found(&username))?;
+
+    let mut stmt = conn.prepare("SELECT permission FROM role_permission WHERE role_id=$1")?;
+    let permissions = stmt.query_map([&role_id], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok((role, permissions))
+}
+
+pub fn register_user(conn: Connection, data: SlimUser) -> Result<(), AppError> {
+    insert_user(&conn, &data)
+}
 
-    let mut retval: Vec<User> = Vec::new();
-    for row in results.unwrap() {
-        retval.push(row.unwrap());
+/// Adds an account with the admin role, its email counts as verified.
+pub fn create_admin(mut conn: Connection, data: SlimUser) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    insert_user(&tx, &data)?;
+    tx.execute(
+        "UPDATE user SET role_id=(SELECT id FROM role WHERE name='admin'), email_verified=1 WHERE username=$1",
+        [&data.username]
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn insert_user(conn: &r2d2_sqlite::rusqlite::Connection, data: &SlimUser) -> Result<(), AppError> {
+    if conn.query_row("SELECT id FROM user WHERE username=$1", [&data.username], |_| Ok(())).optional()?.is_some() {
+        return Err(AppError::Conflict(format!("User '{}' already exists", &data.username)));
     }
-    Ok(retval)
+
+    if let Some(email) = &data.email {
+        if conn.query_row("SELECT id FROM user WHERE email=$1 COLLATE NOCASE", [email], |_| Ok(())).optional()?.is_some() {
+            return Err(AppError::Conflict(format!("Email '{}' is already in use", email)));
+        }
+    }
+
+    conn.execute(
+        "INSERT INTO user (username, email, password, session_stamp) VALUES ($0, $1, $2, $3)",
+        params![data.username, data.email, data.password, data.session_stamp]
+    )?;
+    Ok(())
 }
 
-pub fn register_user(conn: Connection, data: SlimUser) -> Result<String, String> {
-    match conn.query_row("SELECT username FROM user WHERE username=$1", &[&data.username], |_| {Ok(" ")}) {
-        Ok(_) => { return Err(format!("User '{}' already exists", &data.username)) }
-        Err(_) => {  }
-    };
+pub fn set_password(conn: Connection, username: String, hash: String) -> Result<(), AppError> {
+    conn.execute("UPDATE user SET password=$1 WHERE username=$2", [&hash, &username])?;
+    Ok(())
+}
+
+/// Hashes every password that is still stored in plaintext.
+pub fn migrate_plaintext_passwords(conn: Connection, policy: &HashPolicy) -> Result<usize, AppError> {
+    let mut stmt = conn.prepare("SELECT id, password FROM user")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i32>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
 
-    match conn.execute(
-        "INSERT INTO user (username, password, is_admin) VALUES ($0, $1, $2)",
-        &[&data.username, &data.password, &"0".to_string()]
-    ) {
-        Ok(_) => Ok("".to_string()),
-        Err(err) => Err(format!("Failed to insert user {:?}", err.to_string())),
+    let mut migrated = 0;
+    for (id, stored) in rows {
+        if password::is_hashed(&stored) {
+            continue;
+        }
+        let hash = policy.hash(&stored)?;
+        conn.execute("UPDATE user SET password=$1 WHERE id=$2", params![hash, id])?;
+        migrated += 1;
     }
+    Ok(migrated)
+}
+
+pub fn set_verify_nonce(conn: Connection, username: String, nonce: String) -> Result<(), AppError> {
+    conn.execute("UPDATE user SET verify_nonce=$1 WHERE username=$2", [&nonce, &username])?;
+    Ok(())
 }
 
-pub fn check_permissions(conn: Connection, username: String) -> Result<bool, String> {
-    match conn.query_row("SELECT is_admin FROM user WHERE username=$1", &[&username], |row| {
-        Ok(row.get(0)?)
-    }) {
-        Ok(admin) => Ok(admin),
-        Err(_) => Err(format!("User '{}' was not found", &username))
+/// Marks the email as verified when `nonce` is the outstanding one. The nonce is consumed.
+pub fn verify_email(conn: Connection, username: String, nonce: String) -> Result<bool, AppError> {
+    let updated = conn.execute(
+        "UPDATE user SET email_verified=1, verify_nonce=NULL WHERE username=$1 AND verify_nonce=$2",
+        [&username, &nonce]
+    )?;
+    Ok(updated == 1)
+}
+
+pub fn is_verified(conn: Connection, username: String) -> Result<bool, AppError> {
+    conn.query_row("SELECT email_verified FROM user WHERE username=$1", [&username], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| user_not_found(&username))
+}
+
+pub fn create_password_reset(conn: Connection, username: String, token_hash: String, expires_at: i64) -> Result<(), AppError> {
+    conn.execute(
+        "INSERT INTO password_reset (token_hash, user_id, expires_at) SELECT $1, id, $2 FROM user WHERE username=$3",
+        params![token_hash, expires_at, username]
+    )?;
+    Ok(())
+}
+
+/// Username the reset token was issued for, unless it expired.
+pub fn find_password_reset(conn: Connection, token_hash: String, now: i64) -> Result<String, AppError> {
+    conn.query_row(
+        "SELECT user.username FROM password_reset JOIN user ON user.id = password_reset.user_id
+         WHERE password_reset.token_hash=$1 AND password_reset.expires_at > $2",
+        params![token_hash, now],
+        |row| row.get(0)
+    )
+    .optional()?
+    .ok_or_else(|| AppError::NotFound("The reset link is invalid or has expired".to_string()))
+}
+
+/// Consumes the reset token, sets the new password and rotates the session
+/// stamp so all existing sessions are logged out. The user's API tokens are
+/// revoked along with them. Returns the username.
+///
+/// The token is deleted in the same transaction the password is changed in,
+/// so of two submits of one link only the first succeeds.
+pub fn reset_password(mut conn: Connection, token_hash: String, now: i64, hash: String, session_stamp: String) -> Result<String, AppError> {
+    let tx = conn.transaction()?;
+    let username: String = tx.query_row(
+        "SELECT user.username FROM password_reset JOIN user ON user.id = password_reset.user_id
+         WHERE password_reset.token_hash=$1 AND password_reset.expires_at > $2",
+        params![token_hash, now],
+        |row| row.get(0)
+    )
+    .optional()?
+    .ok_or_else(|| AppError::NotFound("The reset link is invalid or has expired".to_string()))?;
+    let consumed = tx.execute(
+        "DELETE FROM password_reset WHERE token_hash=$1 AND expires_at > $2",
+        params![token_hash, now]
+    )?;
+    if consumed != 1 {
+        return Err(AppError::NotFound("The reset link is invalid or has expired".to_string()));
     }
+    // Any other links sent to the user are void as well
+    tx.execute(
+        "DELETE FROM password_reset WHERE user_id=(SELECT id FROM user WHERE username=$1)",
+        [&username]
+    )?;
+    tx.execute(
+        "DELETE FROM api_token WHERE user_id=(SELECT id FROM user WHERE username=$1)",
+        [&username]
+    )?;
+    tx.execute(
+        "UPDATE user SET password=$1, session_stamp=$2 WHERE username=$3",
+        [&hash, &session_stamp, &username]
+    )?;
+    tx.commit()?;
+    Ok(username)
 }
 
+pub fn get_two_factor(conn: Connection, username: String) -> Result<TwoFactorState, AppError> {
+    conn.query_row(
+        "SELECT totp_secret, totp_enabled, (SELECT COUNT(*) FROM recovery_code WHERE user_id=user.id)
+         FROM user WHERE username=$1",
+        [&username], |row| {
+            Ok(TwoFactorState{
+                secret: row.get(0)?,
+                enabled: row.get(1)?,
+                recovery_codes: row.get(2)?,
+            })
+        }
+    )
+    .optional()?
+    .ok_or_else(|| user_not_found(&username))
+}
 
-pub fn get_all_articles(conn: Connection, ) -> Result<Vec<Article>, String> {
-    let mut stmt = conn.prepare("SELECT * FROM article").unwrap();
-    let results = stmt.query_map([], |row| {
-        Ok(Article{
-            id: row.get(0)?,
-            owner: row.get(1)?,
-            title: row.get(2)?,
-            description: row.get(3)?,
-        })
-    });
+/// Replaces a sealed secret with the same secret sealed under another key,
+/// unless it was changed in the meantime.
+pub fn reseal_totp_secret(conn: Connection, username: String, sealed: String, resealed: String) -> Result<(), AppError> {
+    conn.execute(
+        "UPDATE user SET totp_secret=$1 WHERE username=$2 AND totp_secret=$3",
+        [&resealed, &username, &sealed]
+    )?;
+    Ok(())
+}
+
+/// Starts an enrollment with a new sealed secret. Refused once two-factor authentication is enabled.
+pub fn begin_two_factor(conn: Connection, username: String, sealed: String) -> Result<(), AppError> {
+    let updated = conn.execute(
+        "UPDATE user SET totp_secret=$1, totp_last_step=NULL WHERE username=$2 AND totp_enabled=0",
+        [&sealed, &username]
+    )?;
+    if updated == 0 {
+        return Err(AppError::Conflict("Two-factor authentication is already enabled".to_string()));
+    }
+    Ok(())
+}
+
+/// Finishes the enrollment, replacing any recovery codes with `code_hashes`.
+pub fn enable_two_factor(mut conn: Connection, username: String, code_hashes: Vec<String>) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    let id: i32 = tx.query_row("SELECT id FROM user WHERE username=$1", [&username], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| user_not_found(&username))?;
+    tx.execute("UPDATE user SET totp_enabled=1 WHERE id=$1 AND totp_secret IS NOT NULL", [&id])?;
+    replace_codes(&tx, id, &code_hashes)?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn replace_recovery_codes(mut conn: Connection, username: String, code_hashes: Vec<String>) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    let id: i32 = tx.query_row("SELECT id FROM user WHERE username=$1", [&username], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| user_not_found(&username))?;
+    replace_codes(&tx, id, &code_hashes)?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn replace_codes(conn: &r2d2_sqlite::rusqlite::Connection, id: i32, code_hashes: &[String]) -> Result<(), AppError> {
+    conn.execute("DELETE FROM recovery_code WHERE user_id=$1", [&id])?;
+    for hash in code_hashes {
+        conn.execute("INSERT INTO recovery_code (user_id, code_hash) VALUES ($1, $2)", params![id, hash])?;
+    }
+    Ok(())
+}
+
+pub fn disable_two_factor(mut conn: Connection, username: String) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM recovery_code WHERE user_id=(SELECT id FROM user WHERE username=$1)", [&username])?;
+    tx.execute("UPDATE user SET totp_secret=NULL, totp_enabled=0, totp_last_step=NULL WHERE username=$1", [&username])?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Records `step` as used. False when it, or a later step, was used already.
+pub fn advance_totp_step(conn: Connection, username: String, step: i64) -> Result<bool, AppError> {
+    let updated = conn.execute(
+        "UPDATE user SET totp_last_step=$1 WHERE username=$2 AND (totp_last_step IS NULL OR totp_last_step < $1)",
+        params![step, username]
+    )?;
+    Ok(updated == 1)
+}
 
-    let mut retval: Vec<Article> = Vec::new();
-    for row in results.unwrap() {
-        retval.push(row.unwrap());
+/// Consumes a recovery code. False when it doesn't exist or was used already.
+pub fn use_recovery_code(conn: Connection, username: String, code_hash: String) -> Result<bool, AppError> {
+    let deleted = conn.execute(
+        "DELETE FROM recovery_code WHERE user_id=(SELECT id FROM user WHERE username=$1) AND code_hash=$2",
+        [&username, &code_hash]
+    )?;
+    Ok(deleted == 1)
+}
+
+fn split_scopes(scopes: String) -> Vec<String> {
+    scopes.split_whitespace().map(str::to_string).collect()
+}
+
+/// Stores a new API token for `username`. `scopes` are space separated.
+pub fn create_api_token(conn: Connection, username: String, name: String, token_hash: String, scopes: String, created_at: i64, expires_at: Option<i64>) -> Result<(), AppError> {
+    let inserted = conn.execute(
+        "INSERT INTO api_token (user_id, name, token_hash, scopes, created_at, expires_at)
+         SELECT id, $1, $2, $3, $4, $5 FROM user WHERE username=$6",
+        params![name, token_hash, scopes, created_at, expires_at, username]
+    )?;
+    if inserted == 0 {
+        return Err(user_not_found(&username));
     }
-    Ok(retval)
+    Ok(())
 }
 
-pub fn get_articles(conn: Connection, id: String ) -> Result<Vec<Article>, String> {
-    let mut stmt = conn.prepare("SELECT * FROM article WHERE owner=$1").unwrap();
-    let results = stmt.query_map(&[&id], |row| {
-        Ok(Article{
+pub fn get_api_tokens(conn: Connection, username: String) -> Result<Vec<ApiToken>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT api_token.id, name, scopes, created_at, last_used_at, expires_at
+         FROM api_token JOIN user ON user.id = api_token.user_id
+         WHERE user.username=$1 ORDER BY api_token.id"
+    )?;
+    let results = stmt.query_map([&username], |row| {
+        Ok(ApiToken{
             id: row.get(0)?,
-            owner: row.get(1)?,
-            title: row.get(2)?,
-            description: row.get(3)?,
+            name: row.get(1)?,
+            scopes: split_scopes(row.get(2)?),
+            created_at: row.get(3)?,
+            last_used_at: row.get(4)?,
+            expires_at: row.get(5)?,
         })
-    });
+    })?;
+
+    Ok(results.collect::<Result<Vec<ApiToken>, _>>()?)
+}
 
-    let mut retval: Vec<Article> = Vec::new();
-    for row in results.unwrap() {
-        retval.push(row.unwrap());
+/// Deletes the token `id`, when it belongs to `username`.
+pub fn revoke_api_token(conn: Connection, username: String, id: i64) -> Result<(), AppError> {
+    let deleted = conn.execute(
+        "DELETE FROM api_token WHERE id=$1 AND user_id=(SELECT id FROM user WHERE username=$2)",
+        params![id, username]
+    )?;
+    if deleted == 0 {
+        return Err(AppError::NotFound(format!("API token {} was not found", id)));
     }
-    Ok(retval)
+    Ok(())
 }
 
+/// Deletes every token of `username`, returns how many there were.
+pub fn revoke_api_tokens(conn: Connection, username: String) -> Result<usize, AppError> {
+    Ok(conn.execute(
+        "DELETE FROM api_token WHERE user_id=(SELECT id FROM user WHERE username=$1)",
+        [&username]
+    )?)
+}
 
-pub fn get_article(conn: Connection, id: i32) -> Result<Article, String> {
-    match conn.query_row("SELECT * FROM article WHERE id=$1", &[&id], |row| {
-        Ok(Article{
+/// Looks up an unexpired token and records that it was used at `now`.
+pub fn use_api_token(conn: Connection, token_hash: String, now: i64) -> Result<Option<ApiTokenGrant>, AppError> {
+    let grant = conn.query_row(
+        "SELECT api_token.id, user.username, api_token.scopes
+         FROM api_token JOIN user ON user.id = api_token.user_id
+         WHERE api_token.token_hash=$1 AND (api_token.expires_at IS NULL OR api_token.expires_at > $2)",
+        params![token_hash, now],
+        |row| Ok(ApiTokenGrant{
             id: row.get(0)?,
-            owner: row.get(1)?,
-            title: row.get(2)?,
-            description: row.get(3)?,
+            username: row.get(1)?,
+            scopes: split_scopes(row.get(2)?),
         })
-    }) {
-        Ok(res) => Ok(res),
-        Err(_) => Err(format!("Article '{}' was not found", &id))
+    ).optional()?;
+    if let Some(grant) = &grant {
+        conn.execute("UPDATE api_token SET last_used_at=$1 WHERE id=$2", params![now, grant.id])?;
     }
+    Ok(grant)
 }
 
-pub fn post_article(conn: Connection, data: Article) -> Result<String, String> {
-    if data.id != -1 {
-        match conn.query_row("SELECT * FROM article WHERE id=$1", &[&data.id], |_| {Ok(" ")}) {
-            Ok(_) => {
-                conn.execute("UPDATE article SET title=$1, description=$2 WHERE id=$3", &[&data.title, &data.description, &data.id.to_string()]).unwrap();
-                return Ok("".to_string())
-            }
-            Err(_) => {  }
-        };
+
+impl FromSql for ArticleStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value.as_str()?.parse().map_err(|err: AppError| FromSqlError::Other(err.detail().into()))
     }
+}
 
-    match conn.execute(
-        "INSERT INTO article (owner, title, description) VALUES ($0, $1, $2)",
-        &[&data.owner, &data.title, &data.description]
-    ) {
-        Ok(_) => Ok("".to_string()),
-        Err(err) => Err(format!("Failed to insert article {:?}", err.to_string())),
+impl ToSql for ArticleStatus {
+    fn to_sql(&self) -> r2d2_sqlite::rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
     }
 }
 
-pub fn del_article(conn: Connection, id: i32) -> Result<(), String> {
-    conn.execute("DELETE FROM article WHERE id=$1", &[&id]).unwrap();
+/// Columns read by `article_from_row`, in order.
+const ARTICLE_COLUMNS: &str = "id, owner, title, description, hidden, IFNULL(html, ''), status, publish_at, version,
+    (SELECT group_concat(tag.name, ' ') FROM article_tag JOIN tag ON tag.id = article_tag.tag_id WHERE article_tag.article_id = article.id),
+    (SELECT slug FROM category WHERE category.id = article.category_id),
+    (SELECT name FROM category WHERE category.id = article.category_id)";
+
+fn article_from_row(row: &Row) -> Result<Article, r2d2_sqlite::rusqlite::Error> {
+    Ok(Article{
+        id: row.get(0)?,
+        owner: row.get(1)?,
+        title: row.get(2)?,
+        description: row.get(3)?,
+        hidden: row.get(4)?,
+        html: row.get(5)?,
+        status: row.get(6)?,
+        publish_at: row.get(7)?,
+        version: row.get(8)?,
+        tags: row.get::<_, Option<String>>(9)?
+            .map(|tags| {
+                let mut tags: Vec<String> = tags.split(' ').map(str::to_string).collect();
+                tags.sort();
+                tags
+            })
+            .unwrap_or_default(),
+        category: match (row.get(10)?, row.get(11)?) {
+            (Some(slug), Some(name)) => Some(ArticleCategory { slug, name }),
+            _ => None,
+        },
+    })
+}
+
+pub fn get_all_articles(conn: Connection) -> Result<Vec<Article>, AppError> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM article", ARTICLE_COLUMNS))?;
+    let results = stmt.query_map([], article_from_row)?;
+
+    Ok(results.collect::<Result<Vec<Article>, _>>()?)
+}
+
+/// Every published article that isn't hidden.
+pub fn get_visible_articles(conn: Connection) -> Result<Vec<Article>, AppError> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM article WHERE hidden=0 AND status='published'", ARTICLE_COLUMNS))?;
+    let results = stmt.query_map([], article_from_row)?;
+
+    Ok(results.collect::<Result<Vec<Article>, _>>()?)
+}
+
+pub fn get_articles(conn: Connection, id: String) -> Result<Vec<Article>, AppError> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM article WHERE owner=$1", ARTICLE_COLUMNS))?;
+    let results = stmt.query_map([&id], article_from_row)?;
+
+    Ok(results.collect::<Result<Vec<Article>, _>>()?)
+}
+
+/// One page of articles, newest first, with the number of matching articles.
+pub fn get_articles_page(conn: Connection, filter: ArticleFilter, limit: i64, offset: i64) -> Result<(Vec<Article>, i64), AppError> {
+    // SQLite numbers `$` parameters in the order they first appear
+    // Tags are passed space separated, they can't contain spaces
+    let conditions = "WHERE ($1 IS NULL OR owner=$1) AND ($2 IS NULL OR status=$2)
+        AND (hidden=0 OR $3 OR owner=$4) AND (status='published' OR $5 OR owner=$4)
+        AND ($6 = 0 OR $6 = (
+            SELECT COUNT(*) FROM article_tag JOIN tag ON tag.id = article_tag.tag_id
+            WHERE article_tag.article_id = article.id AND instr(' ' || $7 || ' ', ' ' || tag.name || ' ') > 0
+        ))
+        AND ($8 IS NULL OR category_id IN (
+            WITH RECURSIVE subtree(id) AS (
+                SELECT id FROM category WHERE slug=$8
+                UNION SELECT category.id FROM category JOIN subtree ON category.parent_id = subtree.id
+            )
+            SELECT id FROM subtree
+        ))";
+    let tag_count = filter.tags.len() as i64;
+    let tags = filter.tags.join(" ");
+    let values = params![
+        filter.owner, filter.status, filter.all_hidden, filter.viewer, filter.all_unpublished, tag_count, tags, filter.category
+    ];
+    let total = conn.query_row(&format!("SELECT COUNT(*) FROM article {}", conditions), values, |row| row.get(0))?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM article {} ORDER BY id DESC LIMIT $9 OFFSET $10", ARTICLE_COLUMNS, conditions
+    ))?;
+    let results = stmt.query_map(
+        params![
+            filter.owner, filter.status, filter.all_hidden, filter.viewer, filter.all_unpublished, tag_count, tags, filter.category,
+            limit, offset
+        ],
+        article_from_row
+    )?;
+
+    Ok((results.collect::<Result<Vec<Article>, _>>()?, total))
+}
+
+pub fn get_article(conn: Connection, id: i32) -> Result<Article, AppError> {
+    conn.query_row(&format!("SELECT {} FROM article WHERE id=$1", ARTICLE_COLUMNS), [&id], article_from_row)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound(format!("Article '{}' was not found", &id)))
+}
+
+/// Records the content of article `id` as saved by `author`.
+fn add_revision(conn: &r2d2_sqlite::rusqlite::Connection, id: i32, author: &str, now: i64, data: &CreateArticleForm) -> Result<(), AppError> {
+    conn.execute(
+        "INSERT INTO article_revision (article_id, author, created_at, title, description) VALUES ($1, $2, $3, $4, $5)",
+        params![id, author, now, data.title, data.description]
+    )?;
     Ok(())
 }
+
+/// The version an update was made to, which every update but a restore has to name.
+fn expected_version(data: &CreateArticleForm) -> Result<i64, AppError> {
+    data.version.ok_or_else(|| AppError::Validation("The version of the article the changes were made to is missing".to_string()))
+}
+
+/// Overwrites the content of article `id` and bumps its version. Returns whether the
+/// article exists, fails with a conflict when it is no longer at `expected`.
+/// Only restores pass `None`, they are saved whatever the current version is.
+fn update_content(conn: &r2d2_sqlite::rusqlite::Connection, id: i32, data: &CreateArticleForm, html: &str, expected: Option<i64>) -> Result<bool, AppError> {
+    let updated = conn.execute(
+        "UPDATE article SET title=$1, description=$2, html=$3, version=version+1 WHERE id=$4 AND ($5 IS NULL OR version=$5)",
+        params![data.title, data.description, html, id, expected]
+    )?;
+    if updated == 1 {
+        return Ok(true);
+    }
+    if conn.query_row("SELECT id FROM article WHERE id=$1", [id], |_| Ok(())).optional()?.is_some() {
+        return Err(AppError::Conflict(format!("Article '{}' was changed by someone else in the meantime", id)));
+    }
+    Ok(false)
+}
+
+/// Replaces the tags and the category of article `id` with those in `data`, when it has them.
+fn update_taxonomy(conn: &r2d2_sqlite::rusqlite::Connection, id: i32, data: &CreateArticleForm) -> Result<(), AppError> {
+    if let Some(tags) = &data.tags {
+        conn.execute("DELETE FROM article_tag WHERE article_id=$1", [id])?;
+        for tag in tags {
+            conn.execute("INSERT OR IGNORE INTO tag (name) VALUES ($1)", [tag])?;
+            conn.execute("INSERT INTO article_tag (article_id, tag_id) SELECT $1, id FROM tag WHERE name=$2", params![id, tag])?;
+        }
+    }
+    match data.category.as_deref() {
+        None => {}
+        Some("") => {
+            conn.execute("UPDATE article SET category_id=NULL WHERE id=$1", [id])?;
+        }
+        Some(slug) => {
+            let category: i32 = conn.query_row("SELECT id FROM category WHERE slug=$1", [slug], |row| row.get(0))
+                .optional()?
+                .ok_or_else(|| AppError::Validation(format!("There is no category '{}'", slug)))?;
+            conn.execute("UPDATE article SET category_id=$1 WHERE id=$2", [category, id])?;
+        }
+    }
+    Ok(())
+}
+
+/// Inserts a new draft owned by `author` when `id` is -1, updates the article `id` otherwise.
+/// Either way the content is recorded as a new revision. Returns the article id.
+pub fn post_article(mut conn: Connection, id: i32, author: String, data: CreateArticleForm, now: i64) -> Result<i32, AppError> {
+    let html = markdown::render(&data.description);
+    let tx = conn.transaction()?;
+    let id = if id == -1 {
+        tx.execute(
+            "INSERT INTO article (owner, title, description, html, status) VALUES ($1, $2, $3, $4, $5)",
+            params![author, data.title, data.description, html, ArticleStatus::Draft]
+        )?;
+        tx.last_insert_rowid() as i32
+    } else {
+        if !update_content(&tx, id, &data, &html, Some(expected_version(&data)?))? {
+            return Err(AppError::NotFound(format!("Article '{}' was not found", &id)));
+        }
+        id
+    };
+    update_taxonomy(&tx, id, &data)?;
+    add_revision(&tx, id, &author, now, &data)?;
+    tx.commit()?;
+    Ok(id)
+}
+
+/// Replaces the title and text of an existing article, recording them as a new revision by `author`.
+/// Fails with a conflict when the article is no longer at `data.version`.
+pub fn update_article(mut conn: Connection, id: i32, author: String, data: CreateArticleForm, now: i64) -> Result<(), AppError> {
+    let html = markdown::render(&data.description);
+    let tx = conn.transaction()?;
+    if !update_content(&tx, id, &data, &html, Some(expected_version(&data)?))? {
+        return Err(AppError::NotFound(format!("Article '{}' was not found", &id)));
+    }
+    update_taxonomy(&tx, id, &data)?;
+    add_revision(&tx, id, &author, now, &data)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Saves the content of revision `rid` as a new revision of its article by `author`,
+/// whatever was saved since. Returns the article id.
+pub fn restore_revision(mut conn: Connection, rid: i64, author: String, now: i64) -> Result<i32, AppError> {
+    let tx = conn.transaction()?;
+    let revision = tx.query_row(
+        "SELECT id, article_id, author, created_at, title, description FROM article_revision WHERE id=$1",
+        [rid],
+        revision_from_row
+    )
+    .optional()?
+    .ok_or_else(|| AppError::NotFound(format!("Revision '{}' was not found", rid)))?;
+    let data = CreateArticleForm {
+        title: revision.title,
+        description: revision.description,
+        version: None,
+        tags: None,
+        category: None,
+    };
+    let html = markdown::render(&data.description);
+    if !update_content(&tx, revision.article_id, &data, &html, None)? {
+        return Err(AppError::NotFound(format!("Article '{}' was not found", revision.article_id)));
+    }
+    add_revision(&tx, revision.article_id, &author, now, &data)?;
+    tx.commit()?;
+    Ok(revision.article_id)
+}
+
+fn revision_from_row(row: &Row) -> r2d2_sqlite::rusqlite::Result<ArticleRevision> {
+    Ok(ArticleRevision {
+        id: row.get(0)?,
+        article_id: row.get(1)?,
+        author: row.get(2)?,
+        created_at: row.get(3)?,
+        title: row.get(4)?,
+        description: row.get(5)?,
+    })
+}
+
+/// Every revision of article `id`, newest first.
+pub fn get_revisions(conn: Connection, id: i32) -> Result<Vec<ArticleRevision>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, article_id, author, created_at, title, description FROM article_revision WHERE article_id=$1 ORDER BY id DESC"
+    )?;
+    let results = stmt.query_map([id], revision_from_row)?;
+
+    Ok(results.collect::<Result<Vec<ArticleRevision>, _>>()?)
+}
+
+/// The revision the current content of article `id` came from.
+pub fn get_latest_revision(conn: Connection, id: i32) -> Result<Option<ArticleRevision>, AppError> {
+    Ok(conn.query_row(
+        "SELECT id, article_id, author, created_at, title, description FROM article_revision WHERE article_id=$1 ORDER BY id DESC LIMIT 1",
+        [id],
+        revision_from_row
+    ).optional()?)
+}
+
+pub fn get_revision(conn: Connection, id: i64) -> Result<ArticleRevision, AppError> {
+    conn.query_row(
+        "SELECT id, article_id, author, created_at, title, description FROM article_revision WHERE id=$1",
+        [id],
+        revision_from_row
+    )
+        .optional()?
+        .ok_or_else(|| AppError::NotFound(format!("Revision '{}' was not found", id)))
+}
+
+/// Renders the articles saved before their HTML was cached. Returns how many there were.
+pub fn render_missing_html(conn: Connection) -> Result<usize, AppError> {
+    let mut stmt = conn.prepare("SELECT id, description FROM article WHERE html IS NULL")?;
+    let pending = stmt.query_map([], |row| Ok((row.get::<_, i32>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (id, description) in &pending {
+        conn.execute("UPDATE article SET html=$1 WHERE id=$2", params![markdown::render(description), id])?;
+    }
+    Ok(pending.len())
+}
+
+/// Moves the article `id` from status `from` to `to`. Fails with a conflict when
+/// its status is no longer `from`. Publishing drops the article's schedule.
+pub fn set_status(conn: Connection, id: i32, from: ArticleStatus, to: ArticleStatus) -> Result<(), AppError> {
+    let changed = conn.execute(
+        "UPDATE article SET status=$1, publish_at=CASE WHEN $1='published' THEN NULL ELSE publish_at END
+         WHERE id=$2 AND status=$3",
+        params![to, id, from]
+    )?;
+    if changed == 0 {
+        return Err(AppError::Conflict(format!("The status of article '{}' changed in the meantime", id)));
+    }
+    Ok(())
+}
+
+/// Schedules the article `id` to be published at `publish_at`, or unschedules it.
+pub fn set_publish_at(conn: Connection, id: i32, publish_at: Option<i64>) -> Result<(), AppError> {
+    if conn.execute("UPDATE article SET publish_at=$1 WHERE id=$2", params![publish_at, id])? == 0 {
+        return Err(AppError::NotFound(format!("Article '{}' was not found", &id)));
+    }
+    Ok(())
+}
+
+/// Publishes the drafts and articles in review scheduled at or before `now`.
+/// Returns their ids.
+pub fn publish_due(mut conn: Connection, now: i64) -> Result<Vec<i32>, AppError> {
+    let tx = conn.transaction()?;
+    let due = {
+        let mut stmt = tx.prepare(
+            "SELECT id FROM article WHERE publish_at <= $1 AND status IN ('draft', 'in_review') ORDER BY publish_at, id"
+        )?;
+        let ids = stmt.query_map([now], |row| row.get(0))?;
+        ids.collect::<Result<Vec<i32>, _>>()?
+    };
+    for id in &due {
+        tx.execute("UPDATE article SET status=$1, publish_at=NULL WHERE id=$2", params![ArticleStatus::Published, id])?;
+    }
+    tx.commit()?;
+    Ok(due)
+}
+
+pub fn set_hidden(conn: Connection, id: i32, hidden: bool) -> Result<(), AppError> {
+    if conn.execute("UPDATE article SET hidden=$1 WHERE id=$2", params![hidden, id])? == 0 {
+        return Err(AppError::NotFound(format!("Article '{}' was not found", &id)));
+    }
+    Ok(())
+}
+
+pub fn del_article(mut conn: Connection, id: i32) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM article_revision WHERE article_id=$1", [&id])?;
+    tx.execute("DELETE FROM article_tag WHERE article_id=$1", [&id])?;
+    if tx.execute("DELETE FROM article WHERE id=$1", [&id])? == 0 {
+        return Err(AppError::NotFound(format!("Article '{}' was not found", &id)));
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Tags of public articles starting with `prefix`, the most used first.
+pub fn get_tags(conn: Connection, prefix: String, limit: i64) -> Result<Vec<TagCount>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT tag.name, COUNT(*) FROM tag
+         JOIN article_tag ON article_tag.tag_id = tag.id
+         JOIN article ON article.id = article_tag.article_id
+         WHERE article.hidden=0 AND article.status='published' AND substr(tag.name, 1, length($1)) = $1
+         GROUP BY tag.id ORDER BY COUNT(*) DESC, tag.name LIMIT $2"
+    )?;
+    let results = stmt.query_map(params![prefix, limit], |row| Ok(TagCount { name: row.get(0)?, articles: row.get(1)? }))?;
+
+    Ok(results.collect::<Result<Vec<TagCount>, _>>()?)
+}
+
+fn category_from_row(row: &Row) -> r2d2_sqlite::rusqlite::Result<Category> {
+    Ok(Category {
+        id: row.get(0)?,
+        parent_id: row.get(1)?,
+        name: row.get(2)?,
+        slug: row.get(3)?,
+    })
+}
+
+pub fn get_categories(conn: Connection) -> Result<Vec<Category>, AppError> {
+    let mut stmt = conn.prepare("SELECT id, parent_id, name, slug FROM category")?;
+    let results = stmt.query_map([], category_from_row)?;
+
+    Ok(results.collect::<Result<Vec<Category>, _>>()?)
+}
+
+/// Adds a category under `parent`, or at the top level. Slugs are unique.
+pub fn create_category(conn: Connection, name: String, slug: String, parent: Option<i32>) -> Result<(), AppError> {
+    if conn.query_row("SELECT id FROM category WHERE slug=$1", [&slug], |_| Ok(())).optional()?.is_some() {
+        return Err(AppError::Conflict(format!("There already is a category '{}'", slug)));
+    }
+    if let Some(parent) = parent {
+        if conn.query_row("SELECT id FROM category WHERE id=$1", [parent], |_| Ok(())).optional()?.is_none() {
+            return Err(AppError::Validation(format!("Parent category '{}' was not found", parent)));
+        }
+    }
+    conn.execute("INSERT INTO category (parent_id, name, slug) VALUES ($1, $2, $3)", params![parent, name, slug])?;
+    Ok(())
+}
+
+/// Deletes a category that has neither articles nor subcategories.
+pub fn del_category(mut conn: Connection, id: i32) -> Result<(), AppError> {
+    let tx = conn.transaction()?;
+    let (articles, children): (i64, i64) = tx.query_row(
+        "SELECT (SELECT COUNT(*) FROM article WHERE category_id=$1), (SELECT COUNT(*) FROM category WHERE parent_id=$1)",
+        [id],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    )?;
+    if articles > 0 || children > 0 {
+        return Err(AppError::Conflict("Only categories without articles and subcategories can be deleted".to_string()));
+    }
+    if tx.execute("DELETE FROM category WHERE id=$1", [id])? == 0 {
+        return Err(AppError::NotFound(format!("Category {} was not found", id)));
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use r2d2_sqlite::SqliteConnectionManager;
+    use crate::migrate;
+    use super::*;
+
+    fn setup() -> Pool {
+        // One connection, every connection to `:memory:` is a database of its own
+        let pool = Pool::builder().max_size(1).build(SqliteConnectionManager::memory()).unwrap();
+        migrate::run(&mut pool.get().unwrap()).unwrap();
+        pool
+    }
+
+    fn register(pool: &Pool, username: &str, email: &str) {
+        let user = SlimUser {
+            username: username.to_string(),
+            email: Some(email.to_string()),
+            password: "hash".to_string(),
+            session_stamp: "stamp".to_string(),
+            two_factor: false,
+        };
+        register_user(pool.get().unwrap(), user).unwrap();
+    }
+
+    fn form(title: &str, version: Option<i64>) -> CreateArticleForm {
+        CreateArticleForm {
+            title: title.to_string(),
+            description: format!("{} text", title),
+            version,
+            tags: None,
+            category: None,
+        }
+    }
+
+    #[test]
+    fn rejects_stale_versions() {
+        let pool = setup();
+        let id = post_article(pool.get().unwrap(), -1, "root".to_string(), form("first", None), 0).unwrap();
+        assert_eq!(get_article(pool.get().unwrap(), id).unwrap().version, 1);
+
+        update_article(pool.get().unwrap(), id, "root".to_string(), form("second", Some(1)), 1).unwrap();
+        let stale = update_article(pool.get().unwrap(), id, "root".to_string(), form("lost", Some(1)), 2);
+        assert!(matches!(stale, Err(AppError::Conflict(_))));
+        let stale = post_article(pool.get().unwrap(), id, "root".to_string(), form("lost", Some(1)), 2);
+        assert!(matches!(stale, Err(AppError::Conflict(_))));
+        let unversioned = update_article(pool.get().unwrap(), id, "root".to_string(), form("lost", None), 2);
+        assert!(matches!(unversioned, Err(AppError::Validation(_))));
+
+        let article = get_article(pool.get().unwrap(), id).unwrap();
+        assert_eq!((article.title.as_str(), article.version), ("second", 2));
+        assert_eq!(get_revisions(pool.get().unwrap(), id).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_missing_articles() {
+        let pool = setup();
+        let missing = update_article(pool.get().unwrap(), 999, "root".to_string(), form("gone", Some(1)), 0);
+        assert!(matches!(missing, Err(AppError::NotFound(_))));
+        let missing = post_article(pool.get().unwrap(), 999, "root".to_string(), form("gone", Some(1)), 0);
+        assert!(matches!(missing, Err(AppError::NotFound(_))));
+
+        let articles: i64 = pool.get().unwrap().query_row("SELECT COUNT(*) FROM article", [], |row| row.get(0)).unwrap();
+        assert_eq!(articles, 0);
+    }
+
+    #[test]
+    fn restores_over_newer_versions() {
+        let pool = setup();
+        let id = post_article(pool.get().unwrap(), -1, "root".to_string(), form("first", None), 0).unwrap();
+        update_article(pool.get().unwrap(), id, "root".to_string(), form("second", Some(1)), 1).unwrap();
+        let first = *get_revisions(pool.get().unwrap(), id).unwrap().iter().map(|revision| &revision.id).min().unwrap();
+
+        assert_eq!(restore_revision(pool.get().unwrap(), first, "root".to_string(), 2).unwrap(), id);
+        let article = get_article(pool.get().unwrap(), id).unwrap();
+        assert_eq!((article.title.as_str(), article.version), ("first", 3));
+        assert!(matches!(restore_revision(pool.get().unwrap(), 999, "root".to_string(), 3), Err(AppError::NotFound(_))));
+    }
+
+    #[test]
+    fn reset_links_work_once() {
+        let pool = setup();
+        register(&pool, "alice", "alice@example.com");
+        create_password_reset(pool.get().unwrap(), "alice".to_string(), "token".to_string(), 100).unwrap();
+        create_password_reset(pool.get().unwrap(), "alice".to_string(), "other".to_string(), 100).unwrap();
+
+        let expired = reset_password(pool.get().unwrap(), "token".to_string(), 100, "new".to_string(), "s1".to_string());
+        assert!(matches!(expired, Err(AppError::NotFound(_))));
+
+        create_api_token(pool.get().unwrap(), "alice".to_string(), "ci".to_string(), "digest".to_string(), String::new(), 0, None).unwrap();
+        let username = reset_password(pool.get().unwrap(), "token".to_string(), 50, "new".to_string(), "s1".to_string()).unwrap();
+        assert_eq!(username, "alice");
+        let user = get_user(pool.get().unwrap(), "alice".to_string()).unwrap();
+        assert_eq!((user.password.as_str(), user.session_stamp.as_str()), ("new", "s1"));
+        // Tokens don't carry the session stamp, they are revoked with the sessions
+        assert!(get_api_tokens(pool.get().unwrap(), "alice".to_string()).unwrap().is_empty());
+
+        let again = reset_password(pool.get().unwrap(), "token".to_string(), 50, "newer".to_string(), "s2".to_string());
+        assert!(matches!(again, Err(AppError::NotFound(_))));
+        // The other link was sent before the reset, it is void too
+        let other = reset_password(pool.get().unwrap(), "other".to_string(), 50, "newer".to_string(), "s2".to_string());
+        assert!(matches!(other, Err(AppError::NotFound(_))));
+        assert_eq!(get_user(pool.get().unwrap(), "alice".to_string()).unwrap().password, "new");
+    }
+
+    #[test]
+    fn creates_admins() {
+        let pool = setup();
+        register(&pool, "alice", "alice@example.com");
+        let admin = |username: &str, email: &str| SlimUser {
+            username: username.to_string(),
+            email: Some(email.to_string()),
+            password: "hash".to_string(),
+            session_stamp: "stamp".to_string(),
+            two_factor: false,
+        };
+
+        create_admin(pool.get().unwrap(), admin("bob", "bob@example.com")).unwrap();
+        let users = get_users(pool.get().unwrap()).unwrap();
+        let bob = users.iter().find(|user| user.username == "bob").unwrap();
+        assert_eq!(bob.role, "admin");
+        assert!(is_verified(pool.get().unwrap(), "bob".to_string()).unwrap());
+
+        let taken = create_admin(pool.get().unwrap(), admin("alice", "other@example.com"));
+        assert!(matches!(taken, Err(AppError::Conflict(_))));
+        let email = create_admin(pool.get().unwrap(), admin("carol", "alice@example.com"));
+        assert!(matches!(email, Err(AppError::Conflict(_))));
+        assert_eq!(get_users(pool.get().unwrap()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn hashes_plaintext_passwords() {
+        let pool = setup();
+        let policy = HashPolicy { memory_kib: 64, iterations: 1, parallelism: 1 };
+        register(&pool, "root", "root@example.com");
+        set_password(pool.get().unwrap(), "root".to_string(), "toor".to_string()).unwrap();
+        register(&pool, "alice", "alice@example.com");
+        set_password(pool.get().unwrap(), "alice".to_string(), policy.hash("Secret#1").unwrap()).unwrap();
+        let alice = get_user(pool.get().unwrap(), "alice".to_string()).unwrap().password;
+
+        assert_eq!(migrate_plaintext_passwords(pool.get().unwrap(), &policy).unwrap(), 1);
+        let root = get_user(pool.get().unwrap(), "root".to_string()).unwrap().password;
+        assert_eq!(policy.verify("toor", &root), password::Verification::Valid);
+        assert_eq!(get_user(pool.get().unwrap(), "alice".to_string()).unwrap().password, alice);
+
+        assert_eq!(migrate_plaintext_passwords(pool.get().unwrap(), &policy).unwrap(), 0);
+    }
+
+    #[test]
+    fn revokes_all_tokens_of_a_user() {
+        let pool = setup();
+        register(&pool, "alice", "alice@example.com");
+        register(&pool, "bob", "bob@example.com");
+        for (username, digest) in [("alice", "a1"), ("alice", "a2"), ("bob", "b1")] {
+            create_api_token(pool.get().unwrap(), username.to_string(), "ci".to_string(), digest.to_string(), String::new(), 0, None).unwrap();
+        }
+
+        assert_eq!(revoke_api_tokens(pool.get().unwrap(), "alice".to_string()).unwrap(), 2);
+        assert!(use_api_token(pool.get().unwrap(), "a1".to_string(), 1).unwrap().is_none());
+        assert_eq!(use_api_token(pool.get().unwrap(), "b1".to_string(), 1).unwrap().unwrap().username, "bob");
+        assert_eq!(revoke_api_tokens(pool.get().unwrap(), "alice".to_string()).unwrap(), 0);
+    }
+
+    #[test]
+    fn ignores_the_case_of_emails() {
+        let pool = setup();
+        register(&pool, "alice", "Alice@Example.com");
+        assert_eq!(get_username_by_email(pool.get().unwrap(), "alice@example.COM".to_string()).unwrap(), "alice");
+
+        let user = SlimUser {
+            username: "bob".to_string(),
+            email: Some("ALICE@example.com".to_string()),
+            password: "hash".to_string(),
+            session_stamp: "stamp".to_string(),
+            two_factor: false,
+        };
+        assert!(matches!(register_user(pool.get().unwrap(), user), Err(AppError::Conflict(_))));
+        // The index holds when the check is bypassed
+        let insert = pool.get().unwrap().execute("INSERT INTO user (username, email, password) VALUES ('carol', 'alice@EXAMPLE.com', 'hash')", []);
+        assert!(insert.is_err());
+    }
+}
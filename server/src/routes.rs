@@ -1,48 +1,143 @@
-use crate::models::Article;
-use crate::models::CreateArticleForm;
+use crate::models::{Article, ArticleStatus};
+use crate::models::{ArticleFilter, Category, CreateArticleForm, ListingQuery};
 use actix_session::Session;
 use actix_identity::Identity;
 use actix_web::HttpResponse;
 use actix_web::http::{StatusCode};
-use actix_web::{error, web, get, post, Result};
+use actix_web::{web, get, post};
+use chrono::Utc;
+use crate::error::AppError;
+use crate::csrf::CsrfToken;
 use crate::Pool;
 use crate::repo;
+use crate::taxonomy;
+use crate::authz::{self, ArticleAction, CurrentUser, Permission};
 
+pub mod api;
 pub mod auth;
 pub mod dashboard;
 
 #[get("/")]
 pub async fn index(
+    csrf: CsrfToken,
     id: Identity,
     tmpl: web::Data<tera::Tera>,
     db: web::Data<Pool>,
-) -> Result<HttpResponse> {
+) -> Result<HttpResponse, AppError> {
     let pool = db.clone();
-    let mut ctx = tera::Context::new();
+    let mut ctx = csrf.context();
 
     let articles = web::block(move || {
-        let conn = pool.get().unwrap();
-        repo::get_all_articles(conn)
+        let conn = pool.get()?;
+        repo::get_visible_articles(conn)
     }).await?;
 
-    ctx.insert("is_loggedin", &(None != id.identity()));
+    ctx.insert("is_loggedin", &id.identity().is_some());
     ctx.insert("articles", &articles);
 
-    let body = tmpl.render("index.html", &ctx)
-            .map_err(|_| error::ErrorInternalServerError("Template error"))?;
+    let body = tmpl.render("index.html", &ctx)?;
 
     Ok(HttpResponse::build(StatusCode::OK)
        .content_type("text/html; charset=utf-8")
     .body(body))
 }
 
+/// Articles per page of the tag and category listings.
+const LISTING_PER_PAGE: u32 = 20;
+
+/// Renders a page of the public articles matching `filter`, `ctx` has the rest of the listing.
+async fn listing_page(
+    tmpl: &tera::Tera,
+    db: &web::Data<Pool>,
+    mut ctx: tera::Context,
+    filter: ArticleFilter,
+    page: Option<u32>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let page = page.unwrap_or(1).max(1);
+    let offset = (page as i64 - 1) * LISTING_PER_PAGE as i64;
+
+    let (articles, total) = web::block(move || {
+        repo::get_articles_page(pool.get()?, filter, LISTING_PER_PAGE as i64, offset)
+    }).await?;
+
+    ctx.insert("articles", &articles);
+    ctx.insert("page", &page);
+    ctx.insert("has_previous", &(page > 1));
+    ctx.insert("has_next", &(offset + (articles.len() as i64) < total));
+    ctx.insert("total", &total);
+
+    let body = tmpl.render("listing.html", &ctx)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+       .content_type("text/html; charset=utf-8")
+    .body(body))
+}
+
+#[get("/tag/{name}")]
+pub async fn tag(
+    csrf: CsrfToken,
+    id: Identity,
+    tmpl: web::Data<tera::Tera>,
+    db: web::Data<Pool>,
+    web::Path((name,)): web::Path<(String,)>,
+    query: web::Query<ListingQuery>,
+) -> Result<HttpResponse, AppError> {
+    // The router leaves reserved characters encoded, tags like `c++` and `c#` arrive as `c%2B%2B` and `c%23`
+    let name = taxonomy::normalize_tag(&percent_encoding::percent_decode_str(&name).decode_utf8_lossy());
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &id.identity().is_some());
+    ctx.insert("title", &format!("Tagged {}", name));
+    ctx.insert("ancestors", &Vec::<Category>::new());
+    ctx.insert("subcategories", &Vec::<Category>::new());
+
+    let filter = ArticleFilter { tags: vec![name], ..Default::default() };
+    listing_page(&tmpl, &db, ctx, filter, query.page).await
+}
+
+#[get("/category/{slug}")]
+pub async fn category(
+    csrf: CsrfToken,
+    id: Identity,
+    tmpl: web::Data<tera::Tera>,
+    db: web::Data<Pool>,
+    web::Path((slug,)): web::Path<(String,)>,
+    query: web::Query<ListingQuery>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let categories = web::block(move || repo::get_categories(pool.get()?)).await?;
+    let category = categories.iter().find(|category| category.slug == slug)
+        .ok_or_else(|| AppError::NotFound(format!("Category '{}' was not found", slug)))?;
+
+    // Parents always exist before their children, so the walk up ends at the top level
+    let mut ancestors: Vec<&Category> = Vec::new();
+    let mut parent = category.parent_id;
+    while let Some(found) = parent.and_then(|id| categories.iter().find(|category| category.id == id)) {
+        ancestors.insert(0, found);
+        parent = found.parent_id;
+    }
+    let mut subcategories: Vec<&Category> = categories.iter().filter(|child| child.parent_id == Some(category.id)).collect();
+    subcategories.sort_by_key(|child| child.name.to_lowercase());
+
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &id.identity().is_some());
+    ctx.insert("title", &category.name);
+    ctx.insert("ancestors", &ancestors);
+    ctx.insert("subcategories", &subcategories);
+
+    let filter = ArticleFilter { category: Some(category.slug.to_owned()), ..Default::default() };
+    listing_page(&tmpl, &db, ctx, filter, query.page).await
+}
+
 #[get("/article/create")]
 pub async fn create_article(
-  _id: Identity,
+  csrf: CsrfToken,
+  user: CurrentUser,
   tmpl: web::Data<tera::Tera>,
   session: Session,
-) -> Result<HttpResponse> {
-  let mut ctx = tera::Context::new();
+) -> Result<HttpResponse, AppError> {
+  user.require(Permission::CreateArticle)?;
+  let mut ctx = csrf.context();
   ctx.insert("is_logedin", &false);
 
   if let Some(fail) = session.get::<String>("register_failure")? {
@@ -51,45 +146,89 @@ pub async fn create_article(
     ctx.insert("failed", "");
   }
 
-  let render = tmpl.render("post_article.html", &ctx)
-    .map_err(|_| error::ErrorInternalServerError("Template error")).expect("Test");
+  let render = tmpl.render("post_article.html", &ctx)?;
 
   Ok(HttpResponse::build(StatusCode::OK)
       .content_type("text/html; charset=utf-8")
       .body(render))
 }
 
+#[get("/article/{aid}")]
+pub async fn article(
+    csrf: CsrfToken,
+    user: Option<CurrentUser>,
+    tmpl: web::Data<tera::Tera>,
+    db: web::Data<Pool>,
+    web::Path((aid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &user.is_some());
+
+    let (article, by_author) = web::block(move || {
+        let article = repo::get_article(pool.get()?, aid)?;
+        let by_author = repo::get_articles(pool.get()?, article.owner.to_owned())?;
+        Ok::<_, AppError>((article, by_author))
+    }).await?;
+
+    let may = |action| user.as_ref().is_some_and(|user| user.may(action, &article.owner));
+    let (can_edit, can_delete, can_hide) = (may(ArticleAction::Edit), may(ArticleAction::Delete), may(ArticleAction::Hide));
+    if !authz::can_view(user.as_ref(), &article) {
+        return Err(AppError::NotFound(format!("Article '{}' was not found", aid)));
+    }
+
+    let other_articles: Vec<&Article> = by_author.iter()
+        .filter(|other| other.id != article.id && !other.hidden && other.status == ArticleStatus::Published)
+        .collect();
+
+    ctx.insert("status_label", article.status.label());
+    ctx.insert("article", &article);
+    ctx.insert("other_articles", &other_articles);
+    ctx.insert("can_edit", &can_edit);
+    ctx.insert("can_delete", &can_delete);
+    ctx.insert("can_hide", &can_hide);
+
+    let body = tmpl.render("article.html", &ctx)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+       .content_type("text/html; charset=utf-8")
+    .body(body))
+}
+
 #[post("/article")]
 pub async fn post_new_article(
-    id: Identity,
+    user: CurrentUser,
     params: web::Form<CreateArticleForm>,
     db: web::Data<Pool>,
     session: Session,
-) -> HttpResponse {
+) -> Result<HttpResponse, AppError> {
     let pool = db.clone();
     let data = params.clone();
 
-    if let Some(id) = id.identity() {
-        let _res = web::block(move || {
-            let conn = pool.get().unwrap();
-            let user_data = Article{
-                id: -1,
-                owner: id,
-                title: data.title,
-                description: data.description,
-            };
-            repo::post_article(conn, user_data)
-        }).await
-        .map_err(|err| {
-            session.set("register_failure", &err.to_string()).unwrap();
-            return HttpResponse::Found().header("location", "/register").finish();
-        })
-        .map(|_| {
-            session.set("register_failure", "").unwrap();
-            return HttpResponse::Found().header("location", "/login").finish();
-        });
-        return HttpResponse::Found().header("location", "/login").finish();
-    } else {
-        return HttpResponse::Unauthorized().body("Unauthorized access");
+    user.require(Permission::CreateArticle)?;
+    let id = user.username;
+    if !authz::can_publish(&db, id.to_owned()).await? {
+        session.set("register_failure", "Verify your email address before publishing")?;
+        return Ok(HttpResponse::Found().header("location", "/article/create").finish());
+    }
+    if let Err(err) = data.validate() {
+        session.set("register_failure", err.to_string())?;
+        return Ok(HttpResponse::Found().header("location", "/article/create").finish());
+    }
+
+    let res = web::block(move || {
+        let conn = pool.get()?;
+        repo::post_article(conn, -1, id, data, Utc::now().timestamp())
+    }).await;
+
+    match res {
+        Ok(aid) => {
+            session.set("register_failure", "")?;
+            Ok(HttpResponse::Found().header("location", format!("/article/{}", aid)).finish())
+        }
+        Err(err) => {
+            session.set("register_failure", AppError::from(err).to_string())?;
+            Ok(HttpResponse::Found().header("location", "/article/create").finish())
+        }
     }
 }
@@ -0,0 +1,196 @@
+//! Versioned JSON API under `/api/v1`.
+//!
+//! Requests are authenticated like the HTML pages, with the session cookie or
+//! an `Authorization: Bearer` API token. Cookie authenticated requests that
+//! change something need the `X-CSRF-Token` header. Errors are `AppError`
+//! bodies, `{"error": ..., "message": ...}`.
+//!
+//! The OpenAPI document served at `/api/openapi.json` is generated from the
+//! `#[utoipa::path]` annotations of the handlers and the types they use.
+
+use actix_web::error::{JsonPayloadError, QueryPayloadError};
+use actix_web::{web, HttpRequest, HttpResponse};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use crate::error::AppError;
+
+pub mod articles;
+pub mod taxonomy;
+pub mod users;
+
+/// Where the API is mounted, the paths in `configure` are relative to it.
+pub const PREFIX: &str = "/api/v1";
+
+/// Registers every handler and records them in `OPERATIONS`, so the tests can
+/// compare what is served with what is documented.
+macro_rules! api_routes {
+    ($($method:ident $path:literal => $handler:path),* $(,)?) => {
+        /// Registers the API handlers, inside a scope mounted at `PREFIX`.
+        pub fn configure(cfg: &mut web::ServiceConfig) {
+            $(cfg.route($path, web::$method().to($handler));)*
+        }
+
+        /// Method and path of every registered operation.
+        #[cfg(test)]
+        const OPERATIONS: &[(&str, &str)] = &[$((stringify!($method), $path)),*];
+    };
+}
+
+api_routes! {
+    get "/articles" => articles::list,
+    post "/articles" => articles::create,
+    get "/articles/{aid}" => articles::get,
+    put "/articles/{aid}" => articles::replace,
+    patch "/articles/{aid}" => articles::update,
+    delete "/articles/{aid}" => articles::delete,
+    put "/articles/{aid}/status" => articles::set_status,
+    get "/tags" => taxonomy::tags,
+    get "/categories" => taxonomy::categories,
+    get "/users" => users::list,
+    get "/users/{uid}" => users::get,
+    delete "/users/{uid}" => users::delete,
+    put "/users/{uid}/role" => users::set_role,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Devclectic API", description = "JSON API for articles, their tags and categories, and user administration"),
+    paths(
+        articles::list,
+        articles::create,
+        articles::get,
+        articles::replace,
+        articles::update,
+        articles::delete,
+        articles::set_status,
+        taxonomy::tags,
+        taxonomy::categories,
+        users::list,
+        users::get,
+        users::delete,
+        users::set_role,
+    ),
+    modifiers(&Authentication),
+    security(("token" = []), ("session" = [])),
+    tags(
+        (name = "articles", description = "Articles, with the same permissions as the dashboard"),
+        (name = "taxonomy", description = "Tags and categories articles are filed under"),
+        (name = "users", description = "User administration, for accounts that can manage users"),
+    ),
+)]
+pub struct ApiDoc;
+
+/// Documents the two ways of authenticating.
+struct Authentication;
+
+impl Modify for Authentication {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "token",
+            SecurityScheme::Http(HttpBuilder::new()
+                .scheme(HttpAuthScheme::Bearer)
+                .description(Some("Personal API token, created in the dashboard"))
+                .build()),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                "auth",
+                "Session cookie of a logged in browser, changes also need the X-CSRF-Token header",
+            ))),
+        );
+    }
+}
+
+/// `GET /api/openapi.json`
+pub async fn openapi() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+/// Malformed JSON bodies are answered like any other validation error.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .error_handler(|err: JsonPayloadError, _: &HttpRequest| AppError::Validation(format!("Invalid request body: {}", err)).into())
+}
+
+/// Malformed query strings are answered like any other validation error.
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default()
+        .error_handler(|err: QueryPayloadError, _: &HttpRequest| AppError::Validation(format!("Invalid query string: {}", err)).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use serde_json::Value;
+    use super::*;
+
+    const METHODS: [&str; 8] = ["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+    fn document() -> Value {
+        serde_json::to_value(ApiDoc::openapi()).unwrap()
+    }
+
+    #[test]
+    fn documents_every_route() {
+        let doc = document();
+        let documented: BTreeSet<(String, String)> = doc["paths"].as_object().unwrap().iter()
+            .flat_map(|(path, item)| {
+                item.as_object().unwrap().keys()
+                    .filter(|key| METHODS.contains(&key.as_str()))
+                    .map(move |method| (method.to_string(), path.to_string()))
+            })
+            .collect();
+        let registered: BTreeSet<(String, String)> = OPERATIONS.iter()
+            .map(|(method, path)| (method.to_string(), format!("{}{}", PREFIX, path)))
+            .collect();
+
+        let undocumented: Vec<_> = registered.difference(&documented).collect();
+        let unrouted: Vec<_> = documented.difference(&registered).collect();
+        assert!(undocumented.is_empty(), "routes missing from the OpenAPI document: {:?}", undocumented);
+        assert!(unrouted.is_empty(), "documented operations that aren't routed: {:?}", unrouted);
+    }
+
+    #[test]
+    fn path_parameters_are_documented() {
+        let doc = document();
+        for (path, item) in doc["paths"].as_object().unwrap() {
+            let in_path: BTreeSet<&str> = path.split('/')
+                .filter_map(|segment| segment.strip_prefix('{').and_then(|segment| segment.strip_suffix('}')))
+                .collect();
+            for (method, operation) in item.as_object().unwrap() {
+                let documented: BTreeSet<&str> = operation["parameters"].as_array().into_iter().flatten()
+                    .filter(|parameter| parameter["in"] == "path")
+                    .filter_map(|parameter| parameter["name"].as_str())
+                    .collect();
+                assert_eq!(in_path, documented, "path parameters of {} {}", method, path);
+            }
+        }
+    }
+
+    #[test]
+    fn references_resolve() {
+        fn refs<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+            match value {
+                Value::Object(map) => {
+                    if let Some(Value::String(reference)) = map.get("$ref") {
+                        found.push(reference);
+                    }
+                    map.values().for_each(|value| refs(value, found));
+                }
+                Value::Array(items) => items.iter().for_each(|value| refs(value, found)),
+                _ => {}
+            }
+        }
+
+        let doc = document();
+        let mut found = Vec::new();
+        refs(&doc, &mut found);
+        assert!(!found.is_empty());
+        for reference in found {
+            let name = reference.strip_prefix("#/components/schemas/").unwrap_or_else(|| panic!("unexpected reference {}", reference));
+            assert!(doc["components"]["schemas"].get(name).is_some(), "schema {} is not defined", name);
+        }
+    }
+}
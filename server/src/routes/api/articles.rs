@@ -0,0 +1,283 @@
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use crate::authz::{self, ArticleAction, CurrentUser, Permission};
+use crate::error::{AppError, ErrorBody};
+use crate::models::{Article, ArticleFilter, ArticlePage, ArticlePatch, ArticleQuery, CreateArticleForm, StatusForm};
+use crate::repo;
+use crate::routes::dashboard;
+use crate::taxonomy;
+use crate::Pool;
+
+const DEFAULT_PER_PAGE: u32 = 20;
+const MAX_PER_PAGE: u32 = 100;
+
+/// Loads article `aid`, as a 404 for visitors who may not see it.
+async fn visible_article(db: &web::Data<Pool>, user: Option<&CurrentUser>, aid: i32) -> Result<Article, AppError> {
+    let pool = db.clone();
+    let article = web::block(move || repo::get_article(pool.get()?, aid)).await?;
+    if !authz::can_view(user, &article) {
+        return Err(AppError::NotFound(format!("Article '{}' was not found", aid)));
+    }
+    Ok(article)
+}
+
+/// Lists articles, newest first.
+///
+/// Hidden articles are only listed for their owner and for editors, moderators and admins,
+/// unpublished ones for their owner and for whoever can edit any article.
+#[utoipa::path(
+    get, path = "/api/v1/articles", tag = "articles", operation_id = "list_articles",
+    params(ArticleQuery),
+    responses(
+        (status = 200, description = "One page of articles", body = ArticlePage),
+        (status = 422, description = "Invalid query string or tags", body = ErrorBody),
+    ),
+)]
+pub async fn list(
+    user: Option<CurrentUser>,
+    query: web::Query<ArticleQuery>,
+    db: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let query = query.into_inner();
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+    let tags = query.tag.as_deref().map(taxonomy::parse_tags).unwrap_or_default();
+    taxonomy::validate_tags(&tags)?;
+    let filter = ArticleFilter {
+        owner: query.owner,
+        status: query.status,
+        tags,
+        category: query.category,
+        all_hidden: user.as_ref().is_some_and(authz::sees_all_articles),
+        all_unpublished: user.as_ref().is_some_and(authz::sees_all_unpublished),
+        viewer: user.map(|user| user.username),
+    };
+    let offset = (page as i64 - 1) * per_page as i64;
+    let (articles, total) = web::block(move || {
+        repo::get_articles_page(pool.get()?, filter, per_page as i64, offset)
+    }).await?;
+
+    Ok(HttpResponse::Ok().json(ArticlePage { articles, page, per_page, total }))
+}
+
+/// Fetches an article.
+#[utoipa::path(
+    get, path = "/api/v1/articles/{aid}", tag = "articles", operation_id = "get_article",
+    params(("aid" = i32, Path, description = "Article id")),
+    responses(
+        (status = 200, description = "The article", body = Article),
+        (status = 404, description = "No such article, or it is hidden or unpublished", body = ErrorBody),
+    ),
+)]
+pub async fn get(
+    user: Option<CurrentUser>,
+    db: web::Data<Pool>,
+    web::Path((aid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    let article = visible_article(&db, user.as_ref(), aid).await?;
+    Ok(HttpResponse::Ok().json(article))
+}
+
+/// Creates a new draft owned by the caller.
+#[utoipa::path(
+    post, path = "/api/v1/articles", tag = "articles", operation_id = "create_article",
+    request_body = CreateArticleForm,
+    responses(
+        (status = 201, description = "The new article, its url is in the `Location` header", body = Article),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Not allowed to create articles, or the email address is not verified", body = ErrorBody),
+        (status = 422, description = "Invalid article", body = ErrorBody),
+    ),
+)]
+pub async fn create(
+    user: CurrentUser,
+    params: web::Json<CreateArticleForm>,
+    db: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let data = params.into_inner();
+
+    user.require(Permission::CreateArticle)?;
+    if !authz::can_publish(&db, user.username.to_owned()).await? {
+        return Err(AppError::Forbidden("Verify your email address before publishing".to_string()));
+    }
+    data.validate()?;
+
+    let owner = user.username;
+    let article = web::block(move || {
+        let aid = repo::post_article(pool.get()?, -1, owner, data, Utc::now().timestamp())?;
+        repo::get_article(pool.get()?, aid)
+    }).await?;
+
+    Ok(HttpResponse::Created()
+        .header("location", format!("/api/v1/articles/{}", article.id))
+        .json(article))
+}
+
+/// Saves new content for article `aid` after the same checks as the dashboard editor.
+async fn save(db: &web::Data<Pool>, user: &CurrentUser, aid: i32, data: CreateArticleForm) -> Result<HttpResponse, AppError> {
+    data.validate()?;
+
+    let pool = db.clone();
+    let author = user.username.to_owned();
+    let article = web::block(move || {
+        repo::update_article(pool.get()?, aid, author, data, Utc::now().timestamp())?;
+        repo::get_article(pool.get()?, aid)
+    }).await?;
+    log::info!(target: "audit", "user '{}' updated article {} through the API", user.username, aid);
+
+    Ok(HttpResponse::Ok().json(article))
+}
+
+/// Replaces the title and text of an article.
+///
+/// The `version` the changes were made to is required, the update is rejected
+/// rather than overwrite changes saved by someone else in the meantime.
+#[utoipa::path(
+    put, path = "/api/v1/articles/{aid}", tag = "articles", operation_id = "replace_article",
+    params(("aid" = i32, Path, description = "Article id")),
+    request_body = CreateArticleForm,
+    responses(
+        (status = 200, description = "The updated article", body = Article),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Not allowed to edit the article", body = ErrorBody),
+        (status = 404, description = "No such article", body = ErrorBody),
+        (status = 409, description = "The article was saved since `version`", body = ErrorBody),
+        (status = 422, description = "Invalid article or no `version`", body = ErrorBody),
+    ),
+)]
+pub async fn replace(
+    user: CurrentUser,
+    params: web::Json<CreateArticleForm>,
+    db: web::Data<Pool>,
+    web::Path((aid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    visible_article(&db, Some(&user), aid).await?;
+    authz::require_article(&db, &user, aid, ArticleAction::Edit).await?;
+    save(&db, &user, aid, params.into_inner()).await
+}
+
+/// Changes the fields of an article that are present.
+///
+/// Without a `version` the patch is applied to the version read when the request
+/// arrived, it is still rejected when a save comes in between.
+#[utoipa::path(
+    patch, path = "/api/v1/articles/{aid}", tag = "articles", operation_id = "update_article",
+    params(("aid" = i32, Path, description = "Article id")),
+    request_body = ArticlePatch,
+    responses(
+        (status = 200, description = "The updated article", body = Article),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Not allowed to edit the article", body = ErrorBody),
+        (status = 404, description = "No such article", body = ErrorBody),
+        (status = 409, description = "The article was saved since `version`", body = ErrorBody),
+        (status = 422, description = "Invalid article", body = ErrorBody),
+    ),
+)]
+pub async fn update(
+    user: CurrentUser,
+    params: web::Json<ArticlePatch>,
+    db: web::Data<Pool>,
+    web::Path((aid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    let current = visible_article(&db, Some(&user), aid).await?;
+    authz::require_article(&db, &user, aid, ArticleAction::Edit).await?;
+
+    let patch = params.into_inner();
+    let data = CreateArticleForm {
+        title: patch.title.unwrap_or(current.title),
+        description: patch.description.unwrap_or(current.description),
+        version: patch.version.or(Some(current.version)),
+        tags: patch.tags,
+        category: patch.category,
+    };
+    save(&db, &user, aid, data).await
+}
+
+/// Deletes an article.
+#[utoipa::path(
+    delete, path = "/api/v1/articles/{aid}", tag = "articles", operation_id = "delete_article",
+    params(("aid" = i32, Path, description = "Article id")),
+    responses(
+        (status = 204, description = "The article was deleted"),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Not allowed to delete the article", body = ErrorBody),
+        (status = 404, description = "No such article", body = ErrorBody),
+    ),
+)]
+pub async fn delete(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+    web::Path((aid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    visible_article(&db, Some(&user), aid).await?;
+    authz::require_article(&db, &user, aid, ArticleAction::Delete).await?;
+
+    let pool = db.clone();
+    web::block(move || repo::del_article(pool.get()?, aid)).await?;
+    log::info!(target: "audit", "user '{}' deleted article {} through the API", user.username, aid);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Moves an article through the workflow.
+///
+/// Drafts go to review or get published, articles in review go back to draft or get
+/// published, published articles go back to draft or get archived and archived ones
+/// go back to draft or get published again. Publishing and unpublishing need the
+/// `article.publish` permission on top of being able to edit the article.
+#[utoipa::path(
+    put, path = "/api/v1/articles/{aid}/status", tag = "articles", operation_id = "set_article_status",
+    params(("aid" = i32, Path, description = "Article id")),
+    request_body = StatusForm,
+    responses(
+        (status = 200, description = "The updated article", body = Article),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Not allowed to make this move", body = ErrorBody),
+        (status = 404, description = "No such article", body = ErrorBody),
+        (status = 409, description = "The workflow doesn't allow this move from the current status", body = ErrorBody),
+        (status = 422, description = "Unknown status", body = ErrorBody),
+    ),
+)]
+pub async fn set_status(
+    user: CurrentUser,
+    params: web::Json<StatusForm>,
+    db: web::Data<Pool>,
+    web::Path((aid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    visible_article(&db, Some(&user), aid).await?;
+    dashboard::move_article(&user, &db, aid, params.status).await?;
+
+    let pool = db.clone();
+    let article = web::block(move || repo::get_article(pool.get()?, aid)).await?;
+    Ok(HttpResponse::Ok().json(article))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use actix_web::http::StatusCode;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use crate::migrate;
+    use super::*;
+
+    #[actix_rt::test]
+    async fn rejects_invalid_tag_filters() {
+        let pool = Pool::builder().max_size(1).build(SqliteConnectionManager::memory()).unwrap();
+        migrate::run(&mut pool.get().unwrap()).unwrap();
+        let mut app = test::init_service(
+            App::new().data(pool).route("/api/v1/articles", web::get().to(list))
+        ).await;
+
+        for (query, status) in [
+            ("tag=rust,c%2B%2B", StatusCode::OK),
+            ("tag=rust%20lang", StatusCode::UNPROCESSABLE_ENTITY),
+            ("tag=a,b,c,d,e,f,g,h,i,j,k", StatusCode::UNPROCESSABLE_ENTITY),
+        ] {
+            let req = test::TestRequest::get().uri(&format!("/api/v1/articles?{}", query)).to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), status, "{}", query);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use actix_web::{web, HttpResponse};
+use crate::error::{AppError, ErrorBody};
+use crate::models::{Category, TagCount, TagQuery};
+use crate::repo;
+use crate::taxonomy;
+use crate::Pool;
+
+const DEFAULT_TAG_LIMIT: u32 = 10;
+const MAX_TAG_LIMIT: u32 = 100;
+
+/// Lists the tags of published articles, the most used first.
+///
+/// Meant for autocompletion, `q` narrows the list down to tags starting with it.
+#[utoipa::path(
+    get, path = "/api/v1/tags", tag = "taxonomy", operation_id = "list_tags",
+    params(TagQuery),
+    responses(
+        (status = 200, description = "Matching tags", body = Vec<TagCount>),
+        (status = 422, description = "Invalid query string", body = ErrorBody),
+    ),
+)]
+pub async fn tags(
+    query: web::Query<TagQuery>,
+    db: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let prefix = query.q.as_deref().map(taxonomy::normalize_tag).unwrap_or_default();
+    let limit = query.limit.unwrap_or(DEFAULT_TAG_LIMIT).clamp(1, MAX_TAG_LIMIT);
+
+    let tags = web::block(move || repo::get_tags(pool.get()?, prefix, limit as i64)).await?;
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+/// Lists every category, each one followed by its subcategories.
+#[utoipa::path(
+    get, path = "/api/v1/categories", tag = "taxonomy", operation_id = "list_categories",
+    responses(
+        (status = 200, description = "Every category, depth first", body = Vec<Category>),
+    ),
+)]
+pub async fn categories(db: web::Data<Pool>) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let categories = web::block(move || repo::get_categories(pool.get()?)).await?;
+    let ordered: Vec<Category> = taxonomy::tree(categories).into_iter().map(|node| node.category).collect();
+    Ok(HttpResponse::Ok().json(ordered))
+}
@@ -0,0 +1,102 @@
+use actix_web::{web, HttpResponse};
+use crate::authz::{CurrentUser, Permission, PermissionCache};
+use crate::error::{AppError, ErrorBody};
+use crate::models::{RoleForm, User};
+use crate::repo;
+use crate::routes::dashboard::manage_user;
+use crate::Pool;
+
+/// Lists every user.
+#[utoipa::path(
+    get, path = "/api/v1/users", tag = "users", operation_id = "list_users",
+    responses(
+        (status = 200, description = "Every user", body = Vec<User>),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Not allowed to manage users", body = ErrorBody),
+    ),
+)]
+pub async fn list(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::ManageUsers)?;
+    let pool = db.clone();
+
+    let users = web::block(move || repo::get_users(pool.get()?)).await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+/// Fetches a user.
+#[utoipa::path(
+    get, path = "/api/v1/users/{uid}", tag = "users", operation_id = "get_user",
+    params(("uid" = i32, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user", body = User),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Not allowed to manage users", body = ErrorBody),
+        (status = 404, description = "No such user", body = ErrorBody),
+    ),
+)]
+pub async fn get(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+    web::Path((uid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::ManageUsers)?;
+    let pool = db.clone();
+
+    let found = web::block(move || repo::get_user_by_id(pool.get()?, uid)).await?;
+    Ok(HttpResponse::Ok().json(found))
+}
+
+/// Gives a user another role.
+#[utoipa::path(
+    put, path = "/api/v1/users/{uid}/role", tag = "users", operation_id = "set_user_role",
+    params(("uid" = i32, Path, description = "User id")),
+    request_body = RoleForm,
+    responses(
+        (status = 200, description = "The updated user", body = User),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Not allowed to manage users", body = ErrorBody),
+        (status = 404, description = "No such user", body = ErrorBody),
+        (status = 409, description = "Nobody would be left to manage users", body = ErrorBody),
+        (status = 422, description = "Unknown role", body = ErrorBody),
+    ),
+)]
+pub async fn set_role(
+    user: CurrentUser,
+    params: web::Json<RoleForm>,
+    db: web::Data<Pool>,
+    cache: web::Data<PermissionCache>,
+    web::Path((uid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    let role = params.into_inner().role;
+    let description = format!("gave role '{}' through the API to", role);
+    manage_user(&user, &db, &cache, uid, description, move |conn, uid| repo::set_role(conn, uid, role)).await?;
+
+    let pool = db.clone();
+    let updated = web::block(move || repo::get_user_by_id(pool.get()?, uid)).await?;
+    Ok(HttpResponse::Ok().json(updated))
+}
+
+/// Deletes a user.
+#[utoipa::path(
+    delete, path = "/api/v1/users/{uid}", tag = "users", operation_id = "delete_user",
+    params(("uid" = i32, Path, description = "User id")),
+    responses(
+        (status = 204, description = "The user was deleted"),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 403, description = "Not allowed to manage users", body = ErrorBody),
+        (status = 404, description = "No such user", body = ErrorBody),
+        (status = 409, description = "Nobody would be left to manage users", body = ErrorBody),
+    ),
+)]
+pub async fn delete(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+    cache: web::Data<PermissionCache>,
+    web::Path((uid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    manage_user(&user, &db, &cache, uid, "deleted through the API".to_string(), repo::del_user).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
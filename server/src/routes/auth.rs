@@ -1,24 +1,34 @@
-use serde_json::Value::Bool;
-use serde_json::Value;
-use actix_web::client::Client;
 use crate::repo::register_user;
 use crate::models::SlimUser;
 use actix_session::Session;
-use crate::repo::get_user;
+use crate::repo::{get_permissions, get_session_stamp, get_user, set_password, set_verify_nonce, verify_email};
+use crate::repo::{create_password_reset, find_password_reset, get_username_by_email, reset_password};
+use crate::token::{self, Signer};
+use crate::mailer::Mailer;
+use chrono::Utc;
+use crate::password::{self, HashPolicy, Verification};
+use crate::identity;
+use crate::throttle::LoginThrottle;
+use crate::totp::{self, SecondFactor, TwoFactor};
+use crate::authz::{CurrentUser, Permission};
+use crate::email::EmailValidator;
 use crate::Pool;
-use crate::models::{LoginForm, RegisterForm};
-use actix_web::{error, web, HttpResponse, Result};
+use crate::models::{CodeForm, ForgotPasswordForm, LoginForm, RegisterForm, ResetPasswordForm};
+use crate::error::AppError;
+use crate::csrf::{self, CsrfToken};
+use actix_web::{web, HttpRequest, HttpResponse};
 use actix_web::http::StatusCode;
 use actix_identity::Identity;
 
 pub async fn login_form(
+  csrf: CsrfToken,
   id: Identity,
   tmpl: web::Data<tera::Tera>,
   session: Session,
-) -> Result<HttpResponse> {
-  if let Some(_id) = id.identity() {return Ok(HttpResponse::Found().header("location", "/").finish());}
+) -> Result<HttpResponse, AppError> {
+  if id.identity().is_some() {return Ok(HttpResponse::Found().header("location", "/").finish());}
 
-  let mut ctx = tera::Context::new();
+  let mut ctx = csrf.context();
   ctx.insert("is_logedin", &false);
 
   if let Some(fail) = session.get::<String>("login_failure")? {
@@ -27,57 +37,220 @@ pub async fn login_form(
     ctx.insert("failed", "");
   }
 
-  let render = tmpl.render("login.html", &ctx)
-    .map_err(|_| error::ErrorInternalServerError("Template error")).expect("Test");
+  if let Some(notice) = session.get::<String>("login_notice")? {
+    ctx.insert("notice", &notice);
+    session.remove("login_notice");
+  } else {
+    ctx.insert("notice", "");
+  }
+
+  let render = tmpl.render("login.html", &ctx)?;
 
   Ok(HttpResponse::build(StatusCode::OK)
       .content_type("text/html; charset=utf-8")
       .body(render))
 }
 
+/// The same for unknown accounts and wrong passwords, so it doesn't tell which usernames exist.
+const LOGIN_FAILED: &str = "Invalid username or password";
+
 pub async fn login(
+  req: HttpRequest,
   id: Identity,
   params: web::Form<LoginForm>,
   db: web::Data<Pool>,
+  policy: web::Data<HashPolicy>,
+  throttle: web::Data<LoginThrottle>,
+  two_factor: web::Data<TwoFactor>,
   session: Session,
-) -> HttpResponse {
+) -> Result<HttpResponse, AppError> {
     let pool = db.clone();
     let data = params.clone();
+    let ip = throttle.client_ip(&req);
+    let username = data.username.to_owned();
 
+    let locks = match throttle.attempt(ip, &username) {
+        Ok(locks) => locks,
+        Err(wait) => {
+            log::warn!(target: "audit", "throttled: login as '{}' from {}", username, ip);
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            session.set("login_failure", format!("Too many failed attempts, try again in {} seconds", secs))?;
+            return Ok(HttpResponse::Found().header("location", "/login").finish());
+        }
+    };
+
+    let required = two_factor.required_for_admins;
     let res = web::block(move || {
-        let conn = pool.get().unwrap();
-        get_user(conn, data.username)
-    }).await
-      .map_err(|err| {
-        session.set("login_failure", &err.to_string()).unwrap();
-        HttpResponse::Found().header("location", "/login").finish()
-      })
-      .map(|user| {
-        if user.password != data.password {
-          session.set("login_failure", &"Bad password").unwrap();
-          return HttpResponse::Found().header("location", "/login").finish();
-        }
-          id.remember(user.username.to_owned());
-          session.set("login_failure", "").unwrap();
-          HttpResponse::Found().header("location", "/").finish()
-      });
-
-    match res {Ok(res) => res, Err(res) => res,}
+        let user = match get_user(pool.get()?, data.username) {
+            Ok(user) => user,
+            Err(AppError::NotFound(_)) => {
+                // Takes as long as checking a password would
+                policy.hash(&data.password)?;
+                return Err(AppError::Unauthorized(LOGIN_FAILED.to_string()));
+            }
+            Err(err) => return Err(err),
+        };
+        match policy.verify(&data.password, &user.password) {
+            Verification::Invalid => return Err(AppError::Unauthorized(LOGIN_FAILED.to_string())),
+            Verification::Valid => (),
+            Verification::NeedsRehash => {
+                let hash = policy.hash(&data.password)?;
+                set_password(pool.get()?, user.username.to_owned(), hash)?;
+            }
+        }
+        let must_enroll = required && !user.two_factor && get_permissions(pool.get()?, user.username.to_owned())?
+            .1.iter().any(|permission| permission == Permission::ManageUsers.name());
+        Ok((user, must_enroll))
+    }).await.map_err(AppError::from);
+
+    match res {
+        // The password was right, the code is asked for on the next page
+        Ok((user, _)) if user.two_factor => {
+            throttle.release(ip, &username, locks);
+            session.set(TWO_FACTOR_USER, &user.username)?;
+            session.set(TWO_FACTOR_SINCE, Utc::now().timestamp())?;
+            session.set("login_failure", "")?;
+            Ok(HttpResponse::Found().header("location", "/login/2fa").finish())
+        }
+        Ok((user, must_enroll)) => {
+            throttle.success(ip, &username);
+            id.remember(identity::stamped(&user.session_stamp, &user.username));
+            csrf::renew(&session)?;
+            session.set("login_failure", "")?;
+            let location = if must_enroll { "/dashboard/options" } else { "/" };
+            Ok(HttpResponse::Found().header("location", location).finish())
+        }
+        Err(err) => {
+            if locks {
+                log::warn!(target: "audit", "locked: account '{}' after repeated failed logins, last from {}", username, ip);
+            }
+            session.set("login_failure", err.to_string())?;
+            Ok(HttpResponse::Found().header("location", "/login").finish())
+        }
+    }
+}
+
+/// Session keys of a login waiting for its second factor.
+const TWO_FACTOR_USER: &str = "two_factor_user";
+const TWO_FACTOR_SINCE: &str = "two_factor_since";
+/// How long the second step may take after the password was accepted.
+const TWO_FACTOR_TIMEOUT_SECS: i64 = 300;
+
+fn clear_two_factor(session: &Session) {
+    session.remove(TWO_FACTOR_USER);
+    session.remove(TWO_FACTOR_SINCE);
+}
+
+/// The user whose password was accepted, when that wasn't too long ago.
+fn pending_two_factor(session: &Session) -> Result<Option<String>, AppError> {
+    let since = session.get::<i64>(TWO_FACTOR_SINCE)?.unwrap_or(0);
+    if Utc::now().timestamp() - since > TWO_FACTOR_TIMEOUT_SECS {
+        return Ok(None);
+    }
+    Ok(session.get::<String>(TWO_FACTOR_USER)?)
+}
+
+pub async fn two_factor_form(
+  csrf: CsrfToken,
+  tmpl: web::Data<tera::Tera>,
+  session: Session,
+) -> Result<HttpResponse, AppError> {
+  if pending_two_factor(&session)?.is_none() {
+    clear_two_factor(&session);
+    return Ok(HttpResponse::Found().header("location", "/login").finish());
+  }
+
+  let mut ctx = csrf.context();
+  ctx.insert("is_loggedin", &false);
+
+  if let Some(fail) = session.get::<String>("two_factor_failure")? {
+    ctx.insert("failed", &fail);
+    session.remove("two_factor_failure");
+  } else {
+    ctx.insert("failed", "");
+  }
+
+  let render = tmpl.render("login_2fa.html", &ctx)?;
+
+  Ok(HttpResponse::build(StatusCode::OK)
+      .content_type("text/html; charset=utf-8")
+      .body(render))
 }
 
-pub async fn logout(id: Identity) -> HttpResponse {
+pub async fn two_factor_login(
+  req: HttpRequest,
+  id: Identity,
+  params: web::Form<CodeForm>,
+  db: web::Data<Pool>,
+  two_factor: web::Data<TwoFactor>,
+  throttle: web::Data<LoginThrottle>,
+  session: Session,
+) -> Result<HttpResponse, AppError> {
+    let username = match pending_two_factor(&session)? {
+        Some(username) => username,
+        None => {
+            clear_two_factor(&session);
+            session.set("login_failure", "Your sign in expired, please try again")?;
+            return Ok(HttpResponse::Found().header("location", "/login").finish());
+        }
+    };
+    let ip = throttle.client_ip(&req);
+
+    let locks = match throttle.attempt(ip, &username) {
+        Ok(locks) => locks,
+        Err(wait) => {
+            log::warn!(target: "audit", "throttled: two-factor code for '{}' from {}", username, ip);
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            session.set("two_factor_failure", format!("Too many failed attempts, try again in {} seconds", secs))?;
+            return Ok(HttpResponse::Found().header("location", "/login/2fa").finish());
+        }
+    };
+
+    let factor = totp::check(&two_factor, &db, username.to_owned(), params.code.to_owned()).await?;
+    let factor = match factor {
+        Some(factor) => factor,
+        None => {
+            if locks {
+                log::warn!(target: "audit", "locked: account '{}' after repeated failed two-factor codes, last from {}", username, ip);
+            }
+            session.set("two_factor_failure", "Invalid code")?;
+            return Ok(HttpResponse::Found().header("location", "/login/2fa").finish());
+        }
+    };
+
+    let pool = db.clone();
+    let name = username.to_owned();
+    let stamp = web::block(move || get_session_stamp(pool.get()?, name)).await?;
+
+    throttle.success(ip, &username);
+    clear_two_factor(&session);
+    id.remember(identity::stamped(&stamp, &username));
+    csrf::renew(&session)?;
+    match factor {
+        SecondFactor::Totp => Ok(HttpResponse::Found().header("location", "/").finish()),
+        SecondFactor::RecoveryCode => {
+            log::info!(target: "audit", "user '{}' signed in with a recovery code", username);
+            session.set("options_message", "You signed in with a recovery code, it can't be used again")?;
+            Ok(HttpResponse::Found().header("location", "/dashboard/options").finish())
+        }
+    }
+}
+
+pub async fn logout(id: Identity, session: Session) -> Result<HttpResponse, AppError> {
     id.forget();
-    HttpResponse::Found().header("location", "/").finish()
+    csrf::renew(&session)?;
+    Ok(HttpResponse::Found().header("location", "/").finish())
 }
 
 pub async fn register_form(
+  csrf: CsrfToken,
   id: Identity,
   tmpl: web::Data<tera::Tera>,
   session: Session,
-) -> Result<HttpResponse> {
-  if let Some(_id) = id.identity() {return Ok(HttpResponse::Found().header("location", "/").finish());}
+) -> Result<HttpResponse, AppError> {
+  if id.identity().is_some() {return Ok(HttpResponse::Found().header("location", "/").finish());}
 
-  let mut ctx = tera::Context::new();
+  let mut ctx = csrf.context();
   ctx.insert("is_logedin", &false);
 
   if let Some(fail) = session.get::<String>("register_failure")? {
@@ -86,8 +259,7 @@ pub async fn register_form(
     ctx.insert("failed", "");
   }
 
-  let render = tmpl.render("register.html", &ctx)
-    .map_err(|_| error::ErrorInternalServerError("Template error")).expect("Test");
+  let render = tmpl.render("register.html", &ctx)?;
 
   Ok(HttpResponse::build(StatusCode::OK)
       .content_type("text/html; charset=utf-8")
@@ -97,50 +269,261 @@ pub async fn register_form(
 pub async fn register(
   params: web::Form<RegisterForm>,
   db: web::Data<Pool>,
+  policy: web::Data<HashPolicy>,
+  validator: web::Data<dyn EmailValidator>,
+  signer: web::Data<Signer>,
+  mailer: web::Data<Mailer>,
   session: Session,
-) -> HttpResponse {
+) -> Result<HttpResponse, AppError> {
     let pool = db.clone();
     let data = params.clone();
 
-    let client = Client::default();
-    let resp = client.get(format!("https://mailcheck.p.rapidapi.com/?domain={}", data.email))
-        .header("x-rapidapi-host", "mailcheck.p.rapidapi.com")
-        .header("x-rapidapi-key", "4f21a0e8e2msh739ef3c426b9383p107fb9jsn513436059c77")
-        .send()
-        .await
-        .unwrap()
-        .body()
-        .await
-        .unwrap();
-
-    let json: Result<Value, _> = serde_json::from_slice(&resp);
-    if let Bool(block) = json.unwrap().get("block").unwrap() {
-        if *block {
-          session.set("register_failure", "Invalid email").unwrap();
-          return HttpResponse::Found().header("location", "/register").finish();
-        }
+    if let Err(reason) = validator.validate(&data.email).await {
+        session.set("register_failure", reason)?;
+        return Ok(HttpResponse::Found().header("location", "/register").finish());
     }
-    if data.password != data.password_confirm {
-        session.set("register_failure", "Password do not match").unwrap();
-        return HttpResponse::Found().header("location", "/register").finish();
+    if let Err(err) = password::check_rules(&data.password, &data.password_confirm) {
+        session.set("register_failure", err.to_string())?;
+        return Ok(HttpResponse::Found().header("location", "/register").finish());
     }
 
-    let _res = web::block(move || {
-        let conn = pool.get().unwrap();
+    let res = web::block(move || {
         let user_data = SlimUser{
-          username: data.username,
-          password: data.password,
+          username: data.username.to_owned(),
+          email: Some(data.email.to_owned()),
+          password: policy.hash(&data.password)?,
+          session_stamp: token::random_nonce(),
+          two_factor: false,
+        };
+        register_user(pool.get()?, user_data)?;
+
+        // The account exists at this point, a lost email can be resent from the dashboard
+        if let Err(err) = send_verification(&pool, &signer, &mailer, data.username, &data.email) {
+            log::warn!("Verification email to '{}' failed: {}", data.email, err);
+        }
+        Ok::<_, AppError>(())
+    }).await;
+
+    match res {
+        Ok(_) => {
+            session.set("register_failure", "")?;
+            Ok(HttpResponse::Found().header("location", "/login").finish())
+        }
+        Err(err) => {
+            session.set("register_failure", AppError::from(err).to_string())?;
+            Ok(HttpResponse::Found().header("location", "/register").finish())
+        }
+    }
+}
+
+/// How long an emailed verification link stays valid.
+const VERIFY_TTL_HOURS: i64 = 48;
+
+/// Stores a fresh single use nonce for `username` and emails the signed link to `email`.
+fn send_verification(pool: &Pool, signer: &Signer, mailer: &Mailer, username: String, email: &str) -> Result<(), AppError> {
+    let nonce = token::random_nonce();
+    set_verify_nonce(pool.get()?, username.to_owned(), nonce.to_owned())?;
+
+    let expires = Utc::now().timestamp() + VERIFY_TTL_HOURS * 3600;
+    let token = signer.sign(&format!("verify:{}:{}:{}", expires, nonce, username));
+    let body = format!(
+        "Hello {},\n\nconfirm your email address by opening the link below:\n\n{}\n\nThe link expires in {} hours.\n",
+        username, mailer.link(&format!("/verify/{}", token)), VERIFY_TTL_HOURS,
+    );
+    mailer.send(email, "Confirm your Devclectic account", body)
+}
+
+pub async fn verify(
+  csrf: CsrfToken,
+  id: Identity,
+  tmpl: web::Data<tera::Tera>,
+  db: web::Data<Pool>,
+  signer: web::Data<Signer>,
+  web::Path((token,)): web::Path<(String,)>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+
+    let res = web::block(move || {
+        let invalid = || AppError::Validation("Invalid verification link".to_string());
+        let payload = signer.verify(&token).ok_or_else(invalid)?;
+        let mut parts = payload.splitn(4, ':');
+        let (purpose, expires, nonce, username) = (parts.next(), parts.next(), parts.next(), parts.next());
+        let (expires, nonce, username) = match (purpose, expires.and_then(|e| e.parse::<i64>().ok()), nonce, username) {
+            (Some("verify"), Some(expires), Some(nonce), Some(username)) => (expires, nonce, username),
+            _ => return Err(invalid()),
         };
+        if expires < Utc::now().timestamp() {
+            return Err(AppError::Validation("The verification link has expired".to_string()));
+        }
+        if !verify_email(pool.get()?, username.to_string(), nonce.to_string())? {
+            return Err(AppError::Conflict("The verification link was already used".to_string()));
+        }
+        Ok(())
+    }).await;
+
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &id.identity().is_some());
+    let status = match res.map_err(AppError::from) {
+        Ok(_) => {
+            ctx.insert("message", "Your email address is verified");
+            StatusCode::OK
+        }
+        Err(err @ AppError::Internal(_)) => return Err(err),
+        Err(err) => {
+            ctx.insert("message", &err.to_string());
+            StatusCode::BAD_REQUEST
+        }
+    };
+
+    let render = tmpl.render("verify.html", &ctx)?;
+
+    Ok(HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .body(render))
+}
+
+pub async fn resend_verification(
+  user: CurrentUser,
+  db: web::Data<Pool>,
+  signer: web::Data<Signer>,
+  mailer: web::Data<Mailer>,
+  session: Session,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    let pool = db.clone();
+
+    let username = user.username.to_owned();
+    let res = web::block(move || {
+        let user = get_user(pool.get()?, username)?;
+        let email = user.email.ok_or_else(|| AppError::Validation("Your account has no email address".to_string()))?;
+        send_verification(&pool, &signer, &mailer, user.username, &email)
+    }).await;
+
+    let message = match res {
+        Ok(_) => "Verification email sent".to_string(),
+        Err(err) => AppError::from(err).to_string(),
+    };
+    session.set("options_message", message)?;
+    Ok(HttpResponse::Found().header("location", "/dashboard/options").finish())
+}
+
+/// How long an emailed password reset link stays valid.
+const RESET_TTL_MINUTES: i64 = 60;
+
+pub async fn forgot_password(
+  params: web::Form<ForgotPasswordForm>,
+  db: web::Data<Pool>,
+  mailer: web::Data<Mailer>,
+  session: Session,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let email = params.email.trim().to_string();
+
+    // Unknown addresses get the same answer, so accounts can't be probed. The lookup and the
+    // email happen after the response, known addresses would be slower to answer otherwise
+    actix_rt::spawn(async move {
+        let res = web::block(move || {
+            let username = match get_username_by_email(pool.get()?, email.to_owned()) {
+                Ok(username) => username,
+                Err(AppError::NotFound(_)) => return Ok(()),
+                Err(err) => return Err(err),
+            };
 
-        register_user(conn, user_data)
-    }).await
-      .map_err(|err| {
-        session.set("register_failure", &err.to_string()).unwrap();
-        return HttpResponse::Found().header("location", "/register").finish();
-      })
-      .map(|_| {
-          session.set("register_failure", "").unwrap();
-          return HttpResponse::Found().header("location", "/login").finish();
-      });
-      return HttpResponse::Found().header("location", "/login").finish();
+            // Only a hash is stored, a leaked database can't be used to reset passwords
+            let token = token::random_nonce();
+            let expires_at = Utc::now().timestamp() + RESET_TTL_MINUTES * 60;
+            create_password_reset(pool.get()?, username.to_owned(), token::digest(&token), expires_at)?;
+
+            let body = format!(
+                "Hello {},\n\nsomeone asked to reset your password. If it was you, open the link below:\n\n{}\n\nThe link expires in {} minutes. You can ignore this email otherwise.\n",
+                username, mailer.link(&format!("/password/reset/{}", token)), RESET_TTL_MINUTES,
+            );
+            mailer.send(&email, "Reset your Devclectic password", body)
+        }).await;
+
+        if let Err(err) = res {
+            log::warn!("Password reset request failed: {}", AppError::from(err));
+        }
+    });
+    session.set("login_notice", "If the address belongs to an account, a reset link is on its way")?;
+    Ok(HttpResponse::Found().header("location", "/login").finish())
+}
+
+pub async fn reset_password_form(
+  csrf: CsrfToken,
+  tmpl: web::Data<tera::Tera>,
+  db: web::Data<Pool>,
+  session: Session,
+  web::Path((token,)): web::Path<(String,)>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let token_hash = token::digest(&token);
+
+    let res = web::block(move || find_password_reset(pool.get()?, token_hash, Utc::now().timestamp())).await;
+
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &false);
+    ctx.insert("token", &token);
+    let status = match res.map_err(AppError::from) {
+        Ok(_) => {
+            ctx.insert("valid", &true);
+            StatusCode::OK
+        }
+        Err(err @ AppError::NotFound(_)) => {
+            ctx.insert("valid", &false);
+            session.set("reset_failure", err.to_string())?;
+            StatusCode::NOT_FOUND
+        }
+        Err(err) => return Err(err),
+    };
+
+    if let Some(fail) = session.get::<String>("reset_failure")? {
+        ctx.insert("failed", &fail);
+        session.remove("reset_failure");
+    } else {
+        ctx.insert("failed", "");
+    }
+
+    let render = tmpl.render("reset_password.html", &ctx)?;
+
+    Ok(HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .body(render))
+}
+
+pub async fn reset_password_submit(
+  params: web::Form<ResetPasswordForm>,
+  db: web::Data<Pool>,
+  policy: web::Data<HashPolicy>,
+  session: Session,
+  web::Path((token,)): web::Path<(String,)>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let data = params.clone();
+    let form_location = format!("/password/reset/{}", token);
+
+    if let Err(err) = password::check_rules(&data.password, &data.password_confirm) {
+        session.set("reset_failure", err.to_string())?;
+        return Ok(HttpResponse::Found().header("location", form_location).finish());
+    }
+
+    let res = web::block(move || {
+        let token_hash = token::digest(&token);
+        // Checked before the slow hashing, `reset_password` checks again as it consumes the token
+        find_password_reset(pool.get()?, token_hash.to_owned(), Utc::now().timestamp())?;
+        let hash = policy.hash(&data.password)?;
+        reset_password(pool.get()?, token_hash, Utc::now().timestamp(), hash, token::random_nonce())
+    }).await;
+
+    match res {
+        Ok(username) => {
+            log::info!(target: "audit", "password of '{}' was reset, existing sessions revoked", username);
+            session.set("login_notice", "Your password was changed, log in with the new one")?;
+            Ok(HttpResponse::Found().header("location", "/login").finish())
+        }
+        Err(err) => {
+            session.set("reset_failure", AppError::from(err).to_string())?;
+            Ok(HttpResponse::Found().header("location", form_location).finish())
+        }
+    }
 }
@@ -1,359 +1,998 @@
-use crate::models::CreateArticleForm;
-use crate::models::Article;
+use crate::models::{ApiTokenForm, CategoryForm, CodeForm, CreateArticleForm, PreviewForm, RoleForm, ScheduleForm, StatusForm, VisibilityForm};
+use crate::models::{Article, ArticleRevision, ArticleStatus};
 use actix_session::Session;
-use actix_identity::Identity;
-use actix_web::HttpResponse;
+use actix_web::{HttpRequest, HttpResponse};
 use actix_web::http::{StatusCode};
-use actix_web::{error, web, Result};
+use actix_web::web;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::Serialize;
+use crate::error::AppError;
+use crate::csrf::CsrfToken;
 use crate::Pool;
+use crate::throttle::LoginThrottle;
+use crate::totp::{self, TwoFactor};
 use crate::repo;
+use crate::markdown;
+use crate::diff;
+use crate::taxonomy;
+use crate::token;
+use crate::authz::{self, ArticleAction, CurrentUser, Permission, PermissionCache, Scope};
 
 pub async fn dashboard(
-    id: Identity,
+    _user: CurrentUser,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    session.set("article_focus", -1)?;
+    Ok(HttpResponse::Found().header("location", "/dashboard/options").finish())
+}
+
+pub async fn dashboard_options(
+    csrf: CsrfToken,
+    user: CurrentUser,
+    tmpl: web::Data<tera::Tera>,
     db: web::Data<Pool>,
     session: Session,
-) -> Result<HttpResponse> {
+) -> Result<HttpResponse, AppError> {
     let pool = db.clone();
 
-    if let Some(id) = id.identity() {
-        let res = web::block(move || {
-            let conn = pool.get().unwrap();
-            repo::check_permissions(conn, id)
-        }).await
-        .map_err(|err| {
-            return Ok(HttpResponse::InternalServerError().body(err.to_string()));
-        })
-        .map(|is_admin| {
-            session.set("is_admin", &is_admin)?;
-            return Ok(HttpResponse::Found().header("location", "/dashboard/options").finish());
-        });
-
-        session.set("article_focus", &-1)?;
-        match res {Ok(res) => res, Err(res) => res,}
+    let username = user.username.to_owned();
+    let (verified, two_factor) = web::block(move || {
+        let verified = repo::is_verified(pool.get()?, username.to_owned())?;
+        let two_factor = repo::get_two_factor(pool.get()?, username)?;
+        Ok::<_, AppError>((verified, two_factor))
+    }).await?;
+
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &true);
+    ctx.insert("can_manage_users", &user.can(Permission::ManageUsers));
+    ctx.insert("is_verified", &verified);
+    ctx.insert("two_factor_enabled", &two_factor.enabled);
+    ctx.insert("two_factor_required", &user.two_factor_required);
+    ctx.insert("recovery_codes", &two_factor.recovery_codes);
+
+    if let Some(message) = session.get::<String>("options_message")? {
+        ctx.insert("message", &message);
+        session.remove("options_message");
     } else {
-        return Ok(HttpResponse::Unauthorized().body("Unauthorized access"));
+        ctx.insert("message", "");
     }
+
+    let render = tmpl.render("dashboard_options.html", &ctx)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .body(render))
 }
 
-pub async fn dashboard_options(
-    id: Identity,
+
+/// Starts enrolling a new authenticator app.
+pub async fn two_factor_setup(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+    two_factor: web::Data<TwoFactor>,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    let pool = db.clone();
+
+    let sealed = two_factor.seal(&user.username, &totp::new_secret())?;
+    let username = user.username.to_owned();
+    web::block(move || repo::begin_two_factor(pool.get()?, username, sealed)).await?;
+
+    Ok(HttpResponse::Found().header("location", "/dashboard/two-factor").finish())
+}
+
+pub async fn two_factor_page(
+    user: CurrentUser,
+    csrf: CsrfToken,
     tmpl: web::Data<tera::Tera>,
     db: web::Data<Pool>,
+    two_factor: web::Data<TwoFactor>,
     session: Session,
-) -> Result<HttpResponse> {
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
     let pool = db.clone();
 
-    if let Some(_id) = id.identity() {
-        if let Some(is_admin) = session.get::<bool>("is_admin")? {
+    let username = user.username.to_owned();
+    let state = web::block(move || repo::get_two_factor(pool.get()?, username)).await?;
+    let sealed = match state.secret {
+        Some(sealed) if !state.enabled => sealed,
+        _ => return Ok(HttpResponse::Found().header("location", "/dashboard/options").finish()),
+    };
+
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &true);
+    ctx.insert("can_manage_users", &user.can(Permission::ManageUsers));
+    ctx.insert("enrollment", &two_factor.enrollment(&user.username, &sealed)?);
+
+    if let Some(fail) = session.get::<String>("two_factor_failure")? {
+        ctx.insert("failed", &fail);
+        session.remove("two_factor_failure");
+    } else {
+        ctx.insert("failed", "");
+    }
 
-            let res = web::block(move || {
-                let conn = pool.get().unwrap();
-                repo::get_users(conn)
-            }).await?;
+    let render = tmpl.render("two_factor.html", &ctx)?;
 
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .body(render))
+}
 
-            let mut ctx = tera::Context::new();
-            ctx.insert("is_loggedin", &true);
-            ctx.insert("is_admin", &is_admin);
-            ctx.insert("users", &res);
+/// Checks a code before a two-factor setting changes. Failures count against
+/// the login throttle, a stolen session can't guess its way through.
+async fn confirm_code(
+    user: &CurrentUser,
+    req: &HttpRequest,
+    db: &web::Data<Pool>,
+    two_factor: &web::Data<TwoFactor>,
+    throttle: &web::Data<LoginThrottle>,
+    code: String,
+) -> Result<bool, AppError> {
+    let ip = throttle.client_ip(req);
+    let locks = match throttle.attempt(ip, &user.username) {
+        Ok(locks) => locks,
+        Err(_) => return Ok(false),
+    };
+    if totp::check(two_factor, db, user.username.to_owned(), code).await?.is_some() {
+        throttle.release(ip, &user.username, locks);
+        return Ok(true);
+    }
+    log::warn!(target: "audit", "user '{}' entered a wrong two-factor code in the options", user.username);
+    Ok(false)
+}
 
-            let render = tmpl.render("dashboard_options.html", &ctx)
-            .map_err(|err| error::ErrorInternalServerError(format!("Template error: {:?}", err.to_string()))).expect("Test");
+/// Renders freshly generated recovery codes, the only time they are shown.
+fn recovery_codes_page(user: &CurrentUser, csrf: &CsrfToken, tmpl: &tera::Tera, codes: &[String]) -> Result<HttpResponse, AppError> {
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &true);
+    ctx.insert("can_manage_users", &user.can(Permission::ManageUsers));
+    ctx.insert("codes", codes);
 
-            return Ok(HttpResponse::build(StatusCode::OK)
-                .content_type("text/html; charset=utf-8")
-                .body(render));
-        } else {
-            return Ok(HttpResponse::Found().header("location", "/dashboard").finish());
-        }
+    let render = tmpl.render("recovery_codes.html", &ctx)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .header("cache-control", "no-store")
+        .body(render))
+}
+
+pub async fn two_factor_enable(
+    user: CurrentUser,
+    csrf: CsrfToken,
+    req: HttpRequest,
+    params: web::Form<CodeForm>,
+    tmpl: web::Data<tera::Tera>,
+    db: web::Data<Pool>,
+    cache: web::Data<PermissionCache>,
+    two_factor: web::Data<TwoFactor>,
+    throttle: web::Data<LoginThrottle>,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    if !confirm_code(&user, &req, &db, &two_factor, &throttle, params.code.to_owned()).await? {
+        session.set("two_factor_failure", "Invalid code, check the clock of your device")?;
+        return Ok(HttpResponse::Found().header("location", "/dashboard/two-factor").finish());
+    }
+
+    let pool = db.clone();
+    let codes = totp::new_recovery_codes();
+    let hashes = codes.iter().map(|code| totp::recovery_code_hash(code)).collect();
+    let username = user.username.to_owned();
+    web::block(move || repo::enable_two_factor(pool.get()?, username, hashes)).await?;
+    cache.clear();
+    log::info!(target: "audit", "user '{}' enabled two-factor authentication", user.username);
+
+    recovery_codes_page(&user, &csrf, &tmpl, &codes)
+}
+
+pub async fn two_factor_recovery_codes(
+    user: CurrentUser,
+    csrf: CsrfToken,
+    req: HttpRequest,
+    params: web::Form<CodeForm>,
+    tmpl: web::Data<tera::Tera>,
+    db: web::Data<Pool>,
+    two_factor: web::Data<TwoFactor>,
+    throttle: web::Data<LoginThrottle>,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    if !user.two_factor_enabled {
+        return Err(AppError::Conflict("Two-factor authentication is not enabled".to_string()));
+    }
+    if !confirm_code(&user, &req, &db, &two_factor, &throttle, params.code.to_owned()).await? {
+        session.set("options_message", "Invalid code")?;
+        return Ok(HttpResponse::Found().header("location", "/dashboard/options").finish());
+    }
+
+    let pool = db.clone();
+    let codes = totp::new_recovery_codes();
+    let hashes = codes.iter().map(|code| totp::recovery_code_hash(code)).collect();
+    let username = user.username.to_owned();
+    web::block(move || repo::replace_recovery_codes(pool.get()?, username, hashes)).await?;
+    log::info!(target: "audit", "user '{}' generated new recovery codes", user.username);
+
+    recovery_codes_page(&user, &csrf, &tmpl, &codes)
+}
+
+pub async fn two_factor_disable(
+    user: CurrentUser,
+    req: HttpRequest,
+    params: web::Form<CodeForm>,
+    db: web::Data<Pool>,
+    cache: web::Data<PermissionCache>,
+    two_factor: web::Data<TwoFactor>,
+    throttle: web::Data<LoginThrottle>,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    if user.two_factor_required {
+        return Err(AppError::Conflict("Your role requires two-factor authentication".to_string()));
+    }
+    if !confirm_code(&user, &req, &db, &two_factor, &throttle, params.code.to_owned()).await? {
+        session.set("options_message", "Invalid code")?;
+        return Ok(HttpResponse::Found().header("location", "/dashboard/options").finish());
+    }
+
+    let pool = db.clone();
+    let username = user.username.to_owned();
+    web::block(move || repo::disable_two_factor(pool.get()?, username)).await?;
+    cache.clear();
+    log::info!(target: "audit", "user '{}' disabled two-factor authentication", user.username);
+
+    session.set("options_message", "Two-factor authentication is disabled")?;
+    Ok(HttpResponse::Found().header("location", "/dashboard/options").finish())
+}
+
+/// Longest accepted API token name.
+const TOKEN_NAME_MAX: usize = 64;
+
+/// Renders the API tokens of `user`, with `created` shown once right after it was minted.
+async fn tokens_page(
+    user: &CurrentUser,
+    csrf: &CsrfToken,
+    tmpl: &tera::Tera,
+    db: &web::Data<Pool>,
+    session: &Session,
+    created: Option<String>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+
+    let username = user.username.to_owned();
+    let tokens = web::block(move || repo::get_api_tokens(pool.get()?, username)).await?;
+    let scopes: Vec<(&str, &str)> = Scope::ALL.iter().map(|scope| (scope.name(), scope.description())).collect();
+
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &true);
+    ctx.insert("can_manage_users", &user.can(Permission::ManageUsers));
+    ctx.insert("tokens", &tokens);
+    ctx.insert("scopes", &scopes);
+    ctx.insert("created", &created);
+    ctx.insert("now", &Utc::now().timestamp());
+
+    if let Some(fail) = session.get::<String>("token_failure")? {
+        ctx.insert("failed", &fail);
+        session.remove("token_failure");
     } else {
-        return Ok(HttpResponse::Unauthorized().body("Unauthorized access"));
+        ctx.insert("failed", "");
     }
+
+    let render = tmpl.render("dashboard_tokens.html", &ctx)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .header("cache-control", "no-store")
+        .body(render))
 }
 
+pub async fn dashboard_tokens(
+    user: CurrentUser,
+    csrf: CsrfToken,
+    tmpl: web::Data<tera::Tera>,
+    db: web::Data<Pool>,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    tokens_page(&user, &csrf, &tmpl, &db, &session, None).await
+}
 
-pub async fn dashboard_users(
-    id: Identity,
+pub async fn dashboard_token_create(
+    user: CurrentUser,
+    csrf: CsrfToken,
+    params: web::Form<ApiTokenForm>,
     tmpl: web::Data<tera::Tera>,
     db: web::Data<Pool>,
     session: Session,
-) -> Result<HttpResponse> {
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    let form = params.into_inner();
+
+    let name = form.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > TOKEN_NAME_MAX {
+        session.set("token_failure", format!("The name must be 1 to {} characters long", TOKEN_NAME_MAX))?;
+        return Ok(HttpResponse::Found().header("location", "/dashboard/tokens").finish());
+    }
+    let now = Utc::now();
+    let expires_at = match form.expires.as_str() {
+        "never" => None,
+        days => match days.parse::<i64>() {
+            Ok(days) if (1..=365).contains(&days) => Some((now + Duration::days(days)).timestamp()),
+            _ => {
+                session.set("token_failure", "Pick when the token expires")?;
+                return Ok(HttpResponse::Found().header("location", "/dashboard/tokens").finish());
+            }
+        },
+    };
+    let scopes: Vec<&str> = [
+        (form.articles_write, Scope::WriteArticles),
+        (form.articles_moderate, Scope::ModerateArticles),
+        (form.users_manage, Scope::ManageUsers),
+    ].iter()
+        .filter(|(granted, _)| *granted)
+        .map(|(_, scope)| scope.name())
+        .collect();
+    let scopes = scopes.join(" ");
+
     let pool = db.clone();
+    let secret = token::new_api_token();
+    let (hash, username, token_name, token_scopes) = (token::digest(&secret), user.username.to_owned(), name.to_owned(), scopes.to_owned());
+    web::block(move || repo::create_api_token(pool.get()?, username, token_name, hash, token_scopes, now.timestamp(), expires_at)).await?;
+    log::info!(target: "audit", "user '{}' created API token '{}' with scopes [{}]", user.username, name, scopes);
 
-    if let Some(_id) = id.identity() {
-        if let Some(is_admin) = session.get::<bool>("is_admin")? {
-            if !is_admin {return Ok(HttpResponse::Unauthorized().body("Unauthorized access"))};
+    tokens_page(&user, &csrf, &tmpl, &db, &session, Some(secret)).await
+}
 
-            let res = web::block(move || {
-                let conn = pool.get().unwrap();
-                repo::get_users(conn)
-            }).await?;
+pub async fn dashboard_token_revoke(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+    web::Path((tid,)): web::Path<(i64,)>,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    let pool = db.clone();
 
+    let username = user.username.to_owned();
+    web::block(move || repo::revoke_api_token(pool.get()?, username, tid)).await?;
+    log::info!(target: "audit", "user '{}' revoked API token {}", user.username, tid);
 
-            let mut ctx = tera::Context::new();
-            ctx.insert("is_loggedin", &true);
-            ctx.insert("is_admin", &true);
-            ctx.insert("users", &res);
+    Ok(HttpResponse::Found().header("location", "/dashboard/tokens").finish())
+}
 
-            if let Some(fail) = session.get::<String>("register_failure")? {
-                ctx.insert("failed", &fail);
-            } else {
-                ctx.insert("failed", "");
-            }
+/// Revokes every API token of the user, like a password reset does.
+pub async fn dashboard_token_revoke_all(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    let pool = db.clone();
 
-            let render = tmpl.render("dashboard_users.html", &ctx)
-            .map_err(|err| error::ErrorInternalServerError(format!("Template error: {:?}", err.to_string()))).expect("Test");
+    let username = user.username.to_owned();
+    let revoked = web::block(move || repo::revoke_api_tokens(pool.get()?, username)).await?;
+    log::info!(target: "audit", "user '{}' revoked all {} API token(s)", user.username, revoked);
 
-            return Ok(HttpResponse::build(StatusCode::OK)
-                .content_type("text/html; charset=utf-8")
-                .body(render));
-        } else {
-            return Ok(HttpResponse::Found().header("location", "/dashboard").finish());
-        }
+    Ok(HttpResponse::Found().header("location", "/dashboard/tokens").finish())
+}
+
+pub async fn dashboard_users(
+    csrf: CsrfToken,
+    user: CurrentUser,
+    tmpl: web::Data<tera::Tera>,
+    db: web::Data<Pool>,
+    throttle: web::Data<LoginThrottle>,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    user.require(Permission::ManageUsers)?;
+    let pool = db.clone();
+
+    let (users, roles) = web::block(move || {
+        let users = repo::get_users(pool.get()?)?;
+        let roles = repo::get_roles(pool.get()?)?;
+        Ok::<_, AppError>((users, roles))
+    }).await?;
+
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &true);
+    ctx.insert("can_manage_users", &true);
+    ctx.insert("users", &users);
+    ctx.insert("roles", &roles);
+    let locked: Vec<&str> = users.iter()
+        .map(|user| user.username.as_str())
+        .filter(|username| throttle.is_locked(username))
+        .collect();
+    ctx.insert("locked", &locked);
+
+    if let Some(fail) = session.get::<String>("register_failure")? {
+        ctx.insert("failed", &fail);
     } else {
-        return Ok(HttpResponse::Unauthorized().body("Unauthorized access"));
+        ctx.insert("failed", "");
     }
+
+    let render = tmpl.render("dashboard_users.html", &ctx)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .body(render))
 }
 
+/// Runs `action` on the user `uid`. Cached permissions are dropped afterwards,
+/// the change applies to the user's next request.
+pub async fn manage_user<F>(
+    user: &CurrentUser,
+    db: &web::Data<Pool>,
+    cache: &web::Data<PermissionCache>,
+    uid: i32,
+    description: String,
+    action: F,
+) -> Result<(), AppError>
+where
+    F: FnOnce(repo::Connection, i32) -> Result<(), AppError> + Send + 'static,
+{
+    user.require(Permission::ManageUsers)?;
+    let pool = db.clone();
+
+    web::block(move || {
+        let conn = pool.get()?;
+        action(conn, uid)
+    }).await?;
+    cache.clear();
+    log::info!(target: "audit", "user '{}' {} user {}", user.username, description, uid);
+
+    Ok(())
+}
+
+fn users_page() -> HttpResponse {
+    HttpResponse::Found().header("location", "/dashboard/users").finish()
+}
 
 pub async fn dashboard_user_del(
-    id: Identity,
+    user: CurrentUser,
     db: web::Data<Pool>,
-    session: Session,
+    cache: web::Data<PermissionCache>,
     web::Path((uid,)): web::Path<(i32,)>,
-) -> Result<HttpResponse> {
-    let pool = db.clone();
+) -> Result<HttpResponse, AppError> {
+    manage_user(&user, &db, &cache, uid, "deleted".to_string(), repo::del_user).await?;
+    Ok(users_page())
+}
+
+pub async fn dashboard_user_role(
+    user: CurrentUser,
+    params: web::Form<RoleForm>,
+    db: web::Data<Pool>,
+    cache: web::Data<PermissionCache>,
+    web::Path((uid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    let role = params.into_inner().role;
+    let description = format!("gave role '{}' to", role);
+    manage_user(&user, &db, &cache, uid, description, move |conn, uid| repo::set_role(conn, uid, role)).await?;
+    Ok(users_page())
+}
+
+pub async fn dashboard_user_unlock(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+    cache: web::Data<PermissionCache>,
+    throttle: web::Data<LoginThrottle>,
+    web::Path((uid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    manage_user(&user, &db, &cache, uid, "unlocked".to_string(), move |conn, uid| {
+        throttle.unlock(&repo::get_username(conn, uid)?);
+        Ok(())
+    }).await?;
+    Ok(users_page())
+}
+
+fn empty_article() -> Article {
+    Article{
+        id: -1,
+        owner: "noowner".to_string(),
+        title: "".to_string(),
+        description: "".to_string(),
+        hidden: false,
+        html: "".to_string(),
+        status: ArticleStatus::Draft,
+        publish_at: None,
+        version: 0,
+        tags: Vec::new(),
+        category: None,
+    }
+}
 
-    if let Some(_id) = id.identity() {
-        if let Some(is_admin) = session.get::<bool>("is_admin")? {
-            if !is_admin {return Ok(HttpResponse::Unauthorized().body("Unauthorized access"))};
+/// A status the focused article can be moved to, for the editor's buttons.
+#[derive(Serialize)]
+struct Transition {
+    status: ArticleStatus,
+    action: &'static str,
+}
 
-            web::block(move || {
-                let conn = pool.get().unwrap();
-                repo::del_user(conn, uid)
-            }).await?;
+impl Transition {
+    fn to(status: ArticleStatus) -> Transition {
+        let action = match status {
+            ArticleStatus::Draft => "Back to draft",
+            ArticleStatus::InReview => "Submit for review",
+            ArticleStatus::Published => "Publish",
+            ArticleStatus::Archived => "Archive",
+        };
+        Transition { status, action }
+    }
+}
+
+pub async fn dashboard_articles(
+    csrf: CsrfToken,
+    user: CurrentUser,
+    tmpl: web::Data<tera::Tera>,
+    db: web::Data<Pool>,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
 
-            return Ok(HttpResponse::Found().header("location", "/dashboard/users").finish());
+    // Editors, moderators and admins work on every article, everyone else on their own
+    let sees_all = authz::sees_all_articles(&user);
+    let username = user.username.to_owned();
+    let (res, categories) = web::block(move || {
+        let articles = if sees_all {
+            repo::get_all_articles(pool.get()?)?
         } else {
-            return Ok(HttpResponse::Found().header("location", "/dashboard").finish());
+            repo::get_articles(pool.get()?, username)?
+        };
+        Ok::<_, AppError>((articles, repo::get_categories(pool.get()?)?))
+    }).await?;
+
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &true);
+    ctx.insert("can_manage_users", &user.can(Permission::ManageUsers));
+    ctx.insert("username", &user.username);
+    ctx.insert("can_create", &user.can(Permission::CreateArticle));
+    ctx.insert("can_edit_own", &user.can(Permission::EditOwnArticle));
+    ctx.insert("can_edit_any", &user.can(Permission::EditAnyArticle));
+    ctx.insert("can_delete_own", &user.can(Permission::DeleteOwnArticle));
+    ctx.insert("can_delete_any", &user.can(Permission::DeleteAnyArticle));
+    ctx.insert("can_hide", &user.can(Permission::HideArticle));
+    ctx.insert("can_manage_categories", &user.can(Permission::ManageCategories));
+    ctx.insert("articles", &res);
+    ctx.insert("categories", &taxonomy::tree(categories));
+
+    match session.get::<i32>("article_focus")? {
+        Some(aid) if aid != -1 => {
+            let pool = db.clone();
+            let focus = web::block(move || {
+                let conn = pool.get()?;
+                repo::get_article(conn, aid)
+            }).await;
+            match focus {
+                Ok(focus) => {
+                    let transitions: Vec<Transition> = focus.status.next().iter()
+                        .filter(|status| authz::may_move(&user, &focus, **status))
+                        .map(|status| Transition::to(*status))
+                        .collect();
+                    ctx.insert("status_label", focus.status.label());
+                    ctx.insert("can_schedule", &may_schedule(&user, &focus));
+                    ctx.insert("transitions", &transitions);
+                    ctx.insert("focus", &focus);
+                }
+                // The focused article was deleted in the meantime
+                Err(_) => {
+                    session.set("article_focus", -1)?;
+                    ctx.insert("focus", &empty_article());
+                }
+            }
         }
+        _ => ctx.insert("focus", &empty_article()),
+    }
+
+    if let Some(fail) = session.get::<String>("create_article_failure")? {
+        ctx.insert("failed", &fail);
     } else {
-        return Ok(HttpResponse::Unauthorized().body("Unauthorized access"));
+        ctx.insert("failed", "");
     }
+
+    let render = tmpl.render("dashboard_articles.html", &ctx)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .body(render))
 }
 
+pub async fn dashboard_article_focus(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+    session: Session,
+    web::Path((uid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    if uid != -1 {
+        authz::require_article(&db, &user, uid, ArticleAction::Edit).await?;
+    }
+    session.set("article_focus", uid)?;
+
+    Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish())
+}
 
-pub async fn dashboard_user_promote(
-    id: Identity,
+pub async fn dashboard_article_post(
+    csrf: CsrfToken,
+    user: CurrentUser,
+    params: web::Form<CreateArticleForm>,
+    tmpl: web::Data<tera::Tera>,
     db: web::Data<Pool>,
     session: Session,
     web::Path((uid,)): web::Path<(i32,)>,
-) -> Result<HttpResponse> {
+) -> Result<HttpResponse, AppError> {
     let pool = db.clone();
+    let data = params.into_inner();
+    let mine = data.clone();
 
-    if let Some(_id) = id.identity() {
-        if let Some(is_admin) = session.get::<bool>("is_admin")? {
-            if !is_admin {return Ok(HttpResponse::Unauthorized().body("Unauthorized access"))};
+    if uid != -1 {
+        authz::require_article(&db, &user, uid, ArticleAction::Edit).await?;
+    } else {
+        user.require(Permission::CreateArticle)?;
+    }
+    let id = user.username.to_owned();
+    if uid == -1 && !authz::can_publish(&db, id.to_owned()).await? {
+        session.set("create_article_failure", "Verify your email address before publishing")?;
+        return Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish());
+    }
+    if let Err(err) = data.validate() {
+        session.set("create_article_failure", err.to_string())?;
+        return Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish());
+    }
 
-            web::block(move || {
-                let conn = pool.get().unwrap();
-                repo::promote_user(conn, uid)
-            }).await?;
+    let res = web::block(move || {
+        let conn = pool.get()?;
+        repo::post_article(conn, uid, id, data, Utc::now().timestamp())
+    }).await;
 
-            return Ok(HttpResponse::Found().header("location", "/dashboard/users").finish());
-        } else {
-            return Ok(HttpResponse::Found().header("location", "/dashboard").finish());
-        }
-    } else {
-        return Ok(HttpResponse::Unauthorized().body("Unauthorized access"));
+    match res.map_err(AppError::from) {
+        Ok(_) => session.set("create_article_failure", "")?,
+        Err(AppError::Conflict(_)) => return conflict_page(&csrf, &user, &tmpl, &db, uid, mine).await,
+        Err(err) => session.set("create_article_failure", err.to_string())?,
     }
+    Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish())
+}
+
+/// Answers an edit made to an outdated version of article `uid` with both versions
+/// side by side. The form keeps the rejected changes, saving it again overwrites
+/// the current version.
+async fn conflict_page(
+    csrf: &CsrfToken,
+    user: &CurrentUser,
+    tmpl: &web::Data<tera::Tera>,
+    db: &web::Data<Pool>,
+    uid: i32,
+    mine: CreateArticleForm,
+) -> Result<HttpResponse, AppError> {
+    log::info!(target: "audit", "user '{}' tried to save article {} over newer changes", user.username, uid);
+
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &true);
+    ctx.insert("can_manage_users", &user.can(Permission::ManageUsers));
+
+    let pool = db.clone();
+    let tmpl = tmpl.clone();
+    let render = web::block(move || {
+        let current = repo::get_article(pool.get()?, uid)?;
+        let saved_by = repo::get_latest_revision(pool.get()?, uid)?;
+
+        ctx.insert("changes", &diff::lines(&current.description, &mine.description));
+        ctx.insert("saved_by", &saved_by);
+        ctx.insert("current", &current);
+        ctx.insert("mine", &mine);
+        Ok::<_, AppError>(tmpl.render("dashboard_conflict.html", &ctx)?)
+    }).await?;
+
+    Ok(HttpResponse::build(StatusCode::CONFLICT)
+        .content_type("text/html; charset=utf-8")
+        .body(render))
 }
 
+/// Renders the editor's Markdown like it would be saved, for the live preview.
+pub async fn dashboard_article_preview(
+    _user: CurrentUser,
+    params: web::Form<PreviewForm>,
+) -> Result<HttpResponse, AppError> {
+    let html = web::block(move || Ok::<_, AppError>(markdown::render(&params.description))).await?;
 
-pub async fn dashboard_user_demote(
-    id: Identity,
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .body(html))
+}
+
+pub async fn dashboard_article_del(
+    user: CurrentUser,
     db: web::Data<Pool>,
-    session: Session,
     web::Path((uid,)): web::Path<(i32,)>,
-) -> Result<HttpResponse> {
+) -> Result<HttpResponse, AppError> {
     let pool = db.clone();
 
-    if let Some(_id) = id.identity() {
-        if let Some(is_admin) = session.get::<bool>("is_admin")? {
-            if !is_admin {return Ok(HttpResponse::Unauthorized().body("Unauthorized access"))};
+    authz::require_article(&db, &user, uid, ArticleAction::Delete).await?;
 
-            web::block(move || {
-                let conn = pool.get().unwrap();
-                repo::demote_user(conn, uid)
-            }).await?;
+    web::block(move || {
+        let conn = pool.get()?;
+        repo::del_article(conn, uid)
+    }).await?;
 
-            return Ok(HttpResponse::Found().header("location", "/dashboard/users").finish());
-        } else {
-            return Ok(HttpResponse::Found().header("location", "/dashboard").finish());
-        }
-    } else {
-        return Ok(HttpResponse::Unauthorized().body("Unauthorized access"));
+    Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish())
+}
+
+/// Moves article `aid` to status `to`. A move the workflow doesn't allow from the
+/// current status is a conflict, one the user isn't allowed to make is forbidden.
+pub async fn move_article(user: &CurrentUser, db: &web::Data<Pool>, aid: i32, to: ArticleStatus) -> Result<(), AppError> {
+    let pool = db.clone();
+    let article = web::block(move || repo::get_article(pool.get()?, aid)).await?;
+    let from = article.status;
+
+    if !from.next().contains(&to) {
+        return Err(AppError::Conflict(format!("Article '{}' can't go from {} to {}", aid, from, to)));
+    }
+    if !authz::may_move(user, &article, to) {
+        log::warn!(
+            target: "audit",
+            "denied: user '{}' ({}) tried to move article {} owned by '{}' from {} to {}",
+            user.username, user.role, aid, article.owner, from, to
+        );
+        return Err(AppError::Forbidden(format!("You are not allowed to move this article to {}", to.label().to_lowercase())));
     }
+
+    let pool = db.clone();
+    web::block(move || repo::set_status(pool.get()?, aid, from, to)).await?;
+    log::info!(target: "audit", "user '{}' moved article {} from {} to {}", user.username, aid, from, to);
+
+    Ok(())
 }
 
-pub async fn dashboard_articles(
-    id: Identity,
+pub async fn dashboard_article_status(
+    user: CurrentUser,
+    params: web::Form<StatusForm>,
+    db: web::Data<Pool>,
+    web::Path((uid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    move_article(&user, &db, uid, params.status).await?;
+    Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish())
+}
+
+/// A revision and what it changed since the one before it, for the history page.
+#[derive(Serialize)]
+struct RevisionChanges<'a> {
+    revision: &'a ArticleRevision,
+    /// The previous title, when the revision changed it
+    old_title: Option<&'a str>,
+    changes: Vec<diff::Change<'a>>,
+    /// Whether the text differs from the one before
+    changed: bool,
+}
+
+/// Lists the revisions of article `uid`, newest first, each with a line diff against the one before it.
+pub async fn dashboard_article_history(
+    csrf: CsrfToken,
+    user: CurrentUser,
     tmpl: web::Data<tera::Tera>,
     db: web::Data<Pool>,
-    session: Session,
-) -> Result<HttpResponse> {
-    let pool = db.clone();
+    web::Path((uid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    authz::require_article(&db, &user, uid, ArticleAction::Edit).await?;
 
-    if let Some(id) = id.identity() {
-        if let Some(is_admin) = session.get::<bool>("is_admin")? {
-            let res = web::block(move || {
-                let conn = pool.get().unwrap();
-                if is_admin {
-                    repo::get_all_articles(conn)
-                } else {
-                    repo::get_articles(conn, id)
-                }
-            }).await?;
-
-
-            let mut ctx = tera::Context::new();
-            ctx.insert("is_loggedin", &true);
-            ctx.insert("is_admin", &is_admin);
-            ctx.insert("articles", &res);
-
-            if let Some(aid) = session.get::<i32>("article_focus")? {
-                if aid == -1 {
-                    ctx.insert("focus", &Article{
-                        id: -1,
-                        owner: "noowner".to_string(),
-                        title: "".to_string(),
-                        description: "".to_string(),
-                    });
-                } else {
-                    let pool = db.clone();
-                    let res = web::block(move || {
-                        let conn = pool.get().unwrap();
-                        repo::get_article(conn, aid)
-                    }).await?;
-
-                    ctx.insert("focus", &res);
-                }
-            } else {
-                ctx.insert("focus", &Article{
-                    id: -1,
-                    owner: "noowner".to_string(),
-                    title: "".to_string(),
-                    description: "".to_string(),
-                });
-            }
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &true);
+    ctx.insert("can_manage_users", &user.can(Permission::ManageUsers));
 
-            if let Some(fail) = session.get::<String>("create_article_failure")? {
-                ctx.insert("failed", &fail);
-            } else {
-                ctx.insert("failed", "");
+    let pool = db.clone();
+    let render = web::block(move || {
+        let article = repo::get_article(pool.get()?, uid)?;
+        let revisions = repo::get_revisions(pool.get()?, uid)?;
+        let history: Vec<RevisionChanges> = revisions.iter().enumerate().map(|(index, revision)| {
+            let previous = revisions.get(index + 1);
+            let changes = diff::lines(previous.map_or("", |previous| previous.description.as_str()), &revision.description);
+            RevisionChanges {
+                revision,
+                old_title: previous.map(|previous| previous.title.as_str()).filter(|title| *title != revision.title),
+                changed: diff::changed(&changes),
+                changes,
             }
+        }).collect();
 
-            let render = tmpl.render("dashboard_articles.html", &ctx)
-            .map_err(|err| error::ErrorInternalServerError(format!("Template error: {:?}", err.to_string()))).expect("Test");
+        ctx.insert("article", &article);
+        ctx.insert("history", &history);
+        Ok::<_, AppError>(tmpl.render("dashboard_history.html", &ctx)?)
+    }).await?;
 
-            return Ok(HttpResponse::build(StatusCode::OK)
-                .content_type("text/html; charset=utf-8")
-                .body(render));
-        } else {
-            return Ok(HttpResponse::Found().header("location", "/dashboard").finish());
-        }
-    } else {
-        return Ok(HttpResponse::Unauthorized().body("Unauthorized access"));
-    }
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .body(render))
 }
 
-pub async fn dashboard_article_focus(
-    id: Identity,
+/// Saves the content of revision `rid` as a new revision of its article.
+pub async fn dashboard_article_restore(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+    web::Path((rid,)): web::Path<(i64,)>,
+) -> Result<HttpResponse, AppError> {
+    let pool = db.clone();
+    let revision = web::block(move || repo::get_revision(pool.get()?, rid)).await?;
+    let aid = revision.article_id;
+
+    authz::require_article(&db, &user, aid, ArticleAction::Edit).await?;
+
+    let pool = db.clone();
+    let author = user.username.to_owned();
+    web::block(move || repo::restore_revision(pool.get()?, rid, author, Utc::now().timestamp())).await?;
+    log::info!(target: "audit", "user '{}' restored article {} to revision {}", user.username, aid, rid);
+
+    Ok(HttpResponse::Found().header("location", format!("/dashboard/articles/history/{}", aid)).finish())
+}
+
+/// Drafts and articles in review can be scheduled by whoever may publish them.
+fn may_schedule(user: &CurrentUser, article: &Article) -> bool {
+    matches!(article.status, ArticleStatus::Draft | ArticleStatus::InReview)
+        && authz::may_move(user, article, ArticleStatus::Published)
+}
+
+/// Reads the value of a `datetime-local` input as UTC, browsers leave out the seconds by default.
+fn parse_publish_at(value: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S"))
+        .ok()
+        .map(|naive| DateTime::from_utc(naive, Utc))
+}
+
+/// Schedules article `uid` to be published at the picked time, an empty time unschedules it.
+pub async fn dashboard_article_schedule(
+    user: CurrentUser,
+    params: web::Form<ScheduleForm>,
     db: web::Data<Pool>,
     session: Session,
     web::Path((uid,)): web::Path<(i32,)>,
-) -> Result<HttpResponse> {
+) -> Result<HttpResponse, AppError> {
     let pool = db.clone();
+    let article = web::block(move || repo::get_article(pool.get()?, uid)).await?;
 
-    if let Some(_id) = id.identity() {
-        if let Some(_is_admin) = session.get::<bool>("is_admin")? {
-            if uid != -1 {
-                let res = web::block(move || {
-                    let conn = pool.get().unwrap();
-                    repo::get_article(conn, uid)
-                }).await?;
-                session.set("article_focus", &res.id)?;
-            } else {
-                session.set("article_focus", &-1)?;
+    if !matches!(article.status, ArticleStatus::Draft | ArticleStatus::InReview) {
+        return Err(AppError::Conflict(format!("Article '{}' is {}, only drafts and articles in review can be scheduled", uid, article.status)));
+    }
+    if !may_schedule(&user, &article) {
+        log::warn!(
+            target: "audit",
+            "denied: user '{}' ({}) tried to schedule article {} owned by '{}'",
+            user.username, user.role, uid, article.owner
+        );
+        return Err(AppError::Forbidden("You are not allowed to publish this article".to_string()));
+    }
+
+    let publish_at = match params.publish_at.trim() {
+        "" => None,
+        value => match parse_publish_at(value) {
+            Some(at) if at > Utc::now() => Some(at),
+            Some(_) => {
+                session.set("create_article_failure", "Pick a time in the future")?;
+                return Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish());
+            }
+            None => {
+                session.set("create_article_failure", "Pick a date and time to publish at")?;
+                return Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish());
             }
+        },
+    };
 
-            return Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish());
-        } else {
-            return Ok(HttpResponse::Found().header("location", "/dashboard").finish());
-        }
-    } else {
-        return Ok(HttpResponse::Unauthorized().body("Unauthorized access"));
+    let pool = db.clone();
+    web::block(move || repo::set_publish_at(pool.get()?, uid, publish_at.map(|at| at.timestamp()))).await?;
+    match publish_at {
+        Some(at) => log::info!(target: "audit", "user '{}' scheduled article {} for {}", user.username, uid, at.to_rfc3339()),
+        None => log::info!(target: "audit", "user '{}' unscheduled article {}", user.username, uid),
     }
+    session.set("create_article_failure", "")?;
+
+    Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish())
 }
 
-pub async fn dashboard_article_post(
-    id: Identity,
-    params: web::Form<CreateArticleForm>,
+pub async fn dashboard_article_visibility(
+    user: CurrentUser,
+    params: web::Form<VisibilityForm>,
     db: web::Data<Pool>,
-    session: Session,
     web::Path((uid,)): web::Path<(i32,)>,
-) -> HttpResponse {
+) -> Result<HttpResponse, AppError> {
     let pool = db.clone();
-    let data = params.clone();
-
-    if let Some(id) = id.identity() {
-        let _res = web::block(move || {
-            let conn = pool.get().unwrap();
-            let user_data = Article{
-                id: uid,
-                owner: id,
-                title: data.title,
-                description: data.description,
-            };
-            repo::post_article(conn, user_data)
-        }).await
-        .map_err(|err| {
-            session.set("register_failure", &err.to_string()).unwrap();
-            return HttpResponse::Found().header("location", "/dashboard/articles").finish();
-        })
-        .map(|_| {
-            session.set("register_failure", "").unwrap();
-            return HttpResponse::Found().header("location", "/dashboard/articles").finish();
-        });
-        return HttpResponse::Found().header("location", "/dashboard/articles").finish();
+    let hidden = params.hidden;
+
+    authz::require_article(&db, &user, uid, ArticleAction::Hide).await?;
+
+    web::block(move || {
+        let conn = pool.get()?;
+        repo::set_hidden(conn, uid, hidden)
+    }).await?;
+    log::info!(target: "audit", "user '{}' {} article {}", user.username, if hidden { "hid" } else { "unhid" }, uid);
+
+    Ok(HttpResponse::Found().header("location", "/dashboard/articles").finish())
+}
+
+/// Longest accepted category name.
+const CATEGORY_NAME_MAX: usize = 100;
+
+pub async fn dashboard_categories(
+    user: CurrentUser,
+    csrf: CsrfToken,
+    tmpl: web::Data<tera::Tera>,
+    db: web::Data<Pool>,
+    session: Session,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    user.require(Permission::ManageCategories)?;
+    let pool = db.clone();
+
+    let categories = web::block(move || repo::get_categories(pool.get()?)).await?;
+
+    let mut ctx = csrf.context();
+    ctx.insert("is_loggedin", &true);
+    ctx.insert("can_manage_users", &user.can(Permission::ManageUsers));
+    ctx.insert("categories", &taxonomy::tree(categories));
+
+    if let Some(fail) = session.get::<String>("category_failure")? {
+        ctx.insert("failed", &fail);
+        session.remove("category_failure");
     } else {
-        return HttpResponse::Unauthorized().body("Unauthorized access");
+        ctx.insert("failed", "");
     }
+
+    let render = tmpl.render("dashboard_categories.html", &ctx)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/html; charset=utf-8")
+        .body(render))
 }
 
-pub async fn dashboard_article_del(
-    id: Identity,
+pub async fn dashboard_category_create(
+    user: CurrentUser,
+    params: web::Form<CategoryForm>,
     db: web::Data<Pool>,
     session: Session,
-    web::Path((uid,)): web::Path<(i32,)>,
-) -> HttpResponse {
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    user.require(Permission::ManageCategories)?;
+    let form = params.into_inner();
+
+    let name = form.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > CATEGORY_NAME_MAX {
+        session.set("category_failure", format!("The name must be 1 to {} characters long", CATEGORY_NAME_MAX))?;
+        return Ok(HttpResponse::Found().header("location", "/dashboard/categories").finish());
+    }
+    let slug = taxonomy::slugify(if form.slug.trim().is_empty() { &name } else { &form.slug });
+    if slug.is_empty() {
+        session.set("category_failure", "The slug needs at least one letter or digit")?;
+        return Ok(HttpResponse::Found().header("location", "/dashboard/categories").finish());
+    }
+    let parent = match form.parent.as_str() {
+        "" => None,
+        parent => match parent.parse::<i32>() {
+            Ok(parent) => Some(parent),
+            Err(_) => {
+                session.set("category_failure", "Pick a parent category")?;
+                return Ok(HttpResponse::Found().header("location", "/dashboard/categories").finish());
+            }
+        },
+    };
+
     let pool = db.clone();
+    let (category_name, category_slug) = (name.to_owned(), slug.to_owned());
+    match web::block(move || repo::create_category(pool.get()?, category_name, category_slug, parent)).await {
+        Ok(()) => {
+            log::info!(target: "audit", "user '{}' created category '{}'", user.username, slug);
+        }
+        Err(err) => session.set("category_failure", AppError::from(err).to_string())?,
+    }
 
-    if let Some(_id) = id.identity() {
-        let _res = web::block(move || {
-            let conn = pool.get().unwrap();
-            repo::del_article(conn, uid)
-        }).await
-        .map_err(|err| {
-            session.set("register_failure", &err.to_string()).unwrap();
-            return HttpResponse::Found().header("location", "/dashboard/articles").finish();
-        })
-        .map(|_| {
-            session.set("register_failure", "").unwrap();
-            return HttpResponse::Found().header("location", "/dashboard/articles").finish();
-        });
-        return HttpResponse::Found().header("location", "/dashboard/articles").finish();
-    } else {
-        return HttpResponse::Unauthorized().body("Unauthorized access");
+    Ok(HttpResponse::Found().header("location", "/dashboard/categories").finish())
+}
+
+pub async fn dashboard_category_del(
+    user: CurrentUser,
+    db: web::Data<Pool>,
+    session: Session,
+    web::Path((cid,)): web::Path<(i32,)>,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    user.require(Permission::ManageCategories)?;
+    let pool = db.clone();
+
+    match web::block(move || repo::del_category(pool.get()?, cid)).await {
+        Ok(()) => {
+            log::info!(target: "audit", "user '{}' deleted category {}", user.username, cid);
+        }
+        Err(err) => session.set("category_failure", AppError::from(err).to_string())?,
     }
+
+    Ok(HttpResponse::Found().header("location", "/dashboard/categories").finish())
 }
@@ -0,0 +1,172 @@
+//! Publishes scheduled articles once their `publish_at` has passed.
+//!
+//! The scheduler runs inside the server process, checking every
+//! `scheduler.interval_secs` seconds. Time comes from a `Clock` so the tests
+//! can move it forward themselves.
+
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use crate::error::AppError;
+use crate::repo::{self, Pool};
+
+/// Where the scheduler reads the current time from.
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system's wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+pub struct Scheduler<C: Clock = SystemClock> {
+    pool: Pool,
+    clock: C,
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn new(pool: Pool, clock: C) -> Self {
+        Scheduler { pool, clock }
+    }
+
+    /// Publishes every article that is due. Returns their ids.
+    pub fn tick(&self) -> Result<Vec<i32>, AppError> {
+        let published = repo::publish_due(self.pool.get()?, self.clock.now().timestamp())?;
+        for id in &published {
+            log::info!(target: "audit", "scheduler published article {}", id);
+        }
+        Ok(published)
+    }
+
+    /// Runs `tick` every `interval` on the current runtime, the first time right away.
+    /// Failures are logged and retried on the next tick.
+    pub fn start(self, interval: Duration) {
+        let scheduler = Arc::new(self);
+        actix_rt::spawn(async move {
+            let mut ticks = actix_rt::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let scheduler = scheduler.clone();
+                if let Err(err) = web::block(move || scheduler.tick()).await {
+                    log::error!("Publishing scheduled articles failed: {}", AppError::from(err).detail());
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use chrono::Duration;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use crate::migrate;
+    use crate::models::{ArticleStatus, CreateArticleForm};
+    use super::*;
+
+    /// A clock that only moves when told to.
+    struct TestClock(Mutex<DateTime<Utc>>);
+
+    impl TestClock {
+        fn advance(&self, by: Duration) {
+            let mut now = self.0.lock().unwrap();
+            *now = *now + by;
+        }
+    }
+
+    impl Clock for Arc<TestClock> {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn setup() -> (Pool, Arc<TestClock>) {
+        // One connection, every connection to `:memory:` is a database of its own
+        let pool = Pool::builder().max_size(1).build(SqliteConnectionManager::memory()).unwrap();
+        migrate::run(&mut pool.get().unwrap()).unwrap();
+        let start = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap().with_timezone(&Utc);
+        (pool, Arc::new(TestClock(Mutex::new(start))))
+    }
+
+    fn draft(pool: &Pool, title: &str) -> i32 {
+        let data = CreateArticleForm {
+            title: title.to_string(),
+            description: "text".to_string(),
+            version: None,
+            tags: None,
+            category: None,
+        };
+        repo::post_article(pool.get().unwrap(), -1, "root".to_string(), data, 0).unwrap()
+    }
+
+    fn status(pool: &Pool, id: i32) -> ArticleStatus {
+        repo::get_article(pool.get().unwrap(), id).unwrap().status
+    }
+
+    #[test]
+    fn publishes_drafts_when_due() {
+        let (pool, clock) = setup();
+        let scheduler = Scheduler::new(pool.clone(), clock.clone());
+        let now = clock.now().timestamp();
+        let soon = draft(&pool, "soon");
+        let later = draft(&pool, "later");
+        let unscheduled = draft(&pool, "unscheduled");
+        repo::set_publish_at(pool.get().unwrap(), soon, Some(now + 60)).unwrap();
+        repo::set_publish_at(pool.get().unwrap(), later, Some(now + 3600)).unwrap();
+
+        assert!(scheduler.tick().unwrap().is_empty());
+        assert_eq!(status(&pool, soon), ArticleStatus::Draft);
+
+        clock.advance(Duration::seconds(59));
+        assert!(scheduler.tick().unwrap().is_empty());
+
+        clock.advance(Duration::seconds(1));
+        assert_eq!(scheduler.tick().unwrap(), vec![soon]);
+        let article = repo::get_article(pool.get().unwrap(), soon).unwrap();
+        assert_eq!(article.status, ArticleStatus::Published);
+        assert_eq!(article.publish_at, None);
+        assert_eq!(status(&pool, later), ArticleStatus::Draft);
+
+        // Already published, nothing left to do until the next one is due
+        assert!(scheduler.tick().unwrap().is_empty());
+
+        clock.advance(Duration::hours(2));
+        assert_eq!(scheduler.tick().unwrap(), vec![later]);
+        assert_eq!(status(&pool, unscheduled), ArticleStatus::Draft);
+    }
+
+    #[test]
+    fn publishes_overdue_articles_in_order() {
+        let (pool, clock) = setup();
+        let scheduler = Scheduler::new(pool.clone(), clock.clone());
+        let now = clock.now().timestamp();
+        let second = draft(&pool, "second");
+        let first = draft(&pool, "first");
+        repo::set_publish_at(pool.get().unwrap(), second, Some(now - 10)).unwrap();
+        repo::set_publish_at(pool.get().unwrap(), first, Some(now - 20)).unwrap();
+        repo::set_status(pool.get().unwrap(), second, ArticleStatus::Draft, ArticleStatus::InReview).unwrap();
+
+        assert_eq!(scheduler.tick().unwrap(), vec![first, second]);
+        assert_eq!(status(&pool, second), ArticleStatus::Published);
+    }
+
+    #[test]
+    fn leaves_archived_articles_alone() {
+        let (pool, clock) = setup();
+        let scheduler = Scheduler::new(pool.clone(), clock.clone());
+        let id = draft(&pool, "archived");
+        repo::set_status(pool.get().unwrap(), id, ArticleStatus::Draft, ArticleStatus::Published).unwrap();
+        repo::set_status(pool.get().unwrap(), id, ArticleStatus::Published, ArticleStatus::Archived).unwrap();
+        repo::set_publish_at(pool.get().unwrap(), id, Some(clock.now().timestamp())).unwrap();
+
+        clock.advance(Duration::days(1));
+        assert!(scheduler.tick().unwrap().is_empty());
+        assert_eq!(status(&pool, id), ArticleStatus::Archived);
+    }
+}
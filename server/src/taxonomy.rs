@@ -0,0 +1,166 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::error::AppError;
+use crate::models::Category;
+
+/// Most tags an article can have.
+pub const TAGS_MAX: usize = 10;
+/// Longest accepted tag, in characters.
+pub const TAG_MAX: usize = 32;
+
+/// Lower cases `tag` and trims it. Tags are stored and compared in this form.
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+/// Tags are letters, digits and `-+#.`, so they fit in urls and space separated lists.
+pub fn validate_tags(tags: &[String]) -> Result<(), AppError> {
+    if tags.len() > TAGS_MAX {
+        return Err(AppError::Validation(format!("An article can't have more than {} tags", TAGS_MAX)));
+    }
+    for tag in tags {
+        if tag.chars().count() > TAG_MAX {
+            return Err(AppError::Validation(format!("The tag '{}' is longer than {} characters", tag, TAG_MAX)));
+        }
+        if !tag.chars().all(|c| c.is_alphanumeric() || "-+#.".contains(c)) {
+            return Err(AppError::Validation(format!("The tag '{}' may only contain letters, digits, '-', '+', '#' and '.'", tag)));
+        }
+    }
+    Ok(())
+}
+
+/// Splits a comma separated list into normalized tags, dropping empty entries and repeats.
+pub fn parse_tags(list: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in list.split(',').map(normalize_tag) {
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+/// Reads tags from a comma separated string, as sent by the editor, or from a list,
+/// as sent to the API.
+pub fn deserialize_tags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Tags {
+        List(Vec<String>),
+        Text(String),
+    }
+
+    Ok(Option::<Tags>::deserialize(deserializer)?.map(|tags| match tags {
+        Tags::List(list) => parse_tags(&list.join(",")),
+        Tags::Text(text) => parse_tags(&text),
+    }))
+}
+
+/// Url friendly form of a category name, `Rust & WebAssembly` becomes `rust-webassembly`.
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.trim().chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// A category and how deep it is in the hierarchy, 0 for top level categories.
+#[derive(Debug, Serialize)]
+pub struct CategoryNode {
+    #[serde(flatten)]
+    pub category: Category,
+    pub depth: usize,
+}
+
+/// Appends the not yet taken children of `parent` and their own children to `order`.
+fn visit(categories: &[Category], parent: Option<i32>, depth: usize, taken: &mut [bool], order: &mut Vec<(usize, usize)>) {
+    for (index, category) in categories.iter().enumerate() {
+        if category.parent_id == parent && !taken[index] {
+            taken[index] = true;
+            order.push((index, depth));
+            visit(categories, Some(category.id), depth + 1, taken, order);
+        }
+    }
+}
+
+/// Orders `categories` depth first, every category followed by its children,
+/// siblings by name.
+pub fn tree(mut categories: Vec<Category>) -> Vec<CategoryNode> {
+    categories.sort_by_key(|category| category.name.to_lowercase());
+    let mut taken = vec![false; categories.len()];
+    let mut order = Vec::with_capacity(categories.len());
+    visit(&categories, None, 0, &mut taken, &mut order);
+    // Categories whose parent is missing are shown at the top level rather than lost
+    while let Some(orphan) = taken.iter().position(|taken| !taken) {
+        taken[orphan] = true;
+        order.push((orphan, 0));
+        visit(&categories, Some(categories[orphan].id), 1, &mut taken, &mut order);
+    }
+
+    let mut categories: Vec<Option<Category>> = categories.into_iter().map(Some).collect();
+    order.into_iter()
+        .filter_map(|(index, depth)| categories[index].take().map(|category| CategoryNode { category, depth }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn category(id: i32, parent_id: Option<i32>, name: &str) -> Category {
+        Category { id, parent_id, name: name.to_string(), slug: slugify(name) }
+    }
+
+    #[test]
+    fn parses_tag_lists() {
+        assert_eq!(parse_tags(" Rust, web ,,rust, C++ "), vec!["rust", "web", "c++"]);
+        assert!(parse_tags(" , ").is_empty());
+        assert!(validate_tags(&parse_tags("rust, c#, node.js, x-y")).is_ok());
+        assert!(validate_tags(&parse_tags("two words")).is_err());
+        assert!(validate_tags(&parse_tags("a/b")).is_err());
+        assert!(validate_tags(&parse_tags(&"x".repeat(TAG_MAX + 1))).is_err());
+        let many: Vec<String> = (0..=TAGS_MAX).map(|n| n.to_string()).collect();
+        assert!(validate_tags(&many).is_err());
+    }
+
+    #[test]
+    fn reads_tags_from_forms_and_json() {
+        #[derive(Deserialize)]
+        struct Form {
+            #[serde(default, deserialize_with = "deserialize_tags")]
+            tags: Option<Vec<String>>,
+        }
+
+        let form: Form = serde_urlencoded::from_str("tags=Rust%2C+Web").unwrap();
+        assert_eq!(form.tags.unwrap(), vec!["rust", "web"]);
+        let form: Form = serde_json::from_str(r#"{"tags": ["Rust", " web", "rust"]}"#).unwrap();
+        assert_eq!(form.tags.unwrap(), vec!["rust", "web"]);
+        let form: Form = serde_json::from_str("{}").unwrap();
+        assert!(form.tags.is_none());
+    }
+
+    #[test]
+    fn slugifies_names() {
+        assert_eq!(slugify("Rust & WebAssembly"), "rust-webassembly");
+        assert_eq!(slugify("  Žinios  "), "žinios");
+        assert_eq!(slugify("--"), "");
+    }
+
+    #[test]
+    fn orders_categories_depth_first() {
+        let nodes = tree(vec![
+            category(1, None, "Programming"),
+            category(2, Some(1), "Rust"),
+            category(3, None, "News"),
+            category(4, Some(1), "Go"),
+            category(5, Some(2), "Async"),
+            category(6, Some(99), "Lost"),
+        ]);
+        let order: Vec<(&str, usize)> = nodes.iter().map(|node| (node.category.name.as_str(), node.depth)).collect();
+        assert_eq!(order, vec![("News", 0), ("Programming", 0), ("Go", 1), ("Rust", 1), ("Async", 2), ("Lost", 0)]);
+    }
+}
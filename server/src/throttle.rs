@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_web::HttpRequest;
+use crate::config::LoginConfig;
+
+/// What failed logins are counted against. Accounts are keyed by the name
+/// that was typed, so unknown usernames are throttled like real ones.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    Account(String),
+}
+
+struct Entry {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failed login tracking per client address and per account.
+///
+/// Every attempt counts as a failure until it succeeds. Every failure doubles
+/// the wait before the next attempt, up to `backoff_max_secs`. Accounts are
+/// locked for `lockout_secs` after `max_failures` failures in a row, client
+/// addresses only back off. Kept in memory, a restart lifts every lock.
+pub struct LoginThrottle {
+    config: LoginConfig,
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: LoginConfig) -> Self {
+        LoginThrottle {
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Address of the client, from `X-Forwarded-For` when `trust_proxy` is set.
+    pub fn client_ip(&self, req: &HttpRequest) -> IpAddr {
+        let forwarded = if self.config.trust_proxy {
+            req.connection_info().realip_remote_addr().and_then(|addr| {
+                addr.parse::<IpAddr>().ok().or_else(|| addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+            })
+        } else {
+            None
+        };
+        forwarded
+            .or_else(|| req.peer_addr().map(|addr| addr.ip()))
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+
+    fn backoff(&self, failures: u32) -> Duration {
+        let base = Duration::from_millis(self.config.backoff_base_ms);
+        let max = Duration::from_secs(self.config.backoff_max_secs);
+        base.checked_mul(1 << failures.saturating_sub(1).min(31))
+            .map_or(max, |delay| delay.min(max))
+    }
+
+    fn wait(&self, entry: &Entry, now: Instant) -> Option<Duration> {
+        if let Some(until) = entry.locked_until.filter(|until| *until > now) {
+            return Some(until - now);
+        }
+        let allowed_at = entry.last_failure + self.backoff(entry.failures);
+        (allowed_at > now).then(|| allowed_at - now)
+    }
+
+    /// Reserves an attempt to log in as `username`. It is counted as a failure right away,
+    /// so guesses sent in parallel are throttled before any of them is checked, `success`
+    /// takes it back. Returns how long the client has to wait when it may not try now,
+    /// otherwise whether the attempt locked the account, in case it fails.
+    pub fn attempt(&self, ip: IpAddr, username: &str) -> Result<bool, Duration> {
+        self.attempt_at(ip, username, Instant::now())
+    }
+
+    fn attempt_at(&self, ip: IpAddr, username: &str, now: Instant) -> Result<bool, Duration> {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            // Fails closed, the counts can't be trusted anymore
+            Err(_) => {
+                log::error!("Login throttle state is poisoned, refusing login attempts");
+                return Err(Duration::from_secs(self.config.backoff_max_secs));
+            }
+        };
+        let keys = [Key::Ip(ip), Key::Account(username.to_string())];
+        let wait = keys.iter()
+            .filter_map(|key| entries.get(key))
+            .filter_map(|entry| self.wait(entry, now))
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+
+        let forget_after = Duration::from_secs(self.config.lockout_secs.max(self.config.backoff_max_secs));
+        entries.retain(|_, entry| {
+            now.duration_since(entry.last_failure) < forget_after
+                || entry.locked_until.is_some_and(|until| until > now)
+        });
+
+        let mut locked = false;
+        for key in keys {
+            let is_account = matches!(key, Key::Account(_));
+            let entry = entries.entry(key).or_insert(Entry {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            entry.failures += 1;
+            entry.last_failure = now;
+            if is_account && entry.failures >= self.config.max_failures {
+                entry.failures = 0;
+                entry.locked_until = Some(now + Duration::from_secs(self.config.lockout_secs));
+                locked = true;
+            }
+        }
+        Ok(locked)
+    }
+
+    /// Takes back an attempt that turned out to be neither a failure nor a complete login,
+    /// a right password that still needs its second factor. `locked` is what `attempt` returned.
+    pub fn release(&self, ip: IpAddr, username: &str, locked: bool) {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for key in [Key::Ip(ip), Key::Account(username.to_string())] {
+            let is_account = matches!(key, Key::Account(_));
+            if let Some(entry) = entries.get_mut(&key) {
+                if is_account && locked {
+                    entry.locked_until = None;
+                    entry.failures = self.config.max_failures.saturating_sub(1);
+                } else {
+                    entry.failures = entry.failures.saturating_sub(1);
+                }
+                if entry.failures == 0 && entry.locked_until.is_none() {
+                    entries.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Forgets the failures of the client and the account after a successful login.
+    pub fn success(&self, ip: IpAddr, username: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.remove(&Key::Ip(ip));
+            entries.remove(&Key::Account(username.to_string()));
+        }
+    }
+
+    pub fn is_locked(&self, username: &str) -> bool {
+        self.is_locked_at(username, Instant::now())
+    }
+
+    fn is_locked_at(&self, username: &str, now: Instant) -> bool {
+        self.entries.lock().ok()
+            .and_then(|entries| entries.get(&Key::Account(username.to_string()))
+                .and_then(|entry| entry.locked_until)
+                .map(|until| until > now))
+            .unwrap_or(false)
+    }
+
+    /// Lifts the lock and the backoff of an account.
+    pub fn unlock(&self, username: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.remove(&Key::Account(username.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(LoginConfig {
+            max_failures: 3,
+            lockout_secs: 900,
+            backoff_base_ms: 1000,
+            backoff_max_secs: 60,
+            ..LoginConfig::default()
+        })
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    #[test]
+    fn doubles_the_backoff() {
+        let throttle = throttle();
+        let delays: Vec<Duration> = (1..=9).map(|failures| throttle.backoff(failures)).collect();
+        assert_eq!(delays[0], Duration::from_secs(1));
+        assert_eq!(delays[1], Duration::from_secs(2));
+        assert_eq!(delays[5], Duration::from_secs(32));
+        assert_eq!(delays[6], Duration::from_secs(60));
+        assert_eq!(throttle.backoff(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn throttles_parallel_attempts() {
+        let throttle = throttle();
+        let now = Instant::now();
+        assert_eq!(throttle.attempt_at(ip(1), "alice", now), Ok(false));
+        // A second guess before the first one was checked
+        assert_eq!(throttle.attempt_at(ip(1), "alice", now), Err(Duration::from_secs(1)));
+        assert_eq!(throttle.attempt_at(ip(2), "alice", now), Err(Duration::from_secs(1)));
+        assert_eq!(throttle.attempt_at(ip(1), "bob", now + Duration::from_millis(400)), Err(Duration::from_millis(600)));
+        assert_eq!(throttle.attempt_at(ip(2), "bob", now), Ok(false));
+
+        throttle.success(ip(1), "alice");
+        assert_eq!(throttle.attempt_at(ip(1), "alice", now), Ok(false));
+        throttle.release(ip(1), "alice", false);
+        assert_eq!(throttle.attempt_at(ip(1), "alice", now), Ok(false));
+    }
+
+    #[test]
+    fn released_attempts_keep_earlier_failures() {
+        let throttle = throttle();
+        let mut now = Instant::now();
+        assert_eq!(throttle.attempt_at(ip(1), "alice", now), Ok(false));
+        now += Duration::from_secs(1);
+        assert_eq!(throttle.attempt_at(ip(1), "alice", now), Ok(false));
+        now += Duration::from_secs(2);
+        assert_eq!(throttle.attempt_at(ip(1), "alice", now), Ok(true));
+        // The password was right, the lock of this attempt is lifted but the failures before it count
+        throttle.release(ip(1), "alice", true);
+        assert!(!throttle.is_locked_at("alice", now));
+        now += Duration::from_secs(4);
+        assert_eq!(throttle.attempt_at(ip(1), "alice", now), Ok(true));
+    }
+
+    #[test]
+    fn locks_accounts_after_repeated_failures() {
+        let throttle = throttle();
+        let mut now = Instant::now();
+        assert_eq!(throttle.attempt_at(ip(1), "alice", now), Ok(false));
+        now += Duration::from_millis(500);
+        assert_eq!(throttle.attempt_at(ip(2), "alice", now), Err(Duration::from_millis(500)));
+        now += Duration::from_millis(500);
+        assert_eq!(throttle.attempt_at(ip(2), "alice", now), Ok(false));
+        now += Duration::from_secs(1);
+        assert_eq!(throttle.attempt_at(ip(3), "alice", now), Err(Duration::from_secs(1)));
+        now += Duration::from_secs(1);
+        assert_eq!(throttle.attempt_at(ip(3), "alice", now), Ok(true));
+        assert!(throttle.is_locked_at("alice", now));
+
+        // Another address doesn't help, the account waits out the lockout
+        let later = now + Duration::from_secs(899);
+        assert_eq!(throttle.attempt_at(ip(4), "alice", later), Err(Duration::from_secs(1)));
+        let after = now + Duration::from_secs(900);
+        assert!(!throttle.is_locked_at("alice", after));
+        assert_eq!(throttle.attempt_at(ip(4), "alice", after), Ok(false));
+
+        throttle.unlock("alice");
+        assert!(!throttle.is_locked_at("alice", after));
+    }
+
+    #[test]
+    fn fails_closed_when_poisoned() {
+        let throttle = throttle();
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _entries = throttle.entries.lock().unwrap();
+            panic!("poison the lock");
+        }));
+        assert_eq!(throttle.attempt(ip(1), "alice"), Err(Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,110 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use crate::keys::{self, KeyRing};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs short payloads so they can be handed out in links without being tampered with.
+#[derive(Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    pub fn new(key: Vec<u8>) -> Self {
+        Signer { key }
+    }
+
+    /// Uses `secret` as the key, or a key derived from the cookie keys. Links
+    /// signed with a derived key stop working when the cookie key is rotated.
+    pub fn from_secret(secret: Option<&str>, ring: &KeyRing) -> Signer {
+        match secret {
+            Some(secret) => Signer::new(secret.as_bytes().to_vec()),
+            None => {
+                if !ring.is_persistent() {
+                    log::warn!("No token secret or cookie key is configured, emailed links will not survive a restart");
+                }
+                Signer::new(ring.subkey(keys::LINKS))
+            }
+        }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any size")
+    }
+
+    /// Returns `payload.signature`, both parts url safe base64.
+    pub fn sign(&self, payload: &str) -> String {
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        format!(
+            "{}.{}",
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD),
+        )
+    }
+
+    /// Returns the payload of a token signed with this key.
+    pub fn verify(&self, token: &str) -> Option<String> {
+        let (payload, signature) = token.split_once('.')?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+
+        let mut mac = self.mac();
+        mac.update(&payload);
+        mac.verify_slice(&signature).ok()?;
+        String::from_utf8(payload).ok()
+    }
+}
+
+/// Random url safe string with 256 bits of entropy.
+pub fn random_nonce() -> String {
+    base64::encode_config(rand::thread_rng().gen::<[u8; 32]>(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Prefix of personal API tokens, so they are recognisable when leaked.
+pub const API_TOKEN_PREFIX: &str = "dvc_";
+
+/// A new personal API token.
+pub fn new_api_token() -> String {
+    format!("{}{}", API_TOKEN_PREFIX, random_nonce())
+}
+
+/// SHA-256 of a token, for storing tokens that are only ever looked up.
+pub fn digest(token: &str) -> String {
+    base64::encode_config(Sha256::digest(token.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::KeysConfig;
+    use super::*;
+
+    fn ring(current: &str) -> KeyRing {
+        KeyRing::from_config(&KeysConfig { file: None, current: Some(current.to_string()), previous: Vec::new() }).unwrap()
+    }
+
+    #[test]
+    fn derives_the_key_from_the_cookie_keys() {
+        let key = keys::generate();
+        let token = Signer::from_secret(None, &ring(&key)).sign("payload");
+        // A restart with the same keys
+        assert_eq!(Signer::from_secret(None, &ring(&key)).verify(&token).as_deref(), Some("payload"));
+        assert_eq!(Signer::from_secret(None, &ring(&keys::generate())).verify(&token), None);
+
+        let token = Signer::from_secret(Some("secret"), &ring(&key)).sign("payload");
+        assert_eq!(Signer::from_secret(Some("secret"), &ring(&keys::generate())).verify(&token).as_deref(), Some("payload"));
+        assert_eq!(Signer::from_secret(None, &ring(&key)).verify(&token), None);
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let signer = Signer::new(b"key".to_vec());
+        let token = signer.sign("alice");
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", base64::encode_config("mallory", base64::URL_SAFE_NO_PAD), signature);
+        assert_eq!(signer.verify(&forged), None);
+        assert_eq!(signer.verify("no signature"), None);
+    }
+}
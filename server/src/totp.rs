@@ -0,0 +1,345 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use qrcode::QrCode;
+use qrcode::render::svg;
+use rand::Rng;
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use actix_web::web;
+use crate::config::TwoFactorConfig;
+use crate::error::AppError;
+use crate::keys::{self, KeyRing};
+use crate::repo::{self, Pool};
+use crate::token;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// RFC 6238 defaults, the only parameters every authenticator app supports.
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps accepted on either side of the current one, for clock drift.
+const SKEW: i64 = 1;
+const SECRET_BYTES: usize = 20;
+const NONCE_BYTES: usize = 12;
+const RECOVERY_CODES: usize = 10;
+
+/// RFC 4226 one-time password for `counter`.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = <HmacSha1 as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// The time step `code` was generated for, when it is valid around `now`.
+fn matching_step(secret: &[u8], code: &str, now: i64) -> Option<i64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now.div_euclid(STEP_SECS);
+    (current - SKEW..=current + SKEW)
+        .filter(|step| *step >= 0)
+        .find(|step| bool::from(hotp(secret, *step as u64).to_be_bytes().ct_eq(&code.to_be_bytes())))
+}
+
+/// Percent encodes everything but unreserved characters.
+fn uri_component(value: &str) -> String {
+    value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// A new random TOTP secret.
+pub fn new_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_BYTES];
+    rand::thread_rng().fill(&mut secret[..]);
+    secret
+}
+
+/// Fresh single-use recovery codes, shown to the user once.
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &rand::thread_rng().gen::<[u8; 10]>())
+                .to_lowercase();
+            code.as_bytes()
+                .chunks(4)
+                .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+/// What is stored for a recovery code. Case and separators don't matter when typing it.
+pub fn recovery_code_hash(code: &str) -> String {
+    let normalized: String = code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    token::digest(&normalized)
+}
+
+/// Which second factor a login was completed with.
+#[derive(Debug, Clone, Copy)]
+pub enum SecondFactor {
+    Totp,
+    RecoveryCode,
+}
+
+/// What the enrollment page shows to set up an authenticator app.
+#[derive(Debug, Serialize)]
+pub struct Enrollment {
+    /// The secret for typing it in by hand, base32 like in the uri
+    pub secret: String,
+    pub uri: String,
+    /// Inline SVG of the uri
+    pub qr_code: String,
+}
+
+/// TOTP settings and the keys the secrets are stored encrypted with.
+pub struct TwoFactor {
+    /// The first one seals, all of them open
+    ciphers: Vec<Aes256Gcm>,
+    issuer: String,
+    pub required_for_admins: bool,
+}
+
+impl TwoFactor {
+    /// Derives the encryption key from `two_factor.key`, or from the cookie keys
+    /// when it is unset. Secrets sealed before a cookie key rotation are read
+    /// with the previous keys, until those are dropped from the key ring.
+    pub fn from_config(config: &TwoFactorConfig, ring: &KeyRing) -> TwoFactor {
+        let keys: Vec<[u8; 32]> = match &config.key {
+            Some(key) => vec![Sha256::digest(key.as_bytes()).into()],
+            None => {
+                if !ring.is_persistent() {
+                    log::warn!("No two-factor or cookie key is configured, enrolled users need a recovery code after a restart");
+                }
+                ring.subkeys(keys::TWO_FACTOR).iter().map(|key| Sha256::digest(key).into()).collect()
+            }
+        };
+        TwoFactor {
+            ciphers: keys.iter().map(|key| Aes256Gcm::new_from_slice(key).expect("the key is 32 bytes")).collect(),
+            issuer: config.issuer.to_owned(),
+            required_for_admins: config.required_for_admins,
+        }
+    }
+
+    /// Encrypts `secret`, bound to `username` so it can't be copied to another account.
+    pub fn seal(&self, username: &str, secret: &[u8]) -> Result<String, AppError> {
+        let nonce = rand::thread_rng().gen::<[u8; NONCE_BYTES]>();
+        let sealed = self.ciphers[0]
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: secret, aad: username.as_bytes() })
+            .map_err(|_| AppError::internal("Failed to encrypt a TOTP secret"))?;
+        Ok(base64::encode([&nonce[..], &sealed].concat()))
+    }
+
+    fn open(&self, username: &str, sealed: &str) -> Option<Vec<u8>> {
+        self.open_stale(username, sealed).map(|(secret, _)| secret)
+    }
+
+    /// Like `open`, also tells whether the secret was sealed with a previous key.
+    fn open_stale(&self, username: &str, sealed: &str) -> Option<(Vec<u8>, bool)> {
+        let sealed = base64::decode(sealed).ok()?;
+        if sealed.len() <= NONCE_BYTES {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_BYTES);
+        self.ciphers.iter().enumerate().find_map(|(index, cipher)| {
+            cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: username.as_bytes() })
+                .ok()
+                .map(|secret| (secret, index > 0))
+        })
+    }
+
+    /// The `otpauth://` uri and QR code for the sealed secret of `username`.
+    pub fn enrollment(&self, username: &str, sealed: &str) -> Result<Enrollment, AppError> {
+        let secret = self.open(username, sealed)
+            .ok_or_else(|| AppError::internal("Failed to decrypt a TOTP secret"))?;
+        let secret = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret);
+        let uri = format!(
+            "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+            issuer = uri_component(&self.issuer),
+            account = uri_component(username),
+            secret = secret,
+            digits = DIGITS,
+            period = STEP_SECS,
+        );
+        let svg = QrCode::new(uri.as_bytes())
+            .map_err(|err| AppError::internal(format!("Failed to draw the QR code: {}", err)))?
+            .render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .build();
+        // Inlined into the page, the XML declaration has no place there
+        let qr_code = svg.find("<svg").map_or(svg.as_str(), |start| &svg[start..]).to_string();
+        Ok(Enrollment { secret, uri, qr_code })
+    }
+
+    /// Checks a code typed by `username`, either from their authenticator app or
+    /// one of their recovery codes. Both only work once. A secret sealed with a
+    /// previous key is sealed again with the current one.
+    pub fn verify(&self, pool: &Pool, username: &str, input: &str) -> Result<Option<SecondFactor>, AppError> {
+        let state = repo::get_two_factor(pool.get()?, username.to_string())?;
+        let input = input.trim();
+
+        if let Some(sealed) = state.secret.as_deref() {
+            if let Some((secret, stale)) = self.open_stale(username, sealed) {
+                if let Some(step) = matching_step(&secret, input, Utc::now().timestamp()) {
+                    let fresh = repo::advance_totp_step(pool.get()?, username.to_string(), step)?;
+                    if fresh && stale {
+                        let resealed = self.seal(username, &secret)?;
+                        repo::reseal_totp_secret(pool.get()?, username.to_string(), sealed.to_string(), resealed)?;
+                    }
+                    return Ok(fresh.then_some(SecondFactor::Totp));
+                }
+            }
+        }
+        if state.enabled && repo::use_recovery_code(pool.get()?, username.to_string(), recovery_code_hash(input))? {
+            return Ok(Some(SecondFactor::RecoveryCode));
+        }
+        Ok(None)
+    }
+}
+
+/// Runs `TwoFactor::verify` on the blocking pool.
+pub async fn check(two_factor: &web::Data<TwoFactor>, db: &Pool, username: String, code: String) -> Result<Option<SecondFactor>, AppError> {
+    let pool = db.clone();
+    let two_factor = two_factor.clone();
+    Ok(web::block(move || two_factor.verify(&pool, &username, &code)).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use r2d2_sqlite::SqliteConnectionManager;
+    use crate::config::KeysConfig;
+    use crate::migrate;
+    use crate::models::SlimUser;
+    use super::*;
+
+    /// The SHA-1 secret of RFC 6238, Appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn ring(current: &str, previous: &[&str]) -> KeyRing {
+        let config = KeysConfig {
+            file: None,
+            current: Some(current.to_string()),
+            previous: previous.iter().map(|key| key.to_string()).collect(),
+        };
+        KeyRing::from_config(&config).unwrap()
+    }
+
+    fn two_factor() -> TwoFactor {
+        TwoFactor::from_config(&TwoFactorConfig { key: Some("test key".to_string()), ..TwoFactorConfig::default() }, &ring(&keys::generate(), &[]))
+    }
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        // The last six digits of the eight digit codes in the RFC
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(format!("{:06}", hotp(RFC_SECRET, (time / STEP_SECS) as u64)), code, "T={}", time);
+            assert_eq!(matching_step(RFC_SECRET, code, time), Some(time / STEP_SECS), "T={}", time);
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_skew() {
+        // 287082 belongs to step 1, 30 to 59 seconds
+        assert_eq!(matching_step(RFC_SECRET, "287082", 0), Some(1));
+        assert_eq!(matching_step(RFC_SECRET, "287082", 89), Some(1));
+        assert_eq!(matching_step(RFC_SECRET, "287082", 90), None);
+        assert_eq!(matching_step(RFC_SECRET, "287082", 120), None);
+        assert_eq!(matching_step(b"another secret", "287082", 59), None);
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        for code in ["", "28708", "2870820", "28708a", " 287082", "+87082"] {
+            assert_eq!(matching_step(RFC_SECRET, code, 59), None, "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn normalizes_recovery_codes() {
+        let hash = recovery_code_hash("abcd-efgh-ijkl-mnop");
+        assert_eq!(recovery_code_hash("ABCD EFGH IJKL MNOP"), hash);
+        assert_eq!(recovery_code_hash("abcdefghijklmnop"), hash);
+        assert_ne!(recovery_code_hash("abcd-efgh-ijkl-mnoq"), hash);
+
+        let codes = new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert!(codes.iter().all(|code| code.len() == 19 && code.split('-').count() == 4));
+    }
+
+    #[test]
+    fn binds_sealed_secrets_to_the_user() {
+        let two_factor = two_factor();
+        let sealed = two_factor.seal("alice", RFC_SECRET).unwrap();
+        assert_eq!(two_factor.open("alice", &sealed).as_deref(), Some(RFC_SECRET));
+        assert_eq!(two_factor.open("mallory", &sealed), None);
+        assert_eq!(two_factor.open("alice", "not base64!"), None);
+        assert_eq!(two_factor.open("alice", &base64::encode([0u8; NONCE_BYTES])), None);
+
+        // Sealed under another key
+        let other = TwoFactor::from_config(&TwoFactorConfig { key: Some("other key".to_string()), ..TwoFactorConfig::default() }, &ring(&keys::generate(), &[]));
+        assert_eq!(other.open("alice", &sealed), None);
+    }
+
+    #[test]
+    fn derives_the_key_from_the_cookie_keys() {
+        let (old, new) = (keys::generate(), keys::generate());
+        let sealed = TwoFactor::from_config(&TwoFactorConfig::default(), &ring(&old, &[])).seal("alice", RFC_SECRET).unwrap();
+
+        let restarted = TwoFactor::from_config(&TwoFactorConfig::default(), &ring(&old, &[]));
+        assert_eq!(restarted.open("alice", &sealed).as_deref(), Some(RFC_SECRET));
+        let rotated = TwoFactor::from_config(&TwoFactorConfig::default(), &ring(&new, &[&old]));
+        assert_eq!(rotated.open("alice", &sealed).as_deref(), Some(RFC_SECRET));
+        let dropped = TwoFactor::from_config(&TwoFactorConfig::default(), &ring(&new, &[]));
+        assert_eq!(dropped.open("alice", &sealed), None);
+    }
+
+    #[test]
+    fn reseals_secrets_of_previous_keys() {
+        let pool = Pool::builder().max_size(1).build(SqliteConnectionManager::memory()).unwrap();
+        migrate::run(&mut pool.get().unwrap()).unwrap();
+        let user = SlimUser {
+            username: "alice".to_string(),
+            email: None,
+            password: "hash".to_string(),
+            session_stamp: "stamp".to_string(),
+            two_factor: false,
+        };
+        repo::register_user(pool.get().unwrap(), user).unwrap();
+
+        let (old, new) = (keys::generate(), keys::generate());
+        let sealed = TwoFactor::from_config(&TwoFactorConfig::default(), &ring(&old, &[])).seal("alice", RFC_SECRET).unwrap();
+        repo::begin_two_factor(pool.get().unwrap(), "alice".to_string(), sealed.to_owned()).unwrap();
+
+        let rotated = TwoFactor::from_config(&TwoFactorConfig::default(), &ring(&new, &[&old]));
+        let code = format!("{:06}", hotp(RFC_SECRET, (Utc::now().timestamp() / STEP_SECS) as u64));
+        assert!(matches!(rotated.verify(&pool, "alice", &code).unwrap(), Some(SecondFactor::Totp)));
+
+        let resealed = repo::get_two_factor(pool.get().unwrap(), "alice".to_string()).unwrap().secret.unwrap();
+        assert_ne!(resealed, sealed);
+        let dropped = TwoFactor::from_config(&TwoFactorConfig::default(), &ring(&new, &[]));
+        assert_eq!(dropped.open("alice", &resealed).as_deref(), Some(RFC_SECRET));
+    }
+}
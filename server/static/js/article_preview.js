@@ -0,0 +1,32 @@
+// Live preview of the article editor, rendered by the server exactly like a saved article
+(function () {
+    var form = document.getElementById("article-form");
+    var source = document.getElementById("description");
+    var preview = document.getElementById("preview");
+    if (!form || !source || !preview) {
+        return;
+    }
+    var token = form.elements["csrf_token"].value;
+    var timer = null;
+
+    function update() {
+        var body = new URLSearchParams();
+        body.append("description", source.value);
+        fetch("/dashboard/articles/preview", {
+            method: "POST",
+            credentials: "same-origin",
+            headers: { "X-CSRF-Token": token },
+            body: body
+        })
+        .then(function (res) { return res.ok ? res.text() : Promise.reject(res.status); })
+        // The server sanitizes the HTML, like it does for saved articles
+        .then(function (html) { preview.innerHTML = html; })
+        .catch(function () {});
+    }
+
+    source.addEventListener("input", function () {
+        clearTimeout(timer);
+        timer = setTimeout(update, 300);
+    });
+    update();
+})();
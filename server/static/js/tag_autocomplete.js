@@ -0,0 +1,39 @@
+// Suggests existing tags for the one being typed, the last of the comma separated list
+(function () {
+    var input = document.getElementById("tags");
+    var list = document.getElementById("tag-suggestions");
+    if (!input || !list) {
+        return;
+    }
+    var timer = null;
+
+    function update() {
+        var parts = input.value.split(",");
+        var prefix = parts.pop().trim().toLowerCase();
+        var done = parts.map(function (tag) { return tag.trim(); }).filter(function (tag) { return tag; });
+        if (!prefix) {
+            list.innerHTML = "";
+            return;
+        }
+        fetch("/api/v1/tags?q=" + encodeURIComponent(prefix), { credentials: "same-origin" })
+        .then(function (res) { return res.ok ? res.json() : Promise.reject(res.status); })
+        // Every option is the whole list with the last tag completed, datalists match the whole value
+        .then(function (tags) {
+            list.innerHTML = "";
+            tags.forEach(function (tag) {
+                if (done.indexOf(tag.name) !== -1) {
+                    return;
+                }
+                var option = document.createElement("option");
+                option.value = done.concat([tag.name]).join(", ");
+                list.appendChild(option);
+            });
+        })
+        .catch(function () {});
+    }
+
+    input.addEventListener("input", function () {
+        clearTimeout(timer);
+        timer = setTimeout(update, 200);
+    });
+})();
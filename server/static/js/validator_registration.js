@@ -28,6 +28,10 @@ function registration() {
       alert('Please enter you password');
       return false;
 
+    } else if(passwd.length < 8) {
+      alert('Password must be at least 8 characters long');
+      return false;
+
     } else if(!pwd_expression.test(passwd)) {
       alert('Upper case, Lower case, Special character and Numeric letter are required in Password filed');
       return false;